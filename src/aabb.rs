@@ -1,4 +1,4 @@
-use crate::{geometry::*, math::*, ray::*};
+use crate::{geometry::*, math::*, ray::*, stats};
 
 // Helper Functions
 pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
@@ -31,7 +31,9 @@ impl AABB {
     pub fn max(&self) -> &Point3 {
         &self.max
     }
-    pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+    pub fn hit(&self, ray: &Ray, mut t_min: f64, mut t_max: f64) -> bool {
+        stats::record(|stats| stats.aabb_tests += 1);
+        // The ray is inside the box where it is inside all three slabs.
         for i in 0..3 {
             let divider = 1.0 / ray.direction()[i];
             let mut t0 = (self.min[i] - ray.origin()[i]) * divider;
@@ -39,7 +41,9 @@ impl AABB {
             if divider < 0.0 {
                 std::mem::swap(&mut t0, &mut t1);
             }
-            if t1.min(t_max) <= t0.max(t_min) {
+            t_min = t0.max(t_min);
+            t_max = t1.min(t_max);
+            if t_max <= t_min {
                 return false;
             }
         }
@@ -0,0 +1,259 @@
+use crate::{camera::*, geometry::*, material::*, math::*, output::*, render::*, sampler::*};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Aov
+// Arbitrary output variables, first-hit data written next to the beauty image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    Uv,
+    ObjectIndex,
+    MaterialIndex,
+}
+impl Aov {
+    pub const ALL: &'static [Aov] = &[
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Uv,
+        Aov::ObjectIndex,
+        Aov::MaterialIndex,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Uv => "uv",
+            Aov::ObjectIndex => "object_index",
+            Aov::MaterialIndex => "material_index",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Aov::ALL.iter().find(|aov| aov.name() == name).copied()
+    }
+    // EXR channel names, one per meaningful component of the stored colour.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::Uv => &["U", "V"],
+            Aov::ObjectIndex | Aov::MaterialIndex => &["id"],
+        }
+    }
+}
+
+// AovBuffers
+// One framebuffer per requested AOV. Albedo, normal, position and UV are
+// averaged over the samples of a pixel and are zero where nothing was hit.
+// Normals are averaged and made unit length again. Depth is averaged over the
+// samples that hit something and is infinite otherwise. The indices can't be
+// blended, so they come from the first sample that hit something and are -1
+// where nothing was hit.
+pub struct AovBuffers {
+    buffers: Vec<(Aov, Framebuffer)>,
+}
+impl AovBuffers {
+    pub fn new(width: usize, height: usize, aovs: &[Aov]) -> Self {
+        AovBuffers {
+            buffers: aovs
+                .iter()
+                .map(|aov| (*aov, Framebuffer::new(width, height)))
+                .collect(),
+        }
+    }
+    pub fn get(&self, aov: Aov) -> Option<&Framebuffer> {
+        self.buffers
+            .iter()
+            .find(|(a, _)| *a == aov)
+            .map(|(_, framebuffer)| framebuffer)
+    }
+    pub fn get_mut(&mut self, aov: Aov) -> Option<&mut Framebuffer> {
+        self.buffers
+            .iter_mut()
+            .find(|(a, _)| *a == aov)
+            .map(|(_, framebuffer)| framebuffer)
+    }
+    pub fn buffers(&self) -> &[(Aov, Framebuffer)] {
+        &self.buffers
+    }
+    pub fn retain(&mut self, aovs: &[Aov]) {
+        self.buffers.retain(|(aov, _)| aovs.contains(aov));
+    }
+    pub fn set(&mut self, x: usize, y: usize, pixel: &AovPixel) {
+        for (aov, framebuffer) in self.buffers.iter_mut() {
+            framebuffer.set(x, y, pixel.value(*aov));
+        }
+    }
+}
+
+// AovPixel
+// Every AOV of one pixel.
+#[derive(Debug, Clone)]
+pub struct AovPixel {
+    albedo: Color,
+    normal: Vec3,
+    depth: f64,
+    position: Point3,
+    uv: (f64, f64),
+    object_index: f64,
+    material_index: f64,
+}
+impl AovPixel {
+    pub fn value(&self, aov: Aov) -> Color {
+        match aov {
+            Aov::Albedo => self.albedo,
+            Aov::Normal => self.normal,
+            Aov::Depth => Color::new(self.depth, self.depth, self.depth),
+            Aov::Position => self.position,
+            Aov::Uv => Color::new(self.uv.0, self.uv.1, 0.0),
+            Aov::ObjectIndex => Color::new(self.object_index, self.object_index, self.object_index),
+            Aov::MaterialIndex => Color::new(
+                self.material_index,
+                self.material_index,
+                self.material_index,
+            ),
+        }
+    }
+}
+
+fn render_aov_pixel(
+    x: usize,
+    y: usize,
+    world: &HittableList,
+    camera: &Camera,
+    settings: &RenderSettings,
+    samples: u32,
+) -> AovPixel {
+    // A stream of its own, so the AOVs don't change the beauty samples.
+    let mut sampler = Sampler::new(
+        settings.sampler,
+        settings.seed,
+        u64::MAX,
+        (x, y),
+        settings.width,
+    );
+    let j = settings.height - 1 - y;
+    let mut pixel = AovPixel {
+        albedo: Color::BLACK,
+        normal: Vec3::default(),
+        depth: 0.0,
+        position: Point3::default(),
+        uv: (0.0, 0.0),
+        object_index: -1.0,
+        material_index: -1.0,
+    };
+    let mut hits = 0;
+    for sample in 0..samples {
+        sampler.start_sample(sample as u64, sample, samples);
+        let (du, dv) = sampler.get_2d();
+        let u = (x as f64 + du) / (settings.width - 1) as f64;
+        let v = (j as f64 + dv) / (settings.height - 1) as f64;
+        let ray = camera.get_ray(u, v, &mut sampler);
+        if let Some((object_index, hit)) = world.hit_object(&ray, 0.001, INIFINITY, &mut sampler) {
+            let (hit_u, hit_v) = hit.uv();
+            pixel.albedo = pixel.albedo + albedo(&hit);
+            pixel.normal = pixel.normal + *hit.normal();
+            pixel.depth += camera.depth(hit.point());
+            pixel.position = pixel.position + *hit.point();
+            pixel.uv = (pixel.uv.0 + hit_u, pixel.uv.1 + hit_v);
+            if hits == 0 {
+                pixel.object_index = object_index as f64;
+                pixel.material_index = material_index(hit.material()) as f64;
+            }
+            hits += 1;
+        }
+    }
+    let n = samples.max(1) as f64;
+    pixel.albedo = pixel.albedo / n;
+    // Normals are kept unit length, also where the samples hit surfaces that
+    // face different ways.
+    if pixel.normal.length_squared() > 0.0 {
+        pixel.normal = normalize(&pixel.normal);
+    }
+    pixel.position = pixel.position / n;
+    pixel.uv = (pixel.uv.0 / n, pixel.uv.1 / n);
+    pixel.depth = match hits {
+        0 => INIFINITY,
+        hits => pixel.depth / hits as f64,
+    };
+    pixel
+}
+
+// Traces `samples` camera rays per pixel and records what they hit first.
+// Returns None if the render was interrupted.
+pub fn render_aovs(
+    world: &HittableList,
+    camera: &Camera,
+    settings: &RenderSettings,
+    aovs: &[Aov],
+    samples: u32,
+) -> Option<AovBuffers> {
+    let render_tile = |tile: &Tile| -> Vec<AovPixel> {
+        tile.pixels()
+            .map(|(x, y)| render_aov_pixel(x, y, world, camera, settings, samples))
+            .collect()
+    };
+    let results = render_tiles(settings, render_tile, |_, _| {})?;
+    let mut buffers = AovBuffers::new(settings.width, settings.height, aovs);
+    for (tile, pixels) in results {
+        for ((x, y), pixel) in tile.pixels().zip(pixels) {
+            buffers.set(x, y, &pixel);
+        }
+    }
+    Some(buffers)
+}
+
+// Where an AOV is saved when it gets a file of its own, e.g. image.albedo.png
+// next to image.png.
+pub fn aov_path(output: &Path, aov: Aov) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match output.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, aov.name(), extension.to_string_lossy()),
+        None => format!("{}.{}", stem, aov.name()),
+    };
+    output.with_file_name(file_name)
+}
+
+// Saves every AOV to its own file next to `output`, in the same format.
+pub fn save_aovs(aovs: &AovBuffers, output: &Path, format: OutputFormat) -> io::Result<()> {
+    for (aov, framebuffer) in aovs.buffers() {
+        save_image_with_format(framebuffer, aov_path(output, *aov), format)?;
+    }
+    Ok(())
+}
+
+// Saves the beauty image and the AOVs as layers of a single EXR file. The beauty
+// image keeps the plain R, G and B channels, AOV channels are named like
+// albedo.R or depth.Z.
+pub fn save_exr_layers<P: AsRef<Path>>(
+    framebuffer: &Framebuffer,
+    aovs: &AovBuffers,
+    path: P,
+) -> io::Result<()> {
+    let channel = |framebuffer: &Framebuffer, c: i32| -> Vec<f32> {
+        framebuffer.pixels().iter().map(|p| p[c] as f32).collect()
+    };
+    let mut names = vec![String::from("R"), String::from("G"), String::from("B")];
+    let mut data = vec![
+        channel(framebuffer, 0),
+        channel(framebuffer, 1),
+        channel(framebuffer, 2),
+    ];
+    for (aov, buffer) in aovs.buffers() {
+        for (c, name) in (0..).zip(aov.channels()) {
+            names.push(format!("{}.{}", aov.name(), name));
+            data.push(channel(buffer, c));
+        }
+    }
+    let channels: Vec<(&str, Vec<f32>)> = names.iter().map(|n| n.as_str()).zip(data).collect();
+    write_exr(path, framebuffer.width(), framebuffer.height(), &channels)
+}
@@ -0,0 +1,524 @@
+use crate::{camera::*, geometry::*, material::*, math::*, ray::*, render::*, sampler::*, stats};
+
+// Bidirectional path tracing, after Veach's thesis and PBRT's BDPT. Every camera
+// sample also traces a subpath from a point on a light, and every prefix of the
+// camera subpath is connected to every prefix of the light subpath. A path of n
+// vertices can be made by each of these connections, so they are weighted
+// against each other with multiple importance sampling.
+
+// Transport
+// What a subpath carries: camera subpaths gather radiance, light subpaths carry
+// importance, which scatters with the BSDF's directions swapped.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Transport {
+    Radiance,
+    Importance,
+}
+
+// VertexKind
+enum VertexKind<'a> {
+    // A point on the lens.
+    Camera,
+    // A point on a light that starts a light subpath.
+    Light(RayHit<'a>),
+    // Where a subpath hit a surface or scattered in a medium.
+    Surface(RayHit<'a>),
+}
+
+// Vertex
+// The densities are area densities: `pdf_fwd` of the subpath sampling this
+// vertex from the one before it, `pdf_rev` of the other subpath sampling it if
+// the path had been traced the other way round.
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    point: Point3,
+    // Throughput of the subpath up to and including this vertex.
+    beta: Color,
+    // The subpath left this vertex by a specular bounce, which no connection
+    // can make.
+    delta: bool,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // Density of a light subpath starting here, zero off the world's lights.
+    light_pdf: f64,
+}
+impl<'a> Vertex<'a> {
+    fn camera(point: Point3) -> Self {
+        Vertex {
+            kind: VertexKind::Camera,
+            point,
+            beta: Color::WHITE,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            light_pdf: 0.0,
+        }
+    }
+    // Normal to convert densities with, None on the lens and in media.
+    fn surface_normal(&self) -> Option<Vec3> {
+        match &self.kind {
+            VertexKind::Camera => None,
+            VertexKind::Light(hit) => Some(*hit.normal()),
+            VertexKind::Surface(hit) => match hit.material() {
+                Material::Isotropic(_) => None,
+                _ => Some(*hit.normal()),
+            },
+        }
+    }
+    fn is_connectible(&self) -> bool {
+        match &self.kind {
+            VertexKind::Surface(hit) => lobes(hit.material()).is_non_specular(),
+            _ => true,
+        }
+    }
+    fn emitted(&self) -> Color {
+        match &self.kind {
+            VertexKind::Light(hit) | VertexKind::Surface(hit) => {
+                emitted(hit.material(), hit.uv().0, hit.uv().1, hit.point())
+            }
+            VertexKind::Camera => Color::BLACK,
+        }
+    }
+}
+
+// Helper functions
+// Area density at `to` from a solid angle density at `from`.
+fn convert_density(pdf: f64, from: &Vertex, to: &Vertex) -> f64 {
+    let w = to.point - from.point;
+    let distance_squared = w.length_squared();
+    if distance_squared == 0.0 {
+        return 0.0;
+    }
+    let cosine = match to.surface_normal() {
+        Some(normal) => dot(&normal, &w).abs() / distance_squared.sqrt(),
+        None => 1.0,
+    };
+    pdf * cosine / distance_squared
+}
+// BSDF times the cosine toward `next` at a surface vertex reached from `prev`.
+fn vertex_f(vertex: &Vertex, prev: &Point3, next: &Point3, transport: Transport) -> Color {
+    let hit = match &vertex.kind {
+        VertexKind::Surface(hit) => hit,
+        _ => return Color::BLACK,
+    };
+    let wo = normalize(&(*prev - vertex.point));
+    let wi = normalize(&(*next - vertex.point));
+    match transport {
+        Transport::Radiance => eval(hit.material(), hit, &wo, &wi),
+        Transport::Importance => adjoint_eval(hit, &wo, &wi),
+    }
+}
+
+// Context
+// What every strategy of one camera sample needs.
+struct Context<'a> {
+    world: &'a HittableList,
+    camera: &'a Camera,
+    background: &'a Color,
+    settings: &'a RenderSettings,
+    time: f64,
+    // Ranges of s and t that camera rays are shot with.
+    film_extent: (f64, f64),
+}
+impl<'a> Context<'a> {
+    fn visible(&self, from: &Point3, to: &Point3, sampler: &mut Sampler) -> bool {
+        let direction = *to - *from;
+        let distance = direction.length();
+        let ray = Ray::new(*from, direction, Some(self.time));
+        stats::record(|stats| stats.shadow_rays += 1);
+        self.world
+            .hit(&ray, 0.001, distance - 0.001, sampler)
+            .is_none()
+    }
+    // Density of a light subpath starting at `point`, which a ray from `origin`
+    // found on an emitter. Zero if none of the world's lights is there.
+    fn light_origin_pdf(&self, origin: &Point3, point: &Point3, sampler: &mut Sampler) -> f64 {
+        let lights = self.world.lights();
+        let distance = (*point - *origin).length();
+        let ray = Ray::new(*origin, *point - *origin, Some(self.time));
+        let mut pdf = 0.0;
+        for light in lights {
+            if let Some(hit) = ray_cast(light, &ray, 0.001, INIFINITY, sampler) {
+                if (hit.distance() - distance).abs() <= 1e-4 * distance {
+                    pdf += 1.0 / area(light);
+                }
+            }
+        }
+        pdf / lights.len().max(1) as f64
+    }
+    // Uniform point on a uniformly picked light.
+    fn sample_light_vertex(&self, sampler: &mut Sampler) -> Option<Vertex<'a>> {
+        let world: &'a HittableList = self.world;
+        let (hit, pdf) = world.random_light_point(sampler)?;
+        let radiance = emitted(hit.material(), hit.uv().0, hit.uv().1, hit.point());
+        Some(Vertex {
+            point: *hit.point(),
+            kind: VertexKind::Light(hit),
+            beta: radiance / pdf,
+            delta: false,
+            pdf_fwd: pdf,
+            pdf_rev: 0.0,
+            light_pdf: pdf,
+        })
+    }
+    fn emission_pdf(&self, light: &Vertex, next: &Vertex) -> f64 {
+        let normal = match light.surface_normal() {
+            Some(normal) => normal,
+            None => return 0.0,
+        };
+        let direction = normalize(&(next.point - light.point));
+        convert_density(emission_pdf(&normal, &direction), light, next)
+    }
+    // Area density at `next` of sampling it from `vertex`, reached from `prev`.
+    fn pdf(&self, vertex: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let direction = normalize(&(next.point - vertex.point));
+        let pdf = match (&vertex.kind, prev) {
+            (VertexKind::Camera, _) => {
+                self.camera
+                    .direction_pdf(&vertex.point, &direction, self.film_extent)
+            }
+            (VertexKind::Light(_), _) => return self.emission_pdf(vertex, next),
+            (VertexKind::Surface(hit), Some(prev)) => {
+                let wo = normalize(&(prev.point - vertex.point));
+                pdf(hit.material(), hit, &wo, &direction)
+            }
+            (VertexKind::Surface(_), None) => 0.0,
+        };
+        convert_density(pdf, vertex, next)
+    }
+
+    // Extends `path` by following `ray` until it leaves the world, is absorbed,
+    // ends by Russian roulette or reaches its vertex limit. Returns the
+    // background radiance picked up by camera subpaths that escape.
+    fn random_walk(
+        &self,
+        ray: Ray,
+        beta: Color,
+        pdf_dir: f64,
+        transport: Transport,
+        path: &mut Vec<Vertex<'a>>,
+        sampler: &mut Sampler,
+    ) -> Color {
+        // Camera subpaths hold the camera vertex on top of the bounces.
+        let max_depth = self.settings.max_depth.max(0) as usize;
+        let max_vertices = match transport {
+            Transport::Radiance => max_depth + 1,
+            Transport::Importance => max_depth,
+        };
+        let (mut ray, mut beta, mut pdf_fwd) = (ray, beta, pdf_dir);
+        let mut bounces = 0;
+        while path.len() < max_vertices {
+            // Camera subpaths start with the camera ray.
+            if transport == Transport::Importance || bounces > 0 {
+                stats::record(|stats| stats.secondary_rays += 1);
+            }
+            let hit = match self.world.hit(&ray, 0.001, INIFINITY, sampler) {
+                Some(hit) => hit,
+                None if transport == Transport::Radiance => return beta * *self.background,
+                None => break,
+            };
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface(hit.clone()),
+                point: *hit.point(),
+                beta,
+                delta: false,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+                light_pdf: 0.0,
+            };
+            vertex.pdf_fwd = convert_density(pdf_fwd, &path[path.len() - 1], &vertex);
+            if transport == Transport::Radiance && luminance(&vertex.emitted()) > 0.0 {
+                vertex.light_pdf = self.light_origin_pdf(ray.origin(), hit.point(), sampler);
+            }
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+            let wo = -*ray.direction();
+            let bsdf_sample = match sample(hit.material(), &hit, &wo, sampler) {
+                Some(bsdf_sample) => bsdf_sample,
+                None => break,
+            };
+            let current = path.len() - 1;
+            let pdf_rev = if bsdf_sample.lobe.contains(Lobes::SPECULAR) {
+                path[current].delta = true;
+                pdf_fwd = 0.0;
+                0.0
+            } else {
+                pdf_fwd = bsdf_sample.pdf;
+                pdf(hit.material(), &hit, &bsdf_sample.direction, &wo)
+            };
+            path[current - 1].pdf_rev =
+                convert_density(pdf_rev, &path[current], &path[current - 1]);
+            beta = match transport {
+                Transport::Importance if !path[current].delta => {
+                    beta * adjoint_eval(&hit, &wo, &bsdf_sample.direction) / bsdf_sample.pdf
+                }
+                _ => beta * bsdf_sample.weight(),
+            };
+            bounces += 1;
+            if bounces >= ROULETTE_DEPTH {
+                let survival = beta.x().max(beta.y()).max(beta.z()).min(0.95);
+                if sampler.get_1d() >= survival {
+                    break;
+                }
+                beta = beta / survival;
+            }
+            ray = Ray::new(*hit.point(), bsdf_sample.direction, Some(self.time));
+        }
+        Color::BLACK
+    }
+    fn camera_subpath(
+        &self,
+        ray: &Ray,
+        path: &mut Vec<Vertex<'a>>,
+        sampler: &mut Sampler,
+    ) -> Color {
+        let pdf_dir = self
+            .camera
+            .direction_pdf(ray.origin(), ray.direction(), self.film_extent);
+        // Orthographic cameras see along a single direction, light subpaths
+        // can't connect to them.
+        let mut camera = Vertex::camera(*ray.origin());
+        camera.delta = self.camera.is_orthographic();
+        path.push(camera);
+        self.random_walk(
+            *ray,
+            Color::WHITE,
+            pdf_dir,
+            Transport::Radiance,
+            path,
+            sampler,
+        )
+    }
+    fn light_subpath(&self, path: &mut Vec<Vertex<'a>>, sampler: &mut Sampler) {
+        if self.settings.max_depth <= 0 {
+            return;
+        }
+        let vertex = match self.sample_light_vertex(sampler) {
+            Some(vertex) => vertex,
+            None => return,
+        };
+        let (direction, pdf_dir) = match &vertex.kind {
+            VertexKind::Light(hit) => sample_emission(hit, sampler),
+            _ => return,
+        };
+        if pdf_dir <= 0.0 || luminance(&vertex.beta) <= 0.0 {
+            return;
+        }
+        let cosine = dot(&vertex.surface_normal().unwrap(), &direction).abs();
+        let beta = vertex.beta * (cosine / pdf_dir);
+        let ray = Ray::new(vertex.point, direction, Some(self.time));
+        path.push(vertex);
+        self.random_walk(ray, beta, pdf_dir, Transport::Importance, path, sampler);
+    }
+
+    // MIS weight of the path made by connecting the first `s` light vertices to
+    // the first `t` camera vertices, against every other (s, t) that could have
+    // made it. `sampled` is the light or camera vertex that a connection with
+    // s = 1 or t = 1 sampled in place of the subpath's own.
+    fn mis_weight(
+        &self,
+        light: &[Vertex<'a>],
+        camera: &[Vertex<'a>],
+        sampled: Option<&Vertex<'a>>,
+        (s, t): (usize, usize),
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let qs = match s {
+            0 => None,
+            1 => sampled,
+            _ => Some(&light[s - 1]),
+        };
+        let pt = match t {
+            1 => sampled.unwrap(),
+            _ => &camera[t - 1],
+        };
+        let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+        let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+        // Densities and delta flags of the vertices as they are once connected.
+        let entry = |vertex: &Vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta);
+        let mut light_pdfs: Vec<(f64, f64, bool)> = light[..s].iter().map(entry).collect();
+        let mut camera_pdfs: Vec<(f64, f64, bool)> = camera[..t].iter().map(entry).collect();
+        camera_pdfs[t - 1] = (pt.pdf_fwd, 0.0, false);
+        camera_pdfs[t - 1].1 = match qs {
+            Some(qs) => self.pdf(qs, qs_minus, pt),
+            None => pt.light_pdf,
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera_pdfs[t - 2].1 = match qs {
+                Some(qs) => self.pdf(pt, Some(qs), pt_minus),
+                None => self.emission_pdf(pt, pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            light_pdfs[s - 1] = (qs.pdf_fwd, self.pdf(pt, pt_minus, qs), false);
+            if let Some(qs_minus) = qs_minus {
+                light_pdfs[s - 2].1 = self.pdf(qs, Some(pt), qs_minus);
+            }
+        }
+        // Delta vertices have no density, they cancel out of the ratios.
+        let remap = |pdf: f64| match (pdf, self.settings.mis_heuristic) {
+            (0.0, _) => 1.0,
+            (pdf, MisHeuristic::Balance) => pdf,
+            (pdf, MisHeuristic::Power) => pdf * pdf,
+        };
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera_pdfs[i].1) / remap(camera_pdfs[i].0);
+            if !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+                sum += ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+            // Area lights are never delta.
+            let delta_before = i > 0 && light_pdfs[i - 1].2;
+            if !light_pdfs[i].2 && !delta_before {
+                sum += ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+
+    // Radiance of the path from connecting `s` light and `t` camera vertices,
+    // MIS weighted. Paths with t = 1 go through another pixel than the camera
+    // subpath's, those are pushed to `splats` instead.
+    fn connect(
+        &self,
+        light: &[Vertex<'a>],
+        camera: &[Vertex<'a>],
+        (s, t): (usize, usize),
+        sampler: &mut Sampler,
+        splats: &mut Vec<(usize, Color)>,
+    ) -> Color {
+        if s == 0 {
+            // The camera subpath found a light by itself.
+            let pt = &camera[t - 1];
+            let radiance = pt.emitted();
+            if luminance(&radiance) <= 0.0 {
+                return Color::BLACK;
+            }
+            // Emitters missing from the lights can't be reached any other way.
+            let weight = if pt.light_pdf > 0.0 {
+                self.mis_weight(light, camera, None, (s, t))
+            } else {
+                1.0
+            };
+            return pt.beta * radiance * weight;
+        }
+        if t == 1 {
+            // The light subpath is seen through a new point on the lens.
+            let qs = &light[s - 1];
+            if !qs.is_connectible() {
+                return Color::BLACK;
+            }
+            let lens_point = self.camera.sample_lens(sampler);
+            let to_lens = lens_point - qs.point;
+            let direction = -normalize(&to_lens);
+            let pdf_camera = self
+                .camera
+                .direction_pdf(&lens_point, &direction, self.film_extent);
+            let (film_s, film_t) = match self.camera.project(&lens_point, &direction) {
+                Some(st) if pdf_camera > 0.0 => st,
+                _ => return Color::BLACK,
+            };
+            let f = vertex_f(qs, &light[s - 2].point, &lens_point, Transport::Importance);
+            if luminance(&f) <= 0.0 || !self.visible(&qs.point, &lens_point, sampler) {
+                return Color::BLACK;
+            }
+            let camera_vertex = Vertex::camera(lens_point);
+            let weight = self.mis_weight(light, camera, Some(&camera_vertex), (s, t));
+            // Camera importance, which spreads the pixel's share of the film
+            // over the directions through it.
+            let (width, height) = (self.settings.width, self.settings.height);
+            let importance = pdf_camera * (width * height) as f64 / to_lens.length_squared();
+            let x = ((film_s * (width - 1) as f64) as usize).min(width - 1);
+            let y = ((height as f64 - film_t * (height - 1) as f64) as usize).min(height - 1);
+            splats.push((y * width + x, qs.beta * f * (importance * weight)));
+            return Color::BLACK;
+        }
+        let pt = &camera[t - 1];
+        if !pt.is_connectible() {
+            return Color::BLACK;
+        }
+        if s == 1 {
+            // A new point on a light, like the shadow rays of `ray_color`.
+            let light_vertex = match self.sample_light_vertex(sampler) {
+                Some(light_vertex) => light_vertex,
+                None => return Color::BLACK,
+            };
+            let f = vertex_f(
+                pt,
+                &camera[t - 2].point,
+                &light_vertex.point,
+                Transport::Radiance,
+            );
+            if luminance(&f) <= 0.0 || !self.visible(&pt.point, &light_vertex.point, sampler) {
+                return Color::BLACK;
+            }
+            let weight = self.mis_weight(light, camera, Some(&light_vertex), (s, t));
+            let geometry = convert_density(1.0, pt, &light_vertex);
+            return pt.beta * f * light_vertex.beta * (geometry * weight);
+        }
+        let qs = &light[s - 1];
+        if !qs.is_connectible() {
+            return Color::BLACK;
+        }
+        let f_camera = vertex_f(pt, &camera[t - 2].point, &qs.point, Transport::Radiance);
+        let f_light = vertex_f(qs, &light[s - 2].point, &pt.point, Transport::Importance);
+        let f = f_camera * f_light;
+        if luminance(&f) <= 0.0 || !self.visible(&pt.point, &qs.point, sampler) {
+            return Color::BLACK;
+        }
+        let weight = self.mis_weight(light, camera, None, (s, t));
+        pt.beta * f * qs.beta * (weight / (qs.point - pt.point).length_squared())
+    }
+}
+
+// Radiance along a camera ray by bidirectional path tracing. Light tracing
+// paths, which land on arbitrary pixels, are pushed to `splats` as pixel index
+// and radiance; the film adds them up over the whole image and divides by the
+// total number of camera samples.
+pub fn bdpt_color(
+    ray: &Ray,
+    background: &Color,
+    world: &HittableList,
+    camera: &Camera,
+    settings: &RenderSettings,
+    sampler: &mut Sampler,
+    splats: &mut Vec<(usize, Color)>,
+) -> Color {
+    let context = Context {
+        world,
+        camera,
+        background,
+        settings,
+        time: ray.time(),
+        film_extent: (
+            settings.width as f64 / (settings.width - 1) as f64,
+            settings.height as f64 / (settings.height - 1) as f64,
+        ),
+    };
+    let mut camera_path = Vec::new();
+    let mut light_path = Vec::new();
+    let mut color = context.camera_subpath(ray, &mut camera_path, sampler);
+    context.light_subpath(&mut light_path, sampler);
+    stats::record(|stats| stats.add_path(camera_path.len() - 1));
+    let max_depth = settings.max_depth.max(0) as usize;
+    for t in 1..=camera_path.len() {
+        for s in 0..=light_path.len() {
+            // One light and one camera vertex alone would be a light seen
+            // directly, which the camera subpath already finds.
+            if s + t < 2 || (s == 1 && t == 1) || s + t - 1 > max_depth {
+                continue;
+            }
+            color = color + context.connect(&light_path, &camera_path, (s, t), sampler, splats);
+        }
+    }
+    color
+}
@@ -1,6 +1,8 @@
-use crate::{math::*, ray::*};
+use crate::{math::*, ray::*, sampler::*, stats};
 
 // Camera
+// A thin lens camera, or an orthographic one whose rays all run parallel to the
+// viewing direction, starting from the view rectangle.
 pub struct Camera {
     origin: Point3,
     lower_left_corner: Point3,
@@ -11,6 +13,7 @@ pub struct Camera {
     w: Vec3,
     lens_radius: f64,
     shutter_time: (f64, f64), // shutter open/close time
+    orthographic: bool,
 }
 impl Camera {
     pub fn new(
@@ -47,15 +50,104 @@ impl Camera {
             w,
             lens_radius,
             shutter_time,
+            orthographic: false,
         }
     }
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let ray_direction = Vec3::random_in_unit_disk() * self.lens_radius;
-        let offset = self.u * ray_direction.x() + self.v * ray_direction.y();
+    // Sees a `viewport_height` tall rectangle around `look_from`, as wide as the
+    // aspect ratio makes it.
+    pub fn orthographic(
+        look_from: Point3,
+        look_at: Point3,
+        up: Vec3,
+        viewport_height: f64,
+        aspect_ratio: f64,
+        shutter_time: (f64, f64),
+    ) -> Self {
+        let w = normalize(&(look_from - look_at));
+        let u = normalize(&cross(&up, &w));
+        let v = cross(&w, &u);
+
+        let horizontal = u * aspect_ratio * viewport_height;
+        let vertical = v * viewport_height;
+        Camera {
+            origin: look_from,
+            lower_left_corner: look_from - horizontal / 2.0 - vertical / 2.0,
+            horizontal,
+            vertical,
+            u,
+            v,
+            w,
+            lens_radius: 0.0,
+            shutter_time,
+            orthographic: true,
+        }
+    }
+    pub fn is_orthographic(&self) -> bool {
+        self.orthographic
+    }
+    pub fn get_ray(&self, s: f64, t: f64, sampler: &mut Sampler) -> Ray {
+        stats::record(|stats| stats.camera_rays += 1);
+        if self.orthographic {
+            return Ray::new(
+                self.lower_left_corner + self.horizontal * s + self.vertical * t,
+                -self.w,
+                Some(self.time(sampler.get_1d())),
+            );
+        }
+        let lens_point = self.sample_lens(sampler);
         Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset,
-            Some(random_range_double(self.shutter_time.0, self.shutter_time.1)),
+            lens_point,
+            self.lower_left_corner + self.horizontal * s + self.vertical * t - lens_point,
+            Some(self.time(sampler.get_1d())),
         )
     }
+    // Time within the shutter interval, for a uniform `u`.
+    pub fn time(&self, u: f64) -> f64 {
+        self.shutter_time.0 + (self.shutter_time.1 - self.shutter_time.0) * u
+    }
+    // Uniform point on the lens, where camera rays start.
+    pub fn sample_lens(&self, sampler: &mut Sampler) -> Point3 {
+        let ray_direction = sample_unit_disk(sampler.get_2d()) * self.lens_radius;
+        let offset = self.u * ray_direction.x() + self.v * ray_direction.y();
+        self.origin + offset
+    }
+    // The s and t that `get_ray` would take to shoot a ray from `lens_point`
+    // along `direction`, None for directions that point away from the view.
+    // Orthographic cameras only see along one direction, so never from a point
+    // picked apart from it.
+    pub fn project(&self, lens_point: &Point3, direction: &Vec3) -> Option<(f64, f64)> {
+        let cosine = -dot(direction, &self.w);
+        if cosine <= 0.0 || self.orthographic {
+            return None;
+        }
+        // Rays from every lens point meet again on the focus plane.
+        let focus_distance = dot(&(self.origin - self.lower_left_corner), &self.w);
+        let p = *lens_point + *direction * (focus_distance / cosine) - self.lower_left_corner;
+        Some((
+            dot(&p, &self.horizontal) / self.horizontal.length_squared(),
+            dot(&p, &self.vertical) / self.vertical.length_squared(),
+        ))
+    }
+    // Solid angle density of `get_ray` picking the unit `direction` from
+    // `lens_point`, when s and t are uniform over [0, s_max) x [0, t_max).
+    pub fn direction_pdf(
+        &self,
+        lens_point: &Point3,
+        direction: &Vec3,
+        (s_max, t_max): (f64, f64),
+    ) -> f64 {
+        match self.project(lens_point, direction) {
+            Some((s, t)) if (0.0..s_max).contains(&s) && (0.0..t_max).contains(&t) => {
+                let cosine = -dot(direction, &self.w);
+                let focus_distance = dot(&(self.origin - self.lower_left_corner), &self.w);
+                let area = self.horizontal.length() * self.vertical.length() * s_max * t_max;
+                focus_distance * focus_distance / (cosine * cosine * cosine * area)
+            }
+            _ => 0.0,
+        }
+    }
+    // Distance of a point in front of the camera along the viewing direction.
+    pub fn depth(&self, p: &Point3) -> f64 {
+        -dot(&(*p - self.origin), &self.w)
+    }
 }
@@ -0,0 +1,360 @@
+use crate::{filter::*, math::*, photon::*, render::*, sampler::*};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Helper functions
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+fn read_f64<R: Read>(reader: &mut R) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(reader)?))
+}
+fn read_colors<R: Read>(reader: &mut R, count: usize) -> io::Result<Vec<Color>> {
+    let mut colors = Vec::with_capacity(count);
+    for _ in 0..count {
+        let r = read_f64(reader)?;
+        let g = read_f64(reader)?;
+        let b = read_f64(reader)?;
+        colors.push(Color::new(r, g, b));
+    }
+    Ok(colors)
+}
+// Reads as much as the file has rather than setting the whole length aside
+// up front, so a corrupt length can't ask for gigabytes.
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let length = read_u32(reader)? as u64;
+    let mut bytes = Vec::new();
+    if reader.take(length).read_to_end(&mut bytes)? as u64 != length {
+        return Err(invalid_data("Checkpoint ends early"));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("Checkpoint holds an invalid string"))
+}
+
+// Checkpoint
+// Everything needed to pick a progressive render up where it stopped. Pixel
+// generators are seeded from the render seed and pass index, so those two stand
+// in for the random number generator state.
+pub struct Checkpoint {
+    pub scene: String,
+    pub settings: RenderSettings,
+    pub samples_per_pass: i32,
+    pub adaptive: Option<AdaptiveSettings>,
+    pub state: RenderState,
+}
+impl Checkpoint {
+    const MAGIC: &'static [u8; 8] = b"RTCKPT07";
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        // Write next to the old checkpoint and swap it in, so a crash while
+        // saving never leaves a truncated file behind.
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_all(Checkpoint::MAGIC)?;
+        writer.write_all(&(self.scene.len() as u32).to_le_bytes())?;
+        writer.write_all(self.scene.as_bytes())?;
+        for value in &[
+            self.settings.width as u64,
+            self.settings.height as u64,
+            self.settings.samples_per_pixel as u64,
+            self.settings.max_depth as u64,
+            self.settings.tile_size as u64,
+            self.settings.seed,
+            self.samples_per_pass as u64,
+            self.state.passes as u64,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        let filter_name = self.settings.filter.name();
+        writer.write_all(&(filter_name.len() as u32).to_le_bytes())?;
+        writer.write_all(filter_name.as_bytes())?;
+        writer.write_all(&self.settings.filter.radius().to_le_bytes())?;
+        let sampler_name = self.settings.sampler.name();
+        writer.write_all(&(sampler_name.len() as u32).to_le_bytes())?;
+        writer.write_all(sampler_name.as_bytes())?;
+        let heuristic_name = self.settings.mis_heuristic.name();
+        writer.write_all(&(heuristic_name.len() as u32).to_le_bytes())?;
+        writer.write_all(heuristic_name.as_bytes())?;
+        let integrator_name = self.settings.integrator.name();
+        writer.write_all(&(integrator_name.len() as u32).to_le_bytes())?;
+        writer.write_all(integrator_name.as_bytes())?;
+        // Integrators that don't map photons store the defaults.
+        let photon_settings = self.settings.integrator.photon_settings();
+        let photon_settings = photon_settings.unwrap_or(PhotonSettings {
+            photons: 0,
+            radius: 0.0,
+        });
+        writer.write_all(&(photon_settings.photons as u64).to_le_bytes())?;
+        writer.write_all(&photon_settings.radius.to_le_bytes())?;
+        match &self.adaptive {
+            Some(adaptive) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&adaptive.min_samples.to_le_bytes())?;
+                writer.write_all(&adaptive.max_samples.to_le_bytes())?;
+                writer.write_all(&adaptive.threshold.to_le_bytes())?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+        for sum in self.state.accumulator.sums() {
+            for c in 0..3 {
+                writer.write_all(&sum[c].to_le_bytes())?;
+            }
+        }
+        for square_sum in self.state.accumulator.square_sums() {
+            writer.write_all(&square_sum.to_le_bytes())?;
+        }
+        for count in self.state.accumulator.sample_counts() {
+            writer.write_all(&count.to_le_bytes())?;
+        }
+        for sum in self.state.accumulator.film() {
+            for c in 0..3 {
+                writer.write_all(&sum[c].to_le_bytes())?;
+            }
+        }
+        for weight in self.state.accumulator.film_weights() {
+            writer.write_all(&weight.to_le_bytes())?;
+        }
+        for sum in self.state.accumulator.light_film() {
+            for c in 0..3 {
+                writer.write_all(&sum[c].to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp_path, path)
+    }
+
+    // Bytes stored per pixel: three colour sums, a square sum, a sample count,
+    // film and light film colours and a film weight.
+    const PIXEL_SIZE: u64 = 3 * 8 + 8 + 4 + 3 * 8 + 8 + 3 * 8;
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != Checkpoint::MAGIC {
+            return Err(invalid_data("Not a checkpoint file"));
+        }
+        let scene = read_string(&mut reader)?;
+        let mut values = [0u64; 8];
+        for value in values.iter_mut() {
+            *value = read_u64(&mut reader)?;
+        }
+        let filter_name = read_string(&mut reader)?;
+        let filter = Filter::from_name(&filter_name, Some(read_f64(&mut reader)?))
+            .ok_or_else(|| invalid_data("Checkpoint has an unknown filter"))?;
+        let sampler = SamplerKind::from_name(&read_string(&mut reader)?)
+            .ok_or_else(|| invalid_data("Checkpoint has an unknown sampler"))?;
+        let mis_heuristic = MisHeuristic::from_name(&read_string(&mut reader)?)
+            .ok_or_else(|| invalid_data("Checkpoint has an unknown MIS heuristic"))?;
+        let integrator_name = read_string(&mut reader)?;
+        let photon_settings = PhotonSettings {
+            photons: read_u64(&mut reader)? as usize,
+            radius: read_f64(&mut reader)?,
+        };
+        let integrator = Integrator::from_name(&integrator_name, photon_settings)
+            .ok_or_else(|| invalid_data("Checkpoint has an unknown integrator"))?;
+        let mut has_adaptive = [0];
+        reader.read_exact(&mut has_adaptive)?;
+        let adaptive = match has_adaptive[0] {
+            0 => None,
+            _ => Some(AdaptiveSettings {
+                min_samples: read_u32(&mut reader)?,
+                max_samples: read_u32(&mut reader)?,
+                threshold: read_f64(&mut reader)?,
+            }),
+        };
+        let (width, height) = (values[0] as usize, values[1] as usize);
+        let pixel_count = width
+            .checked_mul(height)
+            .ok_or_else(|| invalid_data("Checkpoint has an invalid resolution"))?;
+        // The pixels are checked against the file before memory is set aside
+        // for them.
+        let pixel_bytes = (pixel_count as u64).checked_mul(Checkpoint::PIXEL_SIZE);
+        if pixel_bytes.is_none_or(|bytes| bytes > file_size) {
+            return Err(invalid_data("Checkpoint is too short for its resolution"));
+        }
+        let sums = read_colors(&mut reader, pixel_count)?;
+        let mut square_sums = Vec::with_capacity(pixel_count);
+        for _ in 0..pixel_count {
+            square_sums.push(read_f64(&mut reader)?);
+        }
+        let mut sample_counts = Vec::with_capacity(pixel_count);
+        for _ in 0..pixel_count {
+            sample_counts.push(read_u32(&mut reader)?);
+        }
+        let film = read_colors(&mut reader, pixel_count)?;
+        let mut film_weights = Vec::with_capacity(pixel_count);
+        for _ in 0..pixel_count {
+            film_weights.push(read_f64(&mut reader)?);
+        }
+        let light_film = read_colors(&mut reader, pixel_count)?;
+        Ok(Checkpoint {
+            scene,
+            settings: RenderSettings {
+                width,
+                height,
+                samples_per_pixel: values[2] as i32,
+                max_depth: values[3] as i32,
+                tile_size: values[4] as usize,
+                threads: 0,
+                seed: values[5],
+                filter,
+                sampler,
+                mis_heuristic,
+                integrator,
+            },
+            samples_per_pass: values[6] as i32,
+            adaptive,
+            state: RenderState {
+                accumulator: Accumulator::from_raw(
+                    width,
+                    height,
+                    sums,
+                    square_sums,
+                    sample_counts,
+                    film,
+                    film_weights,
+                )
+                .with_light_film(light_film),
+                passes: values[7] as u32,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 3x2 render part of the way through, with values that only survive a
+    // bit exact round trip.
+    fn checkpoint() -> Checkpoint {
+        let (width, height) = (3, 2);
+        let count = width * height;
+        let value = |i: usize, salt: f64| (i as f64 + salt) / 3.0 + 1e-300 * salt;
+        let colors = |salt: f64| -> Vec<Color> {
+            (0..count)
+                .map(|i| Color::new(value(i, salt), -value(i, salt + 1.0), value(i, salt) * 1e9))
+                .collect()
+        };
+        let accumulator = Accumulator::from_raw(
+            width,
+            height,
+            colors(0.1),
+            (0..count).map(|i| value(i, 0.2)).collect(),
+            (0..count).map(|i| 7 * i as u32 + 1).collect(),
+            colors(0.3),
+            (0..count).map(|i| value(i, 0.4)).collect(),
+        )
+        .with_light_film(colors(0.5));
+        Checkpoint {
+            scene: String::from("cornell_box"),
+            settings: RenderSettings {
+                width,
+                height,
+                samples_per_pixel: 64,
+                max_depth: 12,
+                tile_size: 16,
+                threads: 0,
+                seed: u64::MAX - 5,
+                filter: Filter::Mitchell(1.75),
+                sampler: SamplerKind::Sobol,
+                mis_heuristic: MisHeuristic::Balance,
+                integrator: Integrator::Sppm(PhotonSettings {
+                    photons: 5000,
+                    radius: 0.1,
+                }),
+            },
+            samples_per_pass: 8,
+            adaptive: Some(AdaptiveSettings {
+                min_samples: 4,
+                max_samples: 256,
+                threshold: 0.015,
+            }),
+            state: RenderState {
+                accumulator,
+                passes: 3,
+            },
+        }
+    }
+    fn color_bits(colors: &[Color]) -> Vec<[u64; 3]> {
+        colors
+            .iter()
+            .map(|c| [c.x().to_bits(), c.y().to_bits(), c.z().to_bits()])
+            .collect()
+    }
+    fn float_bits(values: &[f64]) -> Vec<u64> {
+        values.iter().map(|value| value.to_bits()).collect()
+    }
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("raytrace_rs_checkpoint_{}_test.ckpt", name))
+    }
+
+    #[test]
+    fn checkpoints_round_trip_exactly() {
+        let saved = checkpoint();
+        let path = temp_path("round_trip");
+        saved.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.scene, saved.scene);
+        let (a, b) = (&loaded.settings, &saved.settings);
+        assert_eq!((a.width, a.height), (b.width, b.height));
+        assert_eq!(a.samples_per_pixel, b.samples_per_pixel);
+        assert_eq!(a.max_depth, b.max_depth);
+        assert_eq!(a.tile_size, b.tile_size);
+        assert_eq!(a.seed, b.seed);
+        assert_eq!(a.filter, b.filter);
+        assert_eq!(a.sampler, b.sampler);
+        assert_eq!(a.mis_heuristic, b.mis_heuristic);
+        assert_eq!(a.integrator, b.integrator);
+        assert_eq!(loaded.samples_per_pass, saved.samples_per_pass);
+        let (a, b) = (loaded.adaptive.unwrap(), saved.adaptive.unwrap());
+        assert_eq!(
+            (a.min_samples, a.max_samples),
+            (b.min_samples, b.max_samples)
+        );
+        assert_eq!(a.threshold.to_bits(), b.threshold.to_bits());
+        assert_eq!(loaded.state.passes, saved.state.passes);
+        let (a, b) = (&loaded.state.accumulator, &saved.state.accumulator);
+        assert_eq!(color_bits(a.sums()), color_bits(b.sums()));
+        assert_eq!(float_bits(a.square_sums()), float_bits(b.square_sums()));
+        assert_eq!(a.sample_counts(), b.sample_counts());
+        assert_eq!(color_bits(a.film()), color_bits(b.film()));
+        assert_eq!(float_bits(a.film_weights()), float_bits(b.film_weights()));
+        assert_eq!(color_bits(a.light_film()), color_bits(b.light_film()));
+    }
+
+    #[test]
+    fn damaged_checkpoints_are_rejected() {
+        let path = temp_path("damaged");
+        checkpoint().save(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        // Cut off anywhere, down to nothing.
+        for length in 0..bytes.len() {
+            fs::write(&path, &bytes[..length]).unwrap();
+            assert!(Checkpoint::load(&path).is_err(), "{} bytes loaded", length);
+        }
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[7] ^= 1;
+        fs::write(&path, &wrong_magic).unwrap();
+        let error = Checkpoint::load(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.to_string(), "Not a checkpoint file");
+    }
+}
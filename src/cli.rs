@@ -0,0 +1,455 @@
+use raytrace_rs::checkpoint::Checkpoint;
+use raytrace_rs::render::{AdaptiveSettings, ProgressiveSettings};
+use raytrace_rs::{render::RenderState, scene, OutputFormat, RenderSettings};
+use raytrace_rs::{
+    Aov, DisplaySettings, Filter, Integrator, MisHeuristic, PhotonSettings, SamplerKind, ToneMapper,
+};
+use std::path::PathBuf;
+
+const USAGE: &str = "Usage: raytrace-rs [OPTIONS]
+
+Options:
+    --width <PIXELS>      Image width [default: 800]
+    --height <PIXELS>     Image height [default: same as width]
+    --spp <SAMPLES>       Samples per pixel, the new total when resuming [default: 800]
+    --max-depth <DEPTH>   Hard limit on bounces, paths usually end earlier by Russian roulette [default: 32]
+    --scene <NAME>        Scene to render, by name or as a .gltf or .glb file
+                          [default: final_scene]
+    --output <PATH>       Output image, format picked by extension [default: image.png]
+    --bit-depth <BITS>    Bits per channel for PNG output, 8 or 16 [default: 8]
+    --exposure <STOPS>    Brighten or darken PNG and PPM output by this many stops [default: 0]
+    --tonemap <NAME>      Tone mapper for PNG and PPM output: clamp, reinhard, extended-reinhard,
+                          aces or agx [default: clamp]
+    --white-point <VALUE> Luminance that extended-reinhard maps to white [default: 4]
+    --dither              Dither 8-bit output to hide banding
+    --filter <NAME>       Pixel filter: box, tent, gaussian, mitchell or lanczos [default: box]
+    --filter-radius <PIXELS>
+                          Filter radius [default: 0.5 box, 1 tent, 1.5 gaussian, 2 mitchell, 3 lanczos]
+    --sampler <NAME>      Sample generator: independent, stratified, halton, sobol or blue-noise
+                          [default: independent]
+    --mis <NAME>          How light and BSDF samples are weighted: balance or power [default: power]
+    --integrator <NAME>   Light transport: path, bdpt (bidirectional), photon (photon mapping) or
+                          sppm (progressive photon mapping) [default: path]
+    --photons <COUNT>     Photons shot before every pass by photon and sppm [default: 100000]
+    --photon-radius <DISTANCE>
+                          Radius photons are gathered in, the starting one for sppm
+                          [default: a hundredth of the scene size]
+    --threads <COUNT>     Worker threads, 0 uses every core [default: 0]
+    --seed <SEED>         Seed for scene generation and sampling [default: 0]
+    --progressive <SPP>   Render in passes of this many samples per pixel, 0 renders in one go [default: 0]
+    --snapshot-every <PASSES>
+                          Save the output image every this many passes, 0 disables it [default: 1]
+    --snapshot-seconds <SECONDS>
+                          Save the output image at most this many seconds apart, 0 disables it [default: 0]
+    --checkpoint <PATH>   Save the render state here with every snapshot and on Ctrl-C
+    --resume <PATH>       Continue the render stored in a checkpoint
+    --adaptive <ERROR>    Stop sampling pixels once their relative error drops below this, 0 disables it [default: 0]
+    --min-spp <SAMPLES>   Samples every pixel gets with --adaptive [default: 16]
+    --max-spp <SAMPLES>   Most samples a pixel gets with --adaptive [default: 4 times --spp]
+    --sample-heatmap <PATH>
+                          Save an image of the samples taken per pixel
+    --aovs <LIST>         Also save these first-hit buffers, comma separated or all
+                          (albedo, normal, depth, position, uv, object_index, material_index)
+    --aov-samples <SAMPLES>
+                          Camera rays per pixel for the AOVs [default: 16]
+    --aov-layers          Store the AOVs as layers of the EXR output instead of separate files
+    --denoise             Denoise the final image, guided by the albedo, normal and depth AOVs
+    --stats-json <PATH>   Also write the render statistics as JSON, - for standard output
+    --help                Print this message";
+
+// Options
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: i32,
+    pub max_depth: i32,
+    pub scene: String,
+    pub output: PathBuf,
+    pub bit_depth: u32,
+    pub exposure: f64,
+    pub tone_mapper: String,
+    pub white_point: f64,
+    pub dither: bool,
+    pub filter: String,
+    pub filter_radius: Option<f64>,
+    pub sampler: String,
+    pub mis_heuristic: String,
+    pub integrator: String,
+    pub photons: usize,
+    // 0 picks one from the size of the scene.
+    pub photon_radius: f64,
+    pub threads: usize,
+    pub seed: u64,
+    pub samples_per_pass: i32,
+    pub snapshot_passes: u32,
+    pub snapshot_seconds: f64,
+    pub checkpoint: Option<PathBuf>,
+    pub resume: Option<PathBuf>,
+    pub adaptive_threshold: f64,
+    pub min_samples: u32,
+    // 0 picks four times the average sample count.
+    pub max_samples: u32,
+    pub sample_heatmap: Option<PathBuf>,
+    pub aovs: Vec<Aov>,
+    pub aov_samples: u32,
+    pub aov_layers: bool,
+    pub denoise: bool,
+    pub stats_json: Option<PathBuf>,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            width: 800,
+            height: 800,
+            samples_per_pixel: 800,
+            max_depth: 32,
+            scene: String::from("final_scene"),
+            output: PathBuf::from("image.png"),
+            bit_depth: 8,
+            exposure: 0.0,
+            tone_mapper: String::from("clamp"),
+            white_point: ToneMapper::DEFAULT_WHITE_POINT,
+            dither: false,
+            filter: String::from("box"),
+            filter_radius: None,
+            sampler: String::from("independent"),
+            mis_heuristic: String::from("power"),
+            integrator: String::from("path"),
+            photons: 100_000,
+            photon_radius: 0.0,
+            threads: 0,
+            seed: 0,
+            samples_per_pass: 0,
+            snapshot_passes: 1,
+            snapshot_seconds: 0.0,
+            checkpoint: None,
+            resume: None,
+            adaptive_threshold: 0.0,
+            min_samples: 16,
+            max_samples: 0,
+            sample_heatmap: None,
+            aovs: Vec::new(),
+            aov_samples: 16,
+            aov_layers: false,
+            denoise: false,
+            stats_json: None,
+        }
+    }
+}
+impl Options {
+    const DEFAULT_SAMPLES_PER_PASS: i32 = 16;
+
+    pub fn aspect_ratio(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
+    pub fn output_format(&self) -> Option<OutputFormat> {
+        match OutputFormat::from_path(&self.output) {
+            Some(OutputFormat::Png8) if self.bit_depth == 16 => Some(OutputFormat::Png16),
+            format => format,
+        }
+    }
+    pub fn display_settings(&self) -> DisplaySettings {
+        DisplaySettings {
+            exposure: self.exposure,
+            tone_mapper: ToneMapper::from_name(&self.tone_mapper, self.white_point).unwrap(),
+            dither: self.dither,
+        }
+    }
+    pub fn render_settings(&self) -> RenderSettings {
+        RenderSettings {
+            width: self.width,
+            height: self.height,
+            samples_per_pixel: self.samples_per_pixel,
+            max_depth: self.max_depth,
+            tile_size: RenderSettings::DEFAULT_TILE_SIZE,
+            threads: self.threads,
+            seed: self.seed,
+            filter: Filter::from_name(&self.filter, self.filter_radius).unwrap(),
+            sampler: SamplerKind::from_name(&self.sampler).unwrap(),
+            mis_heuristic: MisHeuristic::from_name(&self.mis_heuristic).unwrap(),
+            integrator: Integrator::from_name(&self.integrator, self.photon_settings()).unwrap(),
+        }
+    }
+    pub fn photon_settings(&self) -> PhotonSettings {
+        PhotonSettings {
+            photons: self.photons,
+            radius: self.photon_radius,
+        }
+    }
+    pub fn progressive_settings(&self) -> Option<ProgressiveSettings> {
+        // Checkpoints are taken between passes, adaptive sampling decides per
+        // pass and sppm shrinks its radius per pass, so all of them need a
+        // progressive render.
+        let samples_per_pass = if self.samples_per_pass > 0 {
+            self.samples_per_pass
+        } else if self.checkpoint.is_some()
+            || self.adaptive_settings().is_some()
+            || self.integrator == "sppm"
+        {
+            Options::DEFAULT_SAMPLES_PER_PASS
+        } else {
+            return None;
+        };
+        Some(ProgressiveSettings {
+            samples_per_pass,
+            snapshot_passes: self.snapshot_passes,
+            snapshot_seconds: self.snapshot_seconds,
+            adaptive: self.adaptive_settings(),
+        })
+    }
+    pub fn adaptive_settings(&self) -> Option<AdaptiveSettings> {
+        if self.adaptive_threshold <= 0.0 {
+            return None;
+        }
+        let max_samples = match self.max_samples {
+            0 => 4 * self.samples_per_pixel.max(1) as u32,
+            max_samples => max_samples,
+        };
+        Some(AdaptiveSettings {
+            min_samples: self.min_samples,
+            max_samples,
+            threshold: self.adaptive_threshold,
+        })
+    }
+    // Takes over everything that has to match the interrupted render. The sample
+    // count only changes when --spp asked for a new total. The merged options are
+    // checked again, since the checkpoint's values never went through parse_args.
+    pub fn resume_from(&mut self, checkpoint: &Checkpoint) -> Result<(), String> {
+        self.scene = checkpoint.scene.clone();
+        self.width = checkpoint.settings.width;
+        self.height = checkpoint.settings.height;
+        self.max_depth = checkpoint.settings.max_depth;
+        self.seed = checkpoint.settings.seed;
+        self.filter = checkpoint.settings.filter.name().to_string();
+        self.filter_radius = Some(checkpoint.settings.filter.radius());
+        self.sampler = checkpoint.settings.sampler.name().to_string();
+        self.mis_heuristic = checkpoint.settings.mis_heuristic.name().to_string();
+        self.integrator = checkpoint.settings.integrator.name().to_string();
+        if let Some(photon_settings) = checkpoint.settings.integrator.photon_settings() {
+            self.photons = photon_settings.photons;
+            self.photon_radius = photon_settings.radius;
+        }
+        self.samples_per_pass = checkpoint.samples_per_pass;
+        match &checkpoint.adaptive {
+            Some(adaptive) => {
+                self.adaptive_threshold = adaptive.threshold;
+                self.min_samples = adaptive.min_samples;
+                self.max_samples = adaptive.max_samples;
+            }
+            None => self.adaptive_threshold = 0.0,
+        }
+        if self.samples_per_pixel <= 0 {
+            self.samples_per_pixel = checkpoint.settings.samples_per_pixel;
+        }
+        if self.checkpoint.is_none() {
+            self.checkpoint = self.resume.clone();
+        }
+        if self.samples_per_pixel < 1 {
+            return Err(String::from("--spp must be at least 1"));
+        }
+        self.validate()
+    }
+    // The checks that also have to hold for the options taken from a checkpoint.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width < 2 || self.height < 2 {
+            return Err(String::from("Image must be at least 2x2 pixels"));
+        }
+        if self.max_depth < 1 {
+            return Err(String::from("--max-depth must be at least 1"));
+        }
+        if self.adaptive_threshold > 0.0 && self.min_samples < 2 {
+            return Err(String::from(
+                "--min-spp must be at least 2 to estimate variance",
+            ));
+        }
+        // A sample count of zero is filled in from the checkpoint later, and so is
+        // the default --max-spp that follows from it.
+        match self.adaptive_settings() {
+            Some(adaptive)
+                if self.samples_per_pixel > 0 && adaptive.min_samples > adaptive.max_samples =>
+            {
+                return Err(format!(
+                    "--min-spp ({}) must not be more than --max-spp ({})",
+                    adaptive.min_samples, adaptive.max_samples
+                ));
+            }
+            _ => {}
+        }
+        if self.sample_heatmap.is_some() && self.adaptive_threshold <= 0.0 {
+            return Err(String::from("--sample-heatmap needs --adaptive"));
+        }
+        if self.bit_depth != 8 && self.bit_depth != 16 {
+            return Err(String::from("--bit-depth must be 8 or 16"));
+        }
+        if ToneMapper::from_name(&self.tone_mapper, self.white_point).is_none() {
+            return Err(format!(
+                "Unknown tone mapper: {} (available: {})",
+                self.tone_mapper,
+                ToneMapper::NAMES.join(", ")
+            ));
+        }
+        if self.white_point <= 0.0 {
+            return Err(String::from("--white-point must be positive"));
+        }
+        if Filter::from_name(&self.filter, self.filter_radius).is_none() {
+            return Err(format!(
+                "Unknown filter: {} (available: {})",
+                self.filter,
+                Filter::NAMES.join(", ")
+            ));
+        }
+        if self.filter_radius.is_some_and(|radius| radius < 0.5) {
+            return Err(String::from("--filter-radius must be at least 0.5"));
+        }
+        if SamplerKind::from_name(&self.sampler).is_none() {
+            return Err(format!(
+                "Unknown sampler: {} (available: {})",
+                self.sampler,
+                SamplerKind::NAMES.join(", ")
+            ));
+        }
+        if MisHeuristic::from_name(&self.mis_heuristic).is_none() {
+            return Err(format!(
+                "Unknown MIS heuristic: {} (available: {})",
+                self.mis_heuristic,
+                MisHeuristic::NAMES.join(", ")
+            ));
+        }
+        if Integrator::from_name(&self.integrator, self.photon_settings()).is_none() {
+            return Err(format!(
+                "Unknown integrator: {} (available: {})",
+                self.integrator,
+                Integrator::NAMES.join(", ")
+            ));
+        }
+        if self.photons < 1 {
+            return Err(String::from("--photons must be at least 1"));
+        }
+        if self.photon_radius < 0.0 {
+            return Err(String::from("--photon-radius must not be negative"));
+        }
+        if !scene::SCENES.contains(&self.scene.as_str()) && !scene::is_scene_file(&self.scene) {
+            return Err(format!(
+                "Unknown scene: {} (available: {}, or a .gltf or .glb file)",
+                self.scene,
+                scene::SCENES.join(", ")
+            ));
+        }
+        if self.output_format().is_none() {
+            return Err(format!(
+                "Unsupported output format: {} (use .png, .hdr, .pfm, .exr or .ppm)",
+                self.output.display()
+            ));
+        }
+        if self.aov_layers && self.output_format() != Some(OutputFormat::Exr) {
+            return Err(String::from("--aov-layers needs an .exr output"));
+        }
+        if self.aov_samples < 1 {
+            return Err(String::from("--aov-samples must be at least 1"));
+        }
+        Ok(())
+    }
+    // AOVs to render, the saved ones plus the ones the denoiser needs.
+    pub fn rendered_aovs(&self) -> Vec<Aov> {
+        let mut aovs = self.aovs.clone();
+        if self.denoise {
+            for aov in &[Aov::Albedo, Aov::Normal, Aov::Depth] {
+                if !aovs.contains(aov) {
+                    aovs.push(*aov);
+                }
+            }
+        }
+        aovs
+    }
+    pub fn checkpoint(&self, state: &RenderState) -> Checkpoint {
+        Checkpoint {
+            scene: self.scene.clone(),
+            settings: self.render_settings(),
+            samples_per_pass: self.progressive_settings().unwrap().samples_per_pass,
+            adaptive: self.adaptive_settings(),
+            state: state.clone(),
+        }
+    }
+}
+
+fn parse_aovs(value: Option<String>) -> Result<Vec<Aov>, String> {
+    let value = value.ok_or("Missing value for --aovs")?;
+    if value == "all" {
+        return Ok(Aov::ALL.to_vec());
+    }
+    value
+        .split(',')
+        .map(|name| Aov::from_name(name.trim()).ok_or(format!("Unknown AOV: {}", name)))
+        .collect()
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("Missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+// Returns None when only the usage text was asked for.
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut height = None;
+    let mut samples_per_pixel = None;
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--width" => options.width = parse_value(&flag, args.next())?,
+            "--height" => height = Some(parse_value(&flag, args.next())?),
+            "--spp" => samples_per_pixel = Some(parse_value(&flag, args.next())?),
+            "--max-depth" => options.max_depth = parse_value(&flag, args.next())?,
+            "--scene" => options.scene = parse_value(&flag, args.next())?,
+            "--output" => options.output = parse_value(&flag, args.next())?,
+            "--bit-depth" => options.bit_depth = parse_value(&flag, args.next())?,
+            "--exposure" => options.exposure = parse_value(&flag, args.next())?,
+            "--tonemap" => options.tone_mapper = parse_value(&flag, args.next())?,
+            "--white-point" => options.white_point = parse_value(&flag, args.next())?,
+            "--dither" => options.dither = true,
+            "--filter" => options.filter = parse_value(&flag, args.next())?,
+            "--filter-radius" => options.filter_radius = Some(parse_value(&flag, args.next())?),
+            "--sampler" => options.sampler = parse_value(&flag, args.next())?,
+            "--mis" => options.mis_heuristic = parse_value(&flag, args.next())?,
+            "--integrator" => options.integrator = parse_value(&flag, args.next())?,
+            "--photons" => options.photons = parse_value(&flag, args.next())?,
+            "--photon-radius" => options.photon_radius = parse_value(&flag, args.next())?,
+            "--threads" => options.threads = parse_value(&flag, args.next())?,
+            "--seed" => options.seed = parse_value(&flag, args.next())?,
+            "--progressive" => options.samples_per_pass = parse_value(&flag, args.next())?,
+            "--snapshot-every" => options.snapshot_passes = parse_value(&flag, args.next())?,
+            "--snapshot-seconds" => options.snapshot_seconds = parse_value(&flag, args.next())?,
+            "--checkpoint" => options.checkpoint = Some(parse_value(&flag, args.next())?),
+            "--resume" => options.resume = Some(parse_value(&flag, args.next())?),
+            "--adaptive" => options.adaptive_threshold = parse_value(&flag, args.next())?,
+            "--min-spp" => options.min_samples = parse_value(&flag, args.next())?,
+            "--max-spp" => options.max_samples = parse_value(&flag, args.next())?,
+            "--sample-heatmap" => options.sample_heatmap = Some(parse_value(&flag, args.next())?),
+            "--aovs" => options.aovs = parse_aovs(args.next())?,
+            "--aov-samples" => options.aov_samples = parse_value(&flag, args.next())?,
+            "--aov-layers" => options.aov_layers = true,
+            "--denoise" => options.denoise = true,
+            "--stats-json" => options.stats_json = Some(parse_value(&flag, args.next())?),
+            "--help" | "-h" => return Ok(None),
+            _ => return Err(format!("Unknown option: {}", flag)),
+        }
+    }
+    options.height = height.unwrap_or(options.width);
+    options.samples_per_pixel = match samples_per_pixel {
+        Some(samples_per_pixel) => samples_per_pixel,
+        // Zero keeps the sample count stored in the checkpoint.
+        None if options.resume.is_some() => 0,
+        None => options.samples_per_pixel,
+    };
+    if options.samples_per_pixel < 1 && samples_per_pixel.is_some() {
+        return Err(String::from("--spp must be at least 1"));
+    }
+    options.validate()?;
+    Ok(Some(options))
+}
+
+pub fn usage() -> &'static str {
+    USAGE
+}
@@ -0,0 +1,248 @@
+use crate::{aov::*, math::*, output::*};
+
+// DenoiseSettings
+// Edge-avoiding À-Trous wavelet filter (Dammertz et al. 2010). Every iteration
+// blurs with a 5x5 B-spline kernel whose taps are spread twice as far apart as
+// in the last one, and drops taps that differ too much from the centre pixel in
+// colour or in any of the feature buffers. Smaller sigmas keep more detail.
+#[derive(Debug, Clone)]
+pub struct DenoiseSettings {
+    pub iterations: u32,
+    pub color_sigma: f64,
+    pub albedo_sigma: f64,
+    pub normal_sigma: f64,
+    // Relative to the depth of the centre pixel.
+    pub depth_sigma: f64,
+    // Number of worker threads, 0 picks one per available core.
+    pub threads: usize,
+}
+impl Default for DenoiseSettings {
+    fn default() -> Self {
+        DenoiseSettings {
+            iterations: 5,
+            color_sigma: 4.0,
+            albedo_sigma: 0.1,
+            normal_sigma: 0.3,
+            depth_sigma: 0.1,
+            threads: 0,
+        }
+    }
+}
+impl DenoiseSettings {
+    pub fn worker_count(&self) -> usize {
+        if self.threads > 0 {
+            return self.threads;
+        }
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    }
+}
+
+// Helper functions
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// Albedo below this is left alone instead of being divided out.
+const MIN_ALBEDO: f64 = 0.01;
+
+fn map_channels(color: &Color, f: impl Fn(f64) -> f64) -> Color {
+    Color::new(f(color.x()), f(color.y()), f(color.z()))
+}
+fn demodulate(color: &Color, albedo: &Color) -> Color {
+    let divide = |c: f64, a: f64| if a > MIN_ALBEDO { c / a } else { c };
+    Color::new(
+        divide(color.x(), albedo.x()),
+        divide(color.y(), albedo.y()),
+        divide(color.z(), albedo.z()),
+    )
+}
+fn modulate(color: &Color, albedo: &Color) -> Color {
+    let multiply = |c: f64, a: f64| if a > MIN_ALBEDO { c * a } else { c };
+    Color::new(
+        multiply(color.x(), albedo.x()),
+        multiply(color.y(), albedo.y()),
+        multiply(color.z(), albedo.z()),
+    )
+}
+fn gaussian_weight(distance_squared: f64, sigma: f64) -> f64 {
+    (-distance_squared / (sigma * sigma)).exp()
+}
+fn depth_weight(p: f64, q: f64, sigma: f64) -> f64 {
+    // Pixels that saw nothing are infinitely far away, and only blend with
+    // each other.
+    match (p.is_finite(), q.is_finite()) {
+        (true, true) => gaussian_weight(((p - q) / (sigma * p.max(1e-4))).powi(2), 1.0),
+        (false, false) => 1.0,
+        _ => 0.0,
+    }
+}
+
+struct Features<'a> {
+    albedo: Option<&'a Framebuffer>,
+    normal: Option<&'a Framebuffer>,
+    depth: Option<&'a Framebuffer>,
+}
+
+fn filter_pixel(
+    input: &[Color],
+    (x, y): (usize, usize),
+    (width, height): (usize, usize),
+    step: usize,
+    color_sigma: f64,
+    features: &Features,
+    settings: &DenoiseSettings,
+) -> Color {
+    // Colours are compared after a square root, so bright pixels don't need a
+    // much wider colour sigma than dark ones.
+    let center = map_channels(&input[y * width + x], |c| c.max(0.0).sqrt());
+    let mut sum = Color::BLACK;
+    let mut total_weight = 0.0;
+    for (j, kernel_y) in KERNEL.iter().enumerate() {
+        let qy = y as isize + (j as isize - 2) * step as isize;
+        if qy < 0 || qy >= height as isize {
+            continue;
+        }
+        for (i, kernel_x) in KERNEL.iter().enumerate() {
+            let qx = x as isize + (i as isize - 2) * step as isize;
+            if qx < 0 || qx >= width as isize {
+                continue;
+            }
+            let (qx, qy) = (qx as usize, qy as usize);
+            let sample = input[qy * width + qx];
+            let distance = (map_channels(&sample, |c| c.max(0.0).sqrt()) - center).length_squared();
+            let mut weight = kernel_x * kernel_y * gaussian_weight(distance, color_sigma);
+            if let Some(albedo) = features.albedo {
+                let distance = (*albedo.get(qx, qy) - *albedo.get(x, y)).length_squared();
+                weight *= gaussian_weight(distance, settings.albedo_sigma);
+            }
+            if let Some(normal) = features.normal {
+                let distance = (*normal.get(qx, qy) - *normal.get(x, y)).length_squared();
+                weight *= gaussian_weight(distance, settings.normal_sigma);
+            }
+            if let Some(depth) = features.depth {
+                let (p, q) = (depth.get(x, y).x(), depth.get(qx, qy).x());
+                weight *= depth_weight(p, q, settings.depth_sigma);
+            }
+            sum = sum + sample * weight;
+            total_weight += weight;
+        }
+    }
+    // The centre tap always has a weight of at least 9/64.
+    sum / total_weight
+}
+
+fn filter_iteration(
+    input: &[Color],
+    (width, height): (usize, usize),
+    step: usize,
+    color_sigma: f64,
+    features: &Features,
+    settings: &DenoiseSettings,
+) -> Vec<Color> {
+    let mut output = vec![Color::BLACK; input.len()];
+    let rows_per_thread = height.div_ceil(settings.worker_count());
+    std::thread::scope(|scope| {
+        for (chunk, rows) in output.chunks_mut(rows_per_thread * width).enumerate() {
+            scope.spawn(move || {
+                for (n, pixel) in rows.iter_mut().enumerate() {
+                    let (x, y) = (n % width, chunk * rows_per_thread + n / width);
+                    *pixel = filter_pixel(
+                        input,
+                        (x, y),
+                        (width, height),
+                        step,
+                        color_sigma,
+                        features,
+                        settings,
+                    );
+                }
+            });
+        }
+    });
+    output
+}
+
+// Denoises a rendered image, guided by whichever of the albedo, normal and depth
+// AOVs are present in `aovs`. With an albedo buffer the filter works on the
+// lighting alone and multiplies the albedo back in afterwards, which keeps
+// textures sharp.
+pub fn denoise(
+    framebuffer: &Framebuffer,
+    aovs: &AovBuffers,
+    settings: &DenoiseSettings,
+) -> Framebuffer {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let features = Features {
+        albedo: aovs.get(Aov::Albedo),
+        normal: aovs.get(Aov::Normal),
+        depth: aovs.get(Aov::Depth),
+    };
+    let mut pixels: Vec<Color> = match features.albedo {
+        Some(albedo) => framebuffer
+            .pixels()
+            .iter()
+            .zip(albedo.pixels())
+            .map(|(color, albedo)| demodulate(color, albedo))
+            .collect(),
+        None => framebuffer.pixels().to_vec(),
+    };
+    let mut color_sigma = settings.color_sigma;
+    for iteration in 0..settings.iterations {
+        let step = 1 << iteration;
+        pixels = filter_iteration(
+            &pixels,
+            (width, height),
+            step,
+            color_sigma,
+            &features,
+            settings,
+        );
+        // Each iteration has less noise left, so colour edges can be stricter.
+        color_sigma *= 0.5;
+    }
+    let mut output = Framebuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let color = pixels[y * width + x];
+            let color = match features.albedo {
+                Some(albedo) => modulate(&color, albedo.get(x, y)),
+                None => color,
+            };
+            output.set(x, y, color);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_images_stay_the_same() {
+        let (width, height) = (13, 9);
+        let color = Color::new(0.3, 1.5, 0.02);
+        let mut framebuffer = Framebuffer::new(width, height);
+        let mut aovs = AovBuffers::new(width, height, &[Aov::Albedo, Aov::Normal, Aov::Depth]);
+        let features = [
+            (Aov::Albedo, Color::new(0.5, 0.005, 0.9)),
+            (Aov::Normal, Color::new(0.0, 0.6, 0.8)),
+            (Aov::Depth, Color::new(2.5, 2.5, 2.5)),
+        ];
+        for y in 0..height {
+            for x in 0..width {
+                framebuffer.set(x, y, color);
+                for (aov, value) in &features {
+                    aovs.get_mut(*aov).unwrap().set(x, y, *value);
+                }
+            }
+        }
+        let settings = DenoiseSettings {
+            threads: 3,
+            ..DenoiseSettings::default()
+        };
+        let denoised = denoise(&framebuffer, &aovs, &settings);
+        for pixel in denoised.pixels() {
+            assert!((*pixel - color).length() < 1e-12, "{:?}", pixel);
+        }
+    }
+}
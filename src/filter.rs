@@ -0,0 +1,163 @@
+use crate::math::*;
+
+// Filter
+// Pixel reconstruction filters. Every sample is weighted into all pixels whose
+// centre lies within the radius, given in pixels. The filters are separable, so
+// the weight is the product of the 1D filter in x and in y.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Filter {
+    Box(f64),
+    Tent(f64),
+    Gaussian(f64),
+    // Mitchell–Netravali with B = C = 1/3.
+    Mitchell(f64),
+    // Sinc windowed by a sinc stretched over the radius.
+    Lanczos(f64),
+}
+impl Filter {
+    pub const NAMES: &'static [&'static str] = &["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+    // Without a radius, the filter gets the one it is usually used with.
+    pub fn from_name(name: &str, radius: Option<f64>) -> Option<Self> {
+        match name {
+            "box" => Some(Filter::Box(radius.unwrap_or(0.5))),
+            "tent" => Some(Filter::Tent(radius.unwrap_or(1.0))),
+            "gaussian" => Some(Filter::Gaussian(radius.unwrap_or(1.5))),
+            "mitchell" => Some(Filter::Mitchell(radius.unwrap_or(2.0))),
+            "lanczos" => Some(Filter::Lanczos(radius.unwrap_or(3.0))),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::Box(_) => "box",
+            Filter::Tent(_) => "tent",
+            Filter::Gaussian(_) => "gaussian",
+            Filter::Mitchell(_) => "mitchell",
+            Filter::Lanczos(_) => "lanczos",
+        }
+    }
+    pub fn radius(&self) -> f64 {
+        match self {
+            Filter::Box(radius)
+            | Filter::Tent(radius)
+            | Filter::Gaussian(radius)
+            | Filter::Mitchell(radius)
+            | Filter::Lanczos(radius) => *radius,
+        }
+    }
+    // How many pixels next to its own a sample can reach in each direction.
+    pub fn pixel_reach(&self) -> usize {
+        (self.radius() - 0.5).max(0.0).ceil() as usize
+    }
+    // Weight of a sample `dx`, `dy` pixels away from a pixel centre.
+    pub fn weight(&self, dx: f64, dy: f64) -> f64 {
+        filter_1d(self, dx) * filter_1d(self, dy)
+    }
+}
+
+// Helper functions
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+fn mitchell(x: f64) -> f64 {
+    const B: f64 = 1.0 / 3.0;
+    const C: f64 = 1.0 / 3.0;
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * B - 6.0 * C) * x * x * x
+            + (-18.0 + 12.0 * B + 6.0 * C) * x * x
+            + (6.0 - 2.0 * B))
+            / 6.0
+    } else if x < 2.0 {
+        ((-B - 6.0 * C) * x * x * x
+            + (6.0 * B + 30.0 * C) * x * x
+            + (-12.0 * B - 48.0 * C) * x
+            + (8.0 * B + 24.0 * C))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+fn filter_1d(filter: &Filter, x: f64) -> f64 {
+    let radius = filter.radius();
+    if x.abs() > radius {
+        return 0.0;
+    }
+    match filter {
+        Filter::Box(_) => 1.0,
+        Filter::Tent(_) => radius - x.abs(),
+        Filter::Gaussian(_) => {
+            // Three standard deviations fit in the radius, shifted down so it
+            // reaches zero there.
+            let sigma = radius / 3.0;
+            let alpha = 1.0 / (2.0 * sigma * sigma);
+            (-alpha * x * x).exp() - (-alpha * radius * radius).exp()
+        }
+        // The Mitchell kernel spans two units, stretch it over the radius.
+        Filter::Mitchell(_) => mitchell(2.0 * x / radius),
+        Filter::Lanczos(_) => sinc(x) * sinc(x / radius),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters() -> Vec<Filter> {
+        let mut filters = Vec::new();
+        for name in Filter::NAMES {
+            filters.push(Filter::from_name(name, None).unwrap());
+            filters.push(Filter::from_name(name, Some(0.75)).unwrap());
+            filters.push(Filter::from_name(name, Some(4.0)).unwrap());
+        }
+        filters
+    }
+
+    #[test]
+    fn filters_peak_at_the_centre() {
+        for filter in filters() {
+            let centre = filter.weight(0.0, 0.0);
+            assert!(centre > 0.0, "{:?}", filter);
+            for i in 1..=100 {
+                let x = filter.radius() * i as f64 / 100.0;
+                assert!(filter.weight(x, 0.0) <= centre, "{:?} at {}", filter, x);
+            }
+        }
+    }
+
+    #[test]
+    fn filters_reach_zero_at_their_radius() {
+        for filter in filters() {
+            let radius = filter.radius();
+            assert_eq!(filter.weight(radius + 1e-9, 0.0), 0.0, "{:?}", filter);
+            assert_eq!(filter.weight(0.0, -radius - 1e-9), 0.0, "{:?}", filter);
+            // The box is the one filter that ends with a step.
+            if let Filter::Box(_) = filter {
+                continue;
+            }
+            assert!(filter.weight(radius, 0.0).abs() < 1e-9, "{:?}", filter);
+            // Only Mitchell and Lanczos have negative lobes.
+            if let Filter::Mitchell(_) | Filter::Lanczos(_) = filter {
+                continue;
+            }
+            for i in 0..=100 {
+                let x = radius * i as f64 / 100.0;
+                assert!(filter.weight(x, x) >= 0.0, "{:?} at {}", filter, x);
+            }
+        }
+    }
+
+    #[test]
+    fn gaussian_widens_with_its_radius() {
+        let narrow = Filter::Gaussian(1.5);
+        let wide = Filter::Gaussian(3.0);
+        // Scaled to the radius, the shape stays the same.
+        let ratio = |filter: Filter, x: f64| filter.weight(x, 0.0) / filter.weight(0.0, 0.0);
+        assert!((ratio(narrow, 0.75) - ratio(wide, 1.5)).abs() < 1e-12);
+        assert!(ratio(wide, 0.75) > ratio(narrow, 0.75));
+    }
+}
@@ -1,4 +1,6 @@
-use crate::{aabb::*, material::*, math::*, ray::*};
+use crate::{aabb::*, material::*, math::*, mesh::*, ray::*, sampler::*, stats, transform::*};
+use std::sync::Arc;
+use std::time::Instant;
 
 // Helper function
 fn get_sphere_uv(p: &Vec3) -> (f64, f64) {
@@ -10,6 +12,7 @@ fn get_sphere_uv(p: &Vec3) -> (f64, f64) {
 }
 
 // Hittable
+#[derive(Clone)]
 pub enum Hittable {
     Sphere(Point3, f64, Material),
     MovingSphere((Point3, Point3), f64, Material, (f64, f64)),
@@ -18,11 +21,69 @@ pub enum Hittable {
     XZRect((f64, f64), (f64, f64), f64, Material),
     Cube(Cuboid),
     BHVNode(AABB, Box<Hittable>, Box<Hittable>),
-    Translate(Box<Hittable>, Vec3),
-    RotateY(Box<Hittable>, f64),
+    // Boxed, since a matrix and its inverse would make every object bigger.
+    Transform(Box<Hittable>, Box<Transform>),
     ConstantMedium(Box<Hittable>, f64, Material),
+    Triangle(Triangle),
+    // Shared, since meshes are big and lights made of them keep their triangles.
+    Mesh(Arc<TriangleMesh>),
 }
-pub fn ray_cast<'a>(obj: &'a Hittable, ray: &Ray, t_min: f64, t_max: f64) -> Option<RayHit<'a>> {
+// Names of the kinds of objects, in `Hittable` order.
+pub const HITTABLE_NAMES: [&str; 11] = [
+    "sphere",
+    "moving_sphere",
+    "xy_rect",
+    "yz_rect",
+    "xz_rect",
+    "cube",
+    "bvh_node",
+    "transform",
+    "constant_medium",
+    "triangle",
+    "triangle_mesh",
+];
+// Position of the object kind in the `Hittable` enum.
+pub fn hittable_index(obj: &Hittable) -> usize {
+    match obj {
+        Hittable::Sphere(..) => 0,
+        Hittable::MovingSphere(..) => 1,
+        Hittable::XYRect(..) => 2,
+        Hittable::YZRect(..) => 3,
+        Hittable::XZRect(..) => 4,
+        Hittable::Cube(_) => 5,
+        Hittable::BHVNode(..) => 6,
+        Hittable::Transform(..) => 7,
+        Hittable::ConstantMedium(..) => 8,
+        Hittable::Triangle(_) => 9,
+        Hittable::Mesh(_) => 10,
+    }
+}
+impl Hittable {
+    // The object under another transform, applied after the ones it already
+    // has. Those are merged into one.
+    pub fn transformed(self, transform: Transform) -> Hittable {
+        match self {
+            Hittable::Transform(object, inner) => {
+                Hittable::Transform(object, Box::new(transform * *inner))
+            }
+            _ => Hittable::Transform(Box::new(self), Box::new(transform)),
+        }
+    }
+    pub fn translate(self, offset: Vec3) -> Hittable {
+        self.transformed(Transform::translation(offset))
+    }
+    pub fn rotate_y(self, degrees: f64) -> Hittable {
+        self.transformed(Transform::rotation(Vec3::UP, degrees))
+    }
+}
+pub fn ray_cast<'a>(
+    obj: &'a Hittable,
+    ray: &Ray,
+    t_min: f64,
+    t_max: f64,
+    sampler: &mut Sampler,
+) -> Option<RayHit<'a>> {
+    stats::record(|stats| stats.ray_casts[hittable_index(obj)] += 1);
     match obj {
         Hittable::Sphere(center, radius, material) => {
             let oc = *ray.origin() - *center;
@@ -154,78 +215,46 @@ pub fn ray_cast<'a>(obj: &'a Hittable, ray: &Ray, t_min: f64, t_max: f64) -> Opt
                 (u, v),
             ))
         }
-        Hittable::Cube(cuboid) => cuboid.sides().hit(ray, t_min, t_max),
+        Hittable::Cube(cuboid) => cuboid.sides().hit(ray, t_min, t_max, sampler),
+        Hittable::Triangle(triangle) => triangle.hit(ray, t_min, t_max),
+        Hittable::Mesh(mesh) => mesh.hit(ray, t_min, t_max),
         Hittable::BHVNode(aabb, left, right) => {
             if !aabb.hit(ray, t_min, t_max) {
                 return None;
             }
-            let hit_left = ray_cast(left, ray, t_min, t_max);
+            let hit_left = ray_cast(left, ray, t_min, t_max, sampler);
+            // Only a hit in front of the left one can replace it.
             let t = match &hit_left {
                 Some(hit) => hit.distance(),
-                None => t_min,
+                None => t_max,
             };
-            let hit_right = ray_cast(right, ray, t, t_max);
-            if hit_left.is_some() {
-                return hit_left;
-            } else if hit_right.is_some() {
+            let hit_right = ray_cast(right, ray, t_min, t, sampler);
+            if hit_right.is_some() {
                 return hit_right;
             }
-            None
-        }
-        Hittable::Translate(object, offset) => {
-            let moved_r = Ray::new(*ray.origin() - *offset, *ray.direction(), Some(ray.time()));
-            if let Some(hit) = ray_cast(object, &moved_r, t_min, t_max) {
-                return Some(RayHit::new(
-                    &moved_r,
-                    *hit.point() + *offset,
-                    hit.distance(),
-                    hit.material(),
-                    *hit.normal(),
-                    hit.uv(),
-                ));
-            }
-            None
+            hit_left
         }
-        Hittable::RotateY(object, degree) => {
-            let mut origin = ray.origin().clone();
-            let mut direction = ray.direction().clone();
-            let radians = degrees_to_radians(*degree);
-            let sin_theta = radians.sin();
-            let cos_theta = radians.cos();
-            origin[0] = cos_theta * ray.origin()[0] - sin_theta * ray.origin()[2];
-            origin[2] = sin_theta * ray.origin()[0] + cos_theta * ray.origin()[2];
-            direction[0] = cos_theta * ray.direction()[0] - sin_theta * ray.direction()[2];
-            direction[2] = sin_theta * ray.direction()[0] + cos_theta * ray.direction()[2];
-
-            let rotated_r = Ray::new(origin, direction, Some(ray.time()));
-            match ray_cast(object, &rotated_r, t_min, t_max) {
-                Some(hit) => {
-                    let mut p = *hit.point();
-                    let mut normal = *hit.normal();
-                    p[0] = cos_theta * hit.point()[0] + sin_theta * hit.point()[2];
-                    p[2] = -sin_theta * hit.point()[0] + cos_theta * hit.point()[2];
-                    normal[0] = cos_theta * hit.normal()[0] + sin_theta * hit.normal()[2];
-                    normal[2] = -sin_theta * hit.normal()[0] + cos_theta * hit.normal()[2];
-                    Some(RayHit::new(
-                        &rotated_r,
-                        p,
-                        (p - ray.origin().clone()).length(),
-                        hit.material(),
-                        normal,
-                        hit.uv(),
-                    ))
-                }
-                None => None,
-            }
+        Hittable::Transform(object, transform) => {
+            // Distances grow by the length of the direction in object space.
+            let direction = transform.inverse_vector(ray.direction());
+            let scale = direction.length();
+            let origin = transform.inverse_point(ray.origin());
+            let object_ray = Ray::new(origin, direction, Some(ray.time()));
+            ray_cast(object, &object_ray, t_min * scale, t_max * scale, sampler).map(|hit| {
+                let point = transform.point(hit.point());
+                let normal = transform.normal(hit.normal());
+                let distance = hit.distance() / scale;
+                hit.transformed(point, distance, normal)
+            })
         }
         Hittable::ConstantMedium(object, density, material) => {
             // Print occasional samples when debugging. To enable, set enableDebug true.
             const ENABLE_DEBUG: bool = false;
-            let debugging: bool = ENABLE_DEBUG && random_double() < 0.00001;
+            let debugging: bool = ENABLE_DEBUG && sampler.rng().random_double() < 0.00001;
             let neg_inv_density: f64 = -1.0 / *density;
-            match ray_cast(object, ray, -INIFINITY, INIFINITY) {
+            match ray_cast(object, ray, -INIFINITY, INIFINITY, sampler) {
                 Some(hit1) => {
-                    match ray_cast(object, ray, hit1.distance() + 0.0001, INIFINITY) {
+                    match ray_cast(object, ray, hit1.distance() + 0.0001, INIFINITY, sampler) {
                         Some(hit2) => {
                             if debugging {
                                 eprintln!("t0={}, t1={}", hit1.distance(), hit2.distance());
@@ -240,7 +269,7 @@ pub fn ray_cast<'a>(obj: &'a Hittable, ray: &Ray, t_min: f64, t_max: f64) -> Opt
                             t1 = clamp(t1, 0.0, t1);
                             let ray_length = ray.direction().length();
                             let distance_inside_boundary = (t2 - t1) * ray_length;
-                            let hit_distance = neg_inv_density * random_double().ln();
+                            let hit_distance = neg_inv_density * sampler.get_1d().ln();
                             if hit_distance > distance_inside_boundary {
                                 return None;
                             }
@@ -296,74 +325,321 @@ pub fn get_bounding_box(obj: &Hittable, t0: f64, t1: f64) -> Option<AABB> {
             Point3::new(k + 0.0001, *y1, *z1),
         )),
         Hittable::Cube(cuboid) => Some(AABB::new(*cuboid.min(), *cuboid.max())),
+        Hittable::Triangle(triangle) => Some(triangle.bounding_box()),
+        Hittable::Mesh(mesh) => Some(mesh.bounding_box()),
         Hittable::BHVNode(aabb, left, right) => Some(aabb.clone()),
-        Hittable::Translate(object, offset) => match get_bounding_box(object, t0, t1) {
-            Some(bounding_box) => Some(AABB::new(
-                *bounding_box.min() + *offset,
-                *bounding_box.max() + *offset,
-            )),
-            None => None,
-        },
-        Hittable::RotateY(object, degree) => {
-            let radians = degrees_to_radians(*degree);
-            let sin_theta = radians.sin();
-            let cos_theta = radians.cos();
-            match get_bounding_box(object, 0.0, 1.0) {
-                Some(bounding_box) => {
-                    let mut min = Point3::new(INIFINITY, INIFINITY, INIFINITY);
-                    let mut max = Point3::new(-INIFINITY, -INIFINITY, -INIFINITY);
-                    for i in 0..2 {
-                        for j in 0..2 {
-                            for k in 0..2 {
-                                let x = i as f64 * bounding_box.max().x()
-                                    + (1 - i) as f64 * bounding_box.min().x();
-                                let y = j as f64 * bounding_box.max().y()
-                                    + (1 - j) as f64 * bounding_box.min().y();
-                                let z = k as f64 * bounding_box.max().z()
-                                    + (1 - k) as f64 * bounding_box.min().z();
-                                let newx = cos_theta * x + sin_theta * z;
-                                let newz = -sin_theta * x + cos_theta * z;
-                                let tester = Vec3::new(newx, y, newz);
-                                for c in 0..3 {
-                                    min[c] = min[c].min(tester[c]);
-                                    max[c] = max[c].max(tester[c]);
-                                }
-                            }
-                        }
-                    }
-                    Some(AABB::new(min, max))
+        Hittable::Transform(object, transform) => get_bounding_box(object, t0, t1)
+            .map(|bounding_box| transform.bounding_box(&bounding_box)),
+        Hittable::ConstantMedium(object, density, material) => get_bounding_box(object, t0, t1),
+        _ => panic!("What are you expecting me to do with this hittable object!??"),
+    }
+}
+
+// Light sampling
+// Emissive parts of an object that can be sampled directly, each wrapped in the
+// transforms of the object it sits in.
+fn emissive_parts(obj: &Hittable) -> Vec<Hittable> {
+    match obj {
+        Hittable::Sphere(_, _, Material::DiffuseLight(_))
+        | Hittable::XYRect(_, _, _, Material::DiffuseLight(_))
+        | Hittable::XZRect(_, _, _, Material::DiffuseLight(_))
+        | Hittable::YZRect(_, _, _, Material::DiffuseLight(_)) => vec![obj.clone()],
+        Hittable::Cube(cuboid) => cuboid.sides().lights().to_vec(),
+        Hittable::Triangle(triangle) if is_emissive(triangle.material()) => vec![obj.clone()],
+        // A glowing mesh is one light, however many triangles it has.
+        Hittable::Mesh(mesh) if is_emissive(mesh.material()) => vec![obj.clone()],
+        Hittable::BHVNode(_, left, right) => {
+            let mut parts = emissive_parts(left);
+            parts.extend(emissive_parts(right));
+            parts
+        }
+        // Spheres squashed into ellipsoids have no area to sample them by, and
+        // squashed meshes would need every triangle scaled on its own.
+        Hittable::Transform(object, transform) => emissive_parts(object)
+            .into_iter()
+            .map(|part| part.transformed(**transform))
+            .filter(|part| match part {
+                Hittable::Transform(object, transform) => {
+                    !matches!(**object, Hittable::Sphere(..) | Hittable::Mesh(_))
+                        || transform.is_similarity()
                 }
-                None => None,
+                _ => true,
+            })
+            .collect(),
+        // Moving emitters and glowing media are only found by chance.
+        _ => Vec::new(),
+    }
+}
+// Solid angle density of `random_direction` picking `direction` from `origin`,
+// zero if the direction misses the object.
+pub fn pdf_value(obj: &Hittable, origin: &Point3, direction: &Vec3, sampler: &mut Sampler) -> f64 {
+    let ray = Ray::new(*origin, *direction, None);
+    let area_pdf = |area: f64, normal: Vec3, sampler: &mut Sampler| -> f64 {
+        match ray_cast(obj, &ray, 0.001, INIFINITY, sampler) {
+            Some(hit) => {
+                // Rays have unit directions, so the distance is in world units.
+                let distance_squared = hit.distance() * hit.distance();
+                let cosine = dot(ray.direction(), &normal).abs();
+                distance_squared / (cosine * area)
             }
+            None => 0.0,
         }
-        Hittable::ConstantMedium(object, density, material) => get_bounding_box(object, t0, t1),
-        _ => panic!("What are you expecting me to do with this hittable object!??"),
+    };
+    match obj {
+        Hittable::Sphere(center, radius, _) => {
+            let distance_squared = (*center - *origin).length_squared();
+            if distance_squared <= radius * radius
+                || ray_cast(obj, &ray, 0.001, INIFINITY, sampler).is_none()
+            {
+                return 0.0;
+            }
+            let cos_theta_max = (1.0 - radius * radius / distance_squared).sqrt();
+            1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+        }
+        Hittable::XYRect((x0, x1), (y0, y1), _, _) => {
+            area_pdf((x1 - x0) * (y1 - y0), Vec3::new(0.0, 0.0, 1.0), sampler)
+        }
+        Hittable::XZRect((x0, x1), (z0, z1), _, _) => {
+            area_pdf((x1 - x0) * (z1 - z0), Vec3::new(0.0, 1.0, 0.0), sampler)
+        }
+        Hittable::YZRect((y0, y1), (z0, z1), _, _) => {
+            area_pdf((y1 - y0) * (z1 - z0), Vec3::new(1.0, 0.0, 0.0), sampler)
+        }
+        Hittable::Triangle(triangle) => area_pdf(triangle.area(), triangle.normal(), sampler),
+        // The triangle that was hit is picked by its share of the area, and then
+        // a point on it uniformly.
+        Hittable::Mesh(mesh) => match mesh.hit_triangle(&ray, 0.001, INIFINITY) {
+            Some((index, distance)) => {
+                let area = mesh.triangle_area(index);
+                let share = area / mesh.area();
+                let cosine = dot(ray.direction(), &mesh.triangle_normal(index)).abs();
+                share * distance * distance / (cosine * area)
+            }
+            None => 0.0,
+        },
+        Hittable::Cube(cuboid) => cuboid.sides().lights_pdf_value(origin, direction, sampler),
+        // Object space squeezes and stretches directions, which changes their
+        // density by the determinant over the cubed length they get.
+        Hittable::Transform(object, transform) => {
+            let inner_direction = transform.inverse_vector(&normalize(direction));
+            let length = inner_direction.length();
+            let origin = transform.inverse_point(origin);
+            pdf_value(object, &origin, &inner_direction, sampler)
+                * transform.inverse_matrix().determinant3().abs()
+                / (length * length * length)
+        }
+        _ => 0.0,
+    }
+}
+// A direction from `origin` toward a point on the object. Spheres are sampled by
+// the cone they fill as seen from `origin`, rectangles, triangles and meshes
+// uniformly by area.
+pub fn random_direction(obj: &Hittable, origin: &Point3, sampler: &mut Sampler) -> Vec3 {
+    match obj {
+        Hittable::Sphere(center, radius, _) => {
+            let direction = *center - *origin;
+            let distance_squared = direction.length_squared();
+            let cos_theta_max = (1.0 - radius * radius / distance_squared).max(0.0).sqrt();
+            let w = normalize(&direction);
+            let (u, v) = orthonormal_basis(&w);
+            let local = sample_cone(sampler.get_2d(), cos_theta_max);
+            u * local.x() + v * local.y() + w * local.z()
+        }
+        Hittable::XYRect((x0, x1), (y0, y1), k, _) => {
+            let (a, b) = sampler.get_2d();
+            Point3::new(x0 + a * (x1 - x0), y0 + b * (y1 - y0), *k) - *origin
+        }
+        Hittable::XZRect((x0, x1), (z0, z1), k, _) => {
+            let (a, b) = sampler.get_2d();
+            Point3::new(x0 + a * (x1 - x0), *k, z0 + b * (z1 - z0)) - *origin
+        }
+        Hittable::YZRect((y0, y1), (z0, z1), k, _) => {
+            let (a, b) = sampler.get_2d();
+            Point3::new(*k, y0 + a * (y1 - y0), z0 + b * (z1 - z0)) - *origin
+        }
+        Hittable::Triangle(triangle) => triangle.sample(sampler.get_2d()).0 - *origin,
+        Hittable::Mesh(mesh) => {
+            let pick = sampler.get_1d();
+            mesh.sample(pick, sampler.get_2d()).0 - *origin
+        }
+        Hittable::Cube(cuboid) => cuboid.sides().random_light_direction(origin, sampler),
+        Hittable::Transform(object, transform) => {
+            let origin = transform.inverse_point(origin);
+            transform.vector(&random_direction(object, &origin, sampler))
+        }
+        _ => panic!("This hittable object can't be sampled as a light!"),
+    }
+}
+
+// Surface area of an object that `random_point` can sample.
+pub fn area(obj: &Hittable) -> f64 {
+    match obj {
+        Hittable::Sphere(_, radius, _) => 4.0 * PI * radius * radius,
+        Hittable::XYRect((x0, x1), (y0, y1), _, _) => (x1 - x0) * (y1 - y0),
+        Hittable::XZRect((x0, x1), (z0, z1), _, _) => (x1 - x0) * (z1 - z0),
+        Hittable::YZRect((y0, y1), (z0, z1), _, _) => (y1 - y0) * (z1 - z0),
+        Hittable::Triangle(triangle) => triangle.area(),
+        Hittable::Mesh(mesh) => mesh.area(),
+        // Lights are flat, or spheres under similarities, which grow any
+        // surface alike.
+        Hittable::Transform(object, transform) => {
+            let normal = match &**object {
+                Hittable::XYRect(..) => Vec3::new(0.0, 0.0, 1.0),
+                Hittable::YZRect(..) => Vec3::new(1.0, 0.0, 0.0),
+                Hittable::Triangle(triangle) => triangle.normal(),
+                _ => Vec3::UP,
+            };
+            area(object) * transform.area_scale(&normal)
+        }
+        _ => panic!("This hittable object can't be sampled as a light!"),
+    }
+}
+// A uniformly distributed point on the surface of the object, as a hit whose
+// normal is the outward one.
+pub fn random_point<'a>(obj: &'a Hittable, sampler: &mut Sampler) -> RayHit<'a> {
+    // A ray into the surface, so that the hit faces outward.
+    let hit = |point: Point3, outward_normal: Vec3, material: &'a Material, uv: (f64, f64)| {
+        let ray = Ray::new(point + outward_normal, -outward_normal, None);
+        RayHit::new(&ray, point, 1.0, material, outward_normal, uv)
+    };
+    match obj {
+        Hittable::Sphere(center, radius, material) => {
+            let outward_normal = sample_unit_vector(sampler.get_2d());
+            let point = *center + outward_normal * *radius;
+            hit(
+                point,
+                outward_normal,
+                material,
+                get_sphere_uv(&outward_normal),
+            )
+        }
+        Hittable::XYRect((x0, x1), (y0, y1), k, material) => {
+            let (u, v) = sampler.get_2d();
+            let point = Point3::new(x0 + u * (x1 - x0), y0 + v * (y1 - y0), *k);
+            hit(point, Vec3::new(0.0, 0.0, 1.0), material, (u, v))
+        }
+        Hittable::XZRect((x0, x1), (z0, z1), k, material) => {
+            let (u, v) = sampler.get_2d();
+            let point = Point3::new(x0 + u * (x1 - x0), *k, z0 + v * (z1 - z0));
+            hit(point, Vec3::new(0.0, 1.0, 0.0), material, (u, v))
+        }
+        Hittable::YZRect((y0, y1), (z0, z1), k, material) => {
+            let (u, v) = sampler.get_2d();
+            let point = Point3::new(*k, y0 + u * (y1 - y0), z0 + v * (z1 - z0));
+            hit(point, Vec3::new(1.0, 0.0, 0.0), material, (u, v))
+        }
+        Hittable::Triangle(triangle) => {
+            let (point, uv) = triangle.sample(sampler.get_2d());
+            hit(point, triangle.normal(), triangle.material(), uv)
+        }
+        Hittable::Mesh(mesh) => {
+            let pick = sampler.get_1d();
+            let (point, normal, uv) = mesh.sample(pick, sampler.get_2d());
+            hit(point, normal, mesh.material(), uv)
+        }
+        Hittable::Transform(object, transform) => {
+            let inner = random_point(object, sampler);
+            hit(
+                transform.point(inner.point()),
+                transform.normal(inner.normal()),
+                inner.material(),
+                inner.uv(),
+            )
+        }
+        _ => panic!("This hittable object can't be sampled as a light!"),
     }
 }
 
 // HittableList
+// Emissive parts of every added object are also kept in a list of lights, so
+// they can be sampled directly.
+#[derive(Clone)]
 pub struct HittableList {
     objects: Vec<Hittable>,
+    lights: Vec<Hittable>,
+}
+impl Default for HittableList {
+    fn default() -> Self {
+        HittableList::new()
+    }
 }
 impl HittableList {
     pub fn new() -> Self {
         HittableList {
             objects: Vec::new(),
+            lights: Vec::new(),
         }
     }
     pub fn add(&mut self, object: Hittable) {
+        self.lights.extend(emissive_parts(&object));
         self.objects.push(object);
     }
     pub fn clear(&mut self) {
         self.objects.clear();
+        self.lights.clear();
     }
-    pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RayHit> {
+    pub fn lights(&self) -> &[Hittable] {
+        &self.lights
+    }
+    // Density of `random_light_direction`, which picks one light uniformly.
+    pub fn lights_pdf_value(
+        &self,
+        origin: &Point3,
+        direction: &Vec3,
+        sampler: &mut Sampler,
+    ) -> f64 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let sum: f64 = self
+            .lights
+            .iter()
+            .map(|light| pdf_value(light, origin, direction, sampler))
+            .sum();
+        sum / self.lights.len() as f64
+    }
+    pub fn random_light_direction(&self, origin: &Point3, sampler: &mut Sampler) -> Vec3 {
+        let index = (sampler.get_1d() * self.lights.len() as f64) as usize;
+        random_direction(
+            &self.lights[index.min(self.lights.len() - 1)],
+            origin,
+            sampler,
+        )
+    }
+    // Uniform point on a uniformly picked light, with its area density.
+    pub fn random_light_point(&self, sampler: &mut Sampler) -> Option<(RayHit<'_>, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index = (sampler.get_1d() * self.lights.len() as f64) as usize;
+        let light = &self.lights[index.min(self.lights.len() - 1)];
+        let pdf = 1.0 / (self.lights.len() as f64 * area(light));
+        Some((random_point(light, sampler), pdf))
+    }
+    pub fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        sampler: &mut Sampler,
+    ) -> Option<RayHit<'_>> {
+        self.hit_object(ray, t_min, t_max, sampler)
+            .map(|(_, hit)| hit)
+    }
+    // Same as `hit`, but also returns the index of the object that was hit.
+    pub fn hit_object(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        sampler: &mut Sampler,
+    ) -> Option<(usize, RayHit<'_>)> {
         let mut closest_hit = None;
         let mut closest_distance = t_max;
-        for object in &self.objects {
-            if let Some(hit) = ray_cast(object, ray, t_min, closest_distance) {
+        for (index, object) in self.objects.iter().enumerate() {
+            if let Some(hit) = ray_cast(object, ray, t_min, closest_distance, sampler) {
                 closest_distance = hit.distance();
-                closest_hit = Some(hit);
+                closest_hit = Some((index, hit));
             }
         }
         closest_hit
@@ -381,18 +657,28 @@ impl HittableList {
                         x
                     } else {
                         surrounding_box(&x, &union_box)
-                    }
+                    };
+                    first_box = false;
                 }
                 None => return None,
             }
         }
         Some(union_box)
     }
-    pub fn to_bhv(self, time0: f64, time1: f64) -> Hittable {
-        HittableList::construct_bhv(self.objects, time0, time1)
+    pub fn to_bhv(self, time0: f64, time1: f64, rng: &mut Rng) -> Hittable {
+        let start = Instant::now();
+        let bvh = HittableList::construct_bhv(self.objects, time0, time1, rng);
+        let seconds = start.elapsed().as_secs_f64();
+        stats::record(|stats| stats.bvh_build_seconds += seconds);
+        bvh
     }
-    fn construct_bhv(mut objects: Vec<Hittable>, time0: f64, time1: f64) -> Hittable {
-        let axis = random_range_int(0, 2);
+    fn construct_bhv(
+        mut objects: Vec<Hittable>,
+        time0: f64,
+        time1: f64,
+        rng: &mut Rng,
+    ) -> Hittable {
+        let axis = rng.random_range_int(0, 2);
         let comparator = |a: &Hittable, b: &Hittable| -> std::cmp::Ordering {
             let box_a = get_bounding_box(a, time0, time1);
             let box_b = get_bounding_box(b, time0, time1);
@@ -427,8 +713,8 @@ impl HittableList {
                 let mut vec_right = objects;
                 let vec_left = vec_right.split_off(object_span / 2);
                 //eprintln!("vec_left: {}, vec_right: {}", vec_left.len(), vec_right.len());
-                left = HittableList::construct_bhv(vec_left, time0, time1);
-                right = HittableList::construct_bhv(vec_right, time0, time1);
+                left = HittableList::construct_bhv(vec_left, time0, time1, rng);
+                right = HittableList::construct_bhv(vec_right, time0, time1, rng);
                 Hittable::BHVNode(
                     surrounding_box(
                         &get_bounding_box(&left, time0, time1).unwrap(),
@@ -442,6 +728,7 @@ impl HittableList {
     }
 }
 
+#[derive(Clone)]
 pub struct Cuboid {
     min: Point3,
     max: Point3,
@@ -0,0 +1,586 @@
+use crate::{camera::*, geometry::*, material::*, math::*, mesh::*, texture::*};
+use gltf::buffer::Source as BufferSource;
+use gltf::camera::Projection;
+use gltf::image::Source as ImageSource;
+use gltf::mesh::Mode;
+use gltf::texture::WrappingMode;
+use gltf::{Document, Gltf, Node};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// glTF 2.0 scenes, from .gltf files with their buffers and images embedded or
+// next to them, or from binary .glb files. Node transforms are baked into the
+// vertices, so every instance of a mesh becomes a triangle mesh of its own.
+// Materials use the metallic-roughness model. Animations, skins, morph targets
+// and extensions are left out.
+
+// Helper functions
+fn invalid_data(path: &Path, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
+// Standard or URL-safe base64, as in data URIs. Padding is skipped.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut bit_count) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => continue,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(bytes)
+}
+// Relative URIs escape spaces and other characters as %XX.
+fn decode_uri(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| {
+            let hex = std::str::from_utf8(hex).ok()?;
+            u8::from_str_radix(hex, 16).ok()
+        });
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+// Bytes behind a buffer or image URI: a base64 data URI, or a file next to the
+// scene.
+fn read_uri(uri: &str, path: &Path) -> io::Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        return data
+            .split_once(";base64,")
+            .and_then(|(_, encoded)| decode_base64(encoded))
+            .ok_or_else(|| invalid_data(path, "Unsupported data URI"));
+    }
+    let file = path.with_file_name(decode_uri(uri));
+    fs::read(&file)
+        .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", file.display(), error)))
+}
+fn to_vec3([x, y, z]: [f32; 3]) -> Vec3 {
+    Vec3::new(x as f64, y as f64, z as f64)
+}
+
+fn load_buffers(
+    document: &Document,
+    mut blob: Option<Vec<u8>>,
+    path: &Path,
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut buffers = Vec::new();
+    for buffer in document.buffers() {
+        let data = match buffer.source() {
+            BufferSource::Bin => blob
+                .take()
+                .ok_or_else(|| invalid_data(path, "Missing binary chunk"))?,
+            BufferSource::Uri(uri) => read_uri(uri, path)?,
+        };
+        if data.len() < buffer.length() {
+            return Err(invalid_data(
+                path,
+                &format!(
+                    "Buffer {} has {} bytes instead of {}",
+                    buffer.index(),
+                    data.len(),
+                    buffer.length()
+                ),
+            ));
+        }
+        buffers.push(data);
+    }
+    Ok(buffers)
+}
+fn load_images(document: &Document, buffers: &[Vec<u8>], path: &Path) -> io::Result<Vec<Image>> {
+    let load = |source: ImageSource| -> io::Result<Image> {
+        match source {
+            ImageSource::View { view, .. } => {
+                let start = view.offset();
+                match buffers[view.buffer().index()].get(start..start + view.length()) {
+                    Some(bytes) => Image::from_memory(bytes),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Buffer view out of range",
+                    )),
+                }
+            }
+            ImageSource::Uri { uri, .. } if uri.starts_with("data:") => {
+                Image::from_memory(&read_uri(uri, path)?)
+            }
+            ImageSource::Uri { uri, .. } => {
+                let file = path.with_file_name(decode_uri(uri));
+                Image::load(&file).map_err(|error| {
+                    io::Error::new(error.kind(), format!("{}: {}", file.display(), error))
+                })
+            }
+        }
+    };
+    document
+        .images()
+        .map(|image| {
+            load(image.source()).map_err(|error| {
+                let message = format!("Image {}: {}", image.index(), error);
+                io::Error::new(error.kind(), format!("{}: {}", path.display(), message))
+            })
+        })
+        .collect()
+}
+
+fn to_wrap(mode: WrappingMode) -> Wrap {
+    match mode {
+        WrappingMode::ClampToEdge => Wrap::Clamp,
+        WrappingMode::MirroredRepeat => Wrap::MirroredRepeat,
+        WrappingMode::Repeat => Wrap::Repeat,
+    }
+}
+// Glowing materials keep their surface and emit the emissive factor times the
+// emissive texture on top, which makes their meshes lights. Also returns the UV
+// set the textures are mapped with: meshes only keep one, the base colour's if
+// there is a base colour texture, so textures on other sets get a warning.
+fn convert_material(material: &gltf::Material, images: &[Image], path: &Path) -> (Material, u32) {
+    let mut uv_set = None;
+    // Colours are stored sRGB encoded, other data linear.
+    let mut image = |texture: gltf::Texture, tex_coord: u32, srgb: bool| -> Texture {
+        match uv_set {
+            Some(set) if set != tex_coord => eprintln!(
+                "{}: Material {} maps textures with UV sets {} and {}, using {} for all of them",
+                path.display(),
+                material.index().unwrap_or(0),
+                set,
+                tex_coord,
+                set
+            ),
+            Some(_) => {}
+            None => uv_set = Some(tex_coord),
+        }
+        let sampler = texture.sampler();
+        let image = images[texture.source().index()].clone();
+        Texture::Image(
+            image
+                .with_wrap(to_wrap(sampler.wrap_s()), to_wrap(sampler.wrap_t()))
+                .with_srgb(srgb),
+        )
+    };
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, _] = pbr.base_color_factor();
+    let base_color_texture = pbr
+        .base_color_texture()
+        .map(|info| image(info.texture(), info.tex_coord(), true));
+    let metallic_roughness_texture = pbr
+        .metallic_roughness_texture()
+        .map(|info| image(info.texture(), info.tex_coord(), false));
+    let normal_texture = material
+        .normal_texture()
+        .map(|normal| image(normal.texture(), normal.tex_coord(), false));
+    let emission_texture = material
+        .emissive_texture()
+        .map(|info| image(info.texture(), info.tex_coord(), true));
+    let material = Material::Microfacet(Box::new(Microfacet {
+        base_color: to_vec3([r, g, b]),
+        base_color_texture,
+        metallic: pbr.metallic_factor() as f64,
+        roughness: pbr.roughness_factor() as f64,
+        metallic_roughness_texture,
+        normal_texture,
+        emission: to_vec3(material.emissive_factor()),
+        emission_texture,
+    }));
+    (material, uv_set.unwrap_or(0))
+}
+// What glTF uses for primitives without a material.
+fn default_material() -> Material {
+    Material::Microfacet(Box::new(Microfacet {
+        base_color: Color::WHITE,
+        base_color_texture: None,
+        metallic: 1.0,
+        roughness: 1.0,
+        metallic_roughness_texture: None,
+        normal_texture: None,
+        emission: Color::BLACK,
+        emission_texture: None,
+    }))
+}
+
+// The triangle primitives of a mesh, moved to world space. Points and lines have
+// no area and are left out.
+fn convert_mesh(
+    mesh: &gltf::Mesh,
+    transform: &Matrix4,
+    buffers: &[Vec<u8>],
+    materials: &[(Material, u32)],
+    path: &Path,
+) -> io::Result<Vec<TriangleMesh>> {
+    // Nodes scaled to nothing are how some exporters hide meshes.
+    let inverse = match transform.inverse() {
+        Some(inverse) => inverse,
+        None => return Ok(Vec::new()),
+    };
+    // Mirroring turns the winding of the triangles and the handedness of their
+    // tangents around.
+    let mirrored = transform.determinant3() < 0.0;
+    let mut meshes = Vec::new();
+    for primitive in mesh.primitives() {
+        let error = |message: &str| {
+            let message = format!(
+                "Mesh {} primitive {}: {}",
+                mesh.index(),
+                primitive.index(),
+                message
+            );
+            invalid_data(path, &message)
+        };
+        let (material, uv_set) = match primitive.material().index() {
+            Some(index) => materials[index].clone(),
+            None => (default_material(), 0),
+        };
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+        let positions: Vec<Point3> = match reader.read_positions() {
+            Some(positions) => positions
+                .map(|p| transform.transform_point(&to_vec3(p)))
+                .collect(),
+            None => return Err(error("No positions")),
+        };
+        if let Some(index) = positions.iter().position(|p| !p.is_finite()) {
+            return Err(error(&format!("Position {} is not finite", index)));
+        }
+        let normals: Vec<Vec3> = reader
+            .read_normals()
+            .map(|normals| {
+                normals
+                    .map(|n| normalize(&inverse.transform_normal(&to_vec3(n))))
+                    .collect()
+            })
+            .unwrap_or_default();
+        // glTF puts v = 0 at the top of images.
+        let uvs: Vec<(f64, f64)> = reader
+            .read_tex_coords(uv_set)
+            .map(|uvs| {
+                uvs.into_f32()
+                    .map(|[u, v]| (u as f64, 1.0 - v as f64))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let tangents: Vec<[f64; 4]> = reader
+            .read_tangents()
+            .map(|tangents| {
+                tangents
+                    .map(|[x, y, z, w]| {
+                        let t = transform.transform_vector(&to_vec3([x, y, z]));
+                        let w = if mirrored { -w } else { w };
+                        [t.x(), t.y(), t.z(), w as f64]
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        for (name, count) in [
+            ("normals", normals.len()),
+            ("UVs", uvs.len()),
+            ("tangents", tangents.len()),
+        ] {
+            if count != 0 && count != positions.len() {
+                return Err(error(&format!(
+                    "{} {} for {} positions",
+                    count,
+                    name,
+                    positions.len()
+                )));
+            }
+        }
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        if indices
+            .iter()
+            .any(|index| *index as usize >= positions.len())
+        {
+            return Err(error("Index out of range"));
+        }
+        let mut triangles: Vec<[u32; 3]> = match primitive.mode() {
+            Mode::Triangles => indices
+                .chunks_exact(3)
+                .map(|corners| [corners[0], corners[1], corners[2]])
+                .collect(),
+            // Every other triangle of a strip goes the other way around.
+            Mode::TriangleStrip => indices
+                .windows(3)
+                .enumerate()
+                .map(|(i, corners)| match i % 2 {
+                    0 => [corners[0], corners[1], corners[2]],
+                    _ => [corners[1], corners[0], corners[2]],
+                })
+                .collect(),
+            Mode::TriangleFan => indices
+                .windows(2)
+                .skip(1)
+                .map(|corners| [indices[0], corners[0], corners[1]])
+                .collect(),
+            _ => Vec::new(),
+        };
+        triangles.retain(|[a, b, c]| a != b && b != c && a != c);
+        if triangles.is_empty() {
+            continue;
+        }
+        if mirrored {
+            for triangle in &mut triangles {
+                triangle.swap(1, 2);
+            }
+        }
+        meshes.push(TriangleMesh::new(
+            positions,
+            normals,
+            uvs,
+            tangents,
+            Vec::new(),
+            triangles,
+            material,
+        ));
+    }
+    Ok(meshes)
+}
+
+// glTF cameras look down their -z axis with +y up. The image keeps the aspect
+// ratio it is rendered at, not the one in the file.
+fn convert_camera(camera: &gltf::Camera, transform: &Matrix4, aspect_ratio: f64) -> Camera {
+    let look_from = transform.transform_point(&Point3::new(0.0, 0.0, 0.0));
+    let look_at = transform.transform_point(&Point3::new(0.0, 0.0, -1.0));
+    let up = transform.transform_vector(&Vec3::UP);
+    match camera.projection() {
+        Projection::Perspective(perspective) => Camera::new(
+            look_from,
+            look_at,
+            up,
+            (perspective.yfov() as f64).to_degrees(),
+            aspect_ratio,
+            0.0,
+            1.0,
+            (0.0, 1.0),
+        ),
+        Projection::Orthographic(orthographic) => Camera::orthographic(
+            look_from,
+            look_at,
+            up,
+            2.0 * orthographic.ymag() as f64,
+            aspect_ratio,
+            (0.0, 1.0),
+        ),
+    }
+}
+// For scenes without a camera: looks down -z at the bounding sphere of the
+// whole scene, the way glTF viewers open files.
+fn framing_camera(objects: &HittableList, aspect_ratio: f64) -> Camera {
+    let (center, radius) = match objects.bounding_box(0.0, 1.0) {
+        Some(bounds) => (
+            (*bounds.min() + *bounds.max()) / 2.0,
+            (*bounds.max() - *bounds.min()).length() / 2.0,
+        ),
+        None => (Point3::new(0.0, 0.0, 0.0), 1.0),
+    };
+    let fov = 40.0;
+    let half_height = degrees_to_radians(fov / 2.0).tan();
+    let half_angle = half_height.min(half_height * aspect_ratio).atan();
+    let look_from = center + Vec3::new(0.0, 0.0, radius / half_angle.sin());
+    Camera::new(
+        look_from,
+        center,
+        Vec3::UP,
+        fov,
+        aspect_ratio,
+        0.0,
+        1.0,
+        (0.0, 1.0),
+    )
+}
+
+// Loads the default scene of a .gltf or .glb file (or its first one), with the
+// camera of the first node that has one.
+pub fn load_gltf<P: AsRef<Path>>(path: P, aspect_ratio: f64) -> io::Result<(HittableList, Camera)> {
+    let path = path.as_ref();
+    let Gltf { document, blob } = Gltf::open(path).map_err(|error| match error {
+        gltf::Error::Io(error) => {
+            io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+        }
+        error => invalid_data(path, &error.to_string()),
+    })?;
+    let buffers = load_buffers(&document, blob, path)?;
+    let images = load_images(&document, &buffers, path)?;
+    let materials = document
+        .materials()
+        .map(|material| convert_material(&material, &images, path))
+        .collect::<Vec<(Material, u32)>>();
+    let scene = match document.default_scene() {
+        Some(scene) => scene,
+        None => document
+            .scenes()
+            .next()
+            .ok_or_else(|| invalid_data(path, "No scene"))?,
+    };
+
+    // Depth first, in the order the nodes are listed.
+    let mut objects = HittableList::new();
+    let mut camera = None;
+    let mut visited = vec![false; document.nodes().len()];
+    let mut stack: Vec<(Node, Matrix4)> = scene
+        .nodes()
+        .map(|node| (node, Matrix4::IDENTITY))
+        .collect();
+    stack.reverse();
+    while let Some((node, parent)) = stack.pop() {
+        if std::mem::replace(&mut visited[node.index()], true) {
+            let message = format!("Node {} has more than one parent", node.index());
+            return Err(invalid_data(path, &message));
+        }
+        let local = node.transform().matrix();
+        let transform = parent * Matrix4::from_columns(local.map(|column| column.map(f64::from)));
+        if let Some(mesh) = node.mesh() {
+            for mesh in convert_mesh(&mesh, &transform, &buffers, &materials, path)? {
+                objects.add(Hittable::Mesh(Arc::new(mesh)));
+            }
+        }
+        if let (None, Some(node_camera)) = (&camera, node.camera()) {
+            camera = Some(convert_camera(&node_camera, &transform, aspect_ratio));
+        }
+        let mut children: Vec<(Node, Matrix4)> =
+            node.children().map(|child| (child, transform)).collect();
+        children.reverse();
+        stack.extend(children);
+    }
+    let camera = camera.unwrap_or_else(|| framing_camera(&objects, aspect_ratio));
+    Ok((objects, camera))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ray::*, sampler::*};
+
+    // One triangle in the z = 0 plane with UVs, and a 1x1 grey image.
+    const BUFFER: &str =
+        "AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/";
+    const IMAGE: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR4nGPYs2cPAARsAjVj7DyMAAAAAElFTkSuQmCC";
+
+    fn write_scene(name: &str, material: &str) -> std::path::PathBuf {
+        let scene = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scene": 0,
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [{{"mesh": 0}}],
+                "meshes": [{{"primitives": [{{
+                    "attributes": {{"POSITION": 0, "TEXCOORD_0": 1}},
+                    "material": 0
+                }}]}}],
+                "materials": [{}],
+                "textures": [{{"source": 0}}],
+                "images": [{{"uri": "data:image/png;base64,{}"}}],
+                "buffers": [{{"byteLength": 60, "uri": "data:application/octet-stream;base64,{}"}}],
+                "bufferViews": [
+                    {{"buffer": 0, "byteOffset": 0, "byteLength": 36}},
+                    {{"buffer": 0, "byteOffset": 36, "byteLength": 24}}
+                ],
+                "accessors": [
+                    {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                      "min": [0, 0, 0], "max": [1, 1, 0]}},
+                    {{"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2"}}
+                ]
+            }}"#,
+            material, IMAGE, BUFFER
+        );
+        let path = std::env::temp_dir().join(format!("raytrace_rs_gltf_{}_test.gltf", name));
+        fs::write(&path, scene).unwrap();
+        path
+    }
+    // What the camera sees straight down on the triangle.
+    fn surface(objects: &HittableList) -> (Material, Color) {
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, 0, (0, 0), 1);
+        let ray = Ray::new(
+            Point3::new(0.25, 0.25, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            None,
+        );
+        let hit = objects.hit(&ray, 0.001, INIFINITY, &mut sampler).unwrap();
+        let (u, v) = hit.uv();
+        let emission = emitted(hit.material(), u, v, hit.point());
+        (hit.material().clone(), emission)
+    }
+
+    #[test]
+    fn emissive_materials_glow_on_top_of_their_surface() {
+        let path = write_scene(
+            "emissive",
+            r#"{
+                "pbrMetallicRoughness": {"baseColorFactor": [0.8, 0.8, 0.8, 1], "metallicFactor": 0},
+                "emissiveFactor": [2, 1, 0.5],
+                "emissiveTexture": {"index": 0}
+            }"#,
+        );
+        let (objects, _) = load_gltf(&path, 1.0).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!objects.lights().is_empty());
+        let (material, emission) = surface(&objects);
+        match &material {
+            Material::Microfacet(microfacet) => {
+                assert_eq!(microfacet.base_color.x(), 0.8f32 as f64);
+                assert_eq!(microfacet.metallic, 0.0);
+            }
+            _ => panic!("Emissive glTF materials should stay microfacet surfaces!"),
+        }
+        assert!(lobes(&material).is_non_specular());
+        // The grey texel decoded from sRGB.
+        let texel = 0.5028864580325687;
+        assert!((emission.x() - 2.0 * texel).abs() < 1e-6);
+        assert!((emission.y() - texel).abs() < 1e-6);
+        assert!((emission.z() - 0.5 * texel).abs() < 1e-6);
+    }
+
+    #[test]
+    fn textures_on_other_uv_sets_fall_back_to_the_base_colour_one() {
+        let path = write_scene(
+            "uv_sets",
+            r#"{
+                "pbrMetallicRoughness": {"baseColorTexture": {"index": 0, "texCoord": 0}},
+                "emissiveFactor": [1, 1, 1],
+                "emissiveTexture": {"index": 0, "texCoord": 1}
+            }"#,
+        );
+        let (objects, _) = load_gltf(&path, 1.0).unwrap();
+        fs::remove_file(&path).unwrap();
+        let (_, emission) = surface(&objects);
+        assert!((emission.x() - 0.5028864580325687).abs() < 1e-6);
+    }
+
+    #[test]
+    fn plain_materials_are_not_lights() {
+        let path = write_scene("plain", r#"{"pbrMetallicRoughness": {}}"#);
+        let (objects, _) = load_gltf(&path, 1.0).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(objects.lights().is_empty());
+        let (material, emission) = surface(&objects);
+        assert!(!is_emissive(&material));
+        assert_eq!((emission.x(), emission.y(), emission.z()), (0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,49 @@
+pub mod aabb;
+pub mod aov;
+pub mod bdpt;
+pub mod camera;
+pub mod checkpoint;
+pub mod denoise;
+pub mod filter;
+pub mod geometry;
+pub mod gltf_import;
+pub mod material;
+pub mod math;
+pub mod mesh;
+pub mod noise;
+pub mod obj;
+pub mod output;
+pub mod photon;
+pub mod ply;
+pub mod ray;
+pub mod render;
+pub mod sampler;
+pub mod scene;
+pub mod stats;
+pub mod texture;
+pub mod tonemap;
+pub mod transform;
+
+pub use aov::{render_aovs, Aov, AovBuffers};
+pub use bdpt::bdpt_color;
+pub use camera::Camera;
+pub use denoise::{denoise, DenoiseSettings};
+pub use filter::Filter;
+pub use geometry::{get_bounding_box, ray_cast, Cuboid, Hittable, HittableList};
+pub use gltf_import::load_gltf;
+pub use material::{albedo, emitted, eval, pdf, sample, BsdfSample, Lobes, Material, Microfacet};
+pub use math::{Color, Point3, Vec3};
+pub use mesh::{Triangle, TriangleMesh};
+pub use obj::load_obj;
+pub use output::{
+    save_display_image, save_image, save_image_with_format, Framebuffer, OutputFormat,
+};
+pub use photon::{photon_color, PhotonSettings};
+pub use ply::load_ply;
+pub use ray::{Ray, RayHit};
+pub use render::{ray_color, render, Integrator, MisHeuristic, RenderSettings};
+pub use sampler::{Sampler, SamplerKind};
+pub use stats::{PhaseTimes, Stats};
+pub use texture::{sample_hit_texture, sample_texture, Image, Texture};
+pub use tonemap::{DisplaySettings, ToneMapper};
+pub use transform::Transform;
@@ -1,85 +1,206 @@
-const ASPECT_RATIO: f64 = 1.0 / 1.0;
-const IMAGE_WIDTH: i32 = 800;
-const IMAGE_HEIGHT: i32 = (IMAGE_WIDTH as f64 / ASPECT_RATIO) as i32;
-const SAMPLES_PER_PIXEL: i32 = 800;
-const MAX_DEPTH: i32 = 32;
+mod cli;
+use raytrace_rs::render::{self, render_progressive, RenderState};
+use raytrace_rs::{aov, denoise, render_aovs, DenoiseSettings, Framebuffer};
+use raytrace_rs::{checkpoint::Checkpoint, math::Rng, scene, stats, PhaseTimes};
+use raytrace_rs::{save_display_image, save_image};
+use std::time::Instant;
 
-mod aabb;
-mod camera;
-mod geometry;
-mod material;
-mod math;
-mod noise;
-mod ray;
-mod scene;
-mod texture;
-use {camera::*, geometry::*, material::*, math::*, ray::*};
-
-fn write_color(color: &Color, samples_per_pixel: i32) {
-    let scale = 1.0 / samples_per_pixel as f64;
-    let r = 255.0 * clamp((color.x() * scale).sqrt(), 0.0, 1.0);
-    let g = 255.0 * clamp((color.y() * scale).sqrt(), 0.0, 1.0);
-    let b = 255.0 * clamp((color.z() * scale).sqrt(), 0.0, 1.0);
-    println!("{} {} {}", r as i32, g as i32, b as i32);
+fn save(framebuffer: &Framebuffer, options: &cli::Options) -> bool {
+    let format = options.output_format().unwrap();
+    let display = options.display_settings();
+    match save_display_image(framebuffer, &options.output, format, &display) {
+        Ok(_) => true,
+        Err(error) => {
+            eprintln!("Could not save {}: {}", options.output.display(), error);
+            false
+        }
+    }
 }
 
-fn ray_color(ray: &Ray, background: &Color, world: &HittableList, depth: i32) -> Color {
-    // If we've exceeded the ray bounce limit, no more light is gathered.
-    if depth <= 0 {
-        return Color::BLACK;
+fn save_checkpoint(state: &RenderState, options: &cli::Options) {
+    if let Some(path) = &options.checkpoint {
+        if let Err(error) = options.checkpoint(state).save(path) {
+            eprintln!("Could not save checkpoint {}: {}", path.display(), error);
+        }
     }
-    // Keep bouncing until the ray gathers enough light.
-    // If the ray hits nothing, return the background color.
-    match world.hit(ray, 0.001, INIFINITY) {
-        Some(hit) => {
-            let emitted = emitted(hit.material(), hit.uv().0, hit.uv().1, hit.point());
-            match scatter(hit.material(), ray, &hit) {
-                Some((scattered, attenuation)) => {
-                    emitted + attenuation * ray_color(&scattered, background, world, depth - 1)
-                }
-                None => emitted,
-            }
+}
+
+// Prints the statistics of the run, and writes them as JSON if asked to.
+fn report_stats(phases: &PhaseTimes, options: &cli::Options) {
+    let stats = stats::totals();
+    eprintln!("{}", stats.report(phases));
+    let result = match &options.stats_json {
+        Some(path) if path.as_os_str() == "-" => {
+            println!("{}", stats.to_json(phases));
+            Ok(())
         }
-        None => *background,
+        Some(path) => std::fs::write(path, stats.to_json(phases) + "\n"),
+        None => Ok(()),
+    };
+    if let Err(error) = result {
+        eprintln!("Could not save statistics: {}", error);
     }
 }
 
 fn main() {
-    // Header
-    println!("P3");
-    println!("{} {}", IMAGE_WIDTH, IMAGE_HEIGHT);
-    println!("255");
+    let mut options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", cli::usage());
+            return;
+        }
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, cli::usage());
+            std::process::exit(2);
+        }
+    };
+    let mut state = None;
+    if let Some(path) = options.resume.clone() {
+        match Checkpoint::load(&path) {
+            Ok(checkpoint) => {
+                if let Err(message) = options.resume_from(&checkpoint) {
+                    eprintln!("Could not resume from {}: {}", path.display(), message);
+                    std::process::exit(1);
+                }
+                eprintln!(
+                    "Resuming {} at {:.1} spp",
+                    checkpoint.scene,
+                    checkpoint.state.accumulator.average_samples()
+                );
+                state = Some(checkpoint.state);
+            }
+            Err(error) => {
+                eprintln!("Could not load checkpoint {}: {}", path.display(), error);
+                std::process::exit(1);
+            }
+        }
+    }
 
     // World
-    let (world, camera, background) = scene::final_scene();
+    let scene_start = Instant::now();
+    let mut rng = Rng::new(options.seed);
+    let (world, camera, background) = if scene::is_scene_file(&options.scene) {
+        match scene::from_file(&options.scene, options.aspect_ratio(), &mut rng) {
+            Ok(scene) => scene,
+            Err(error) => {
+                eprintln!("Could not load scene: {}", error);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        scene::from_name(&options.scene, options.aspect_ratio(), &mut rng).unwrap()
+    };
+    let bvh = stats::totals().bvh_build_seconds;
+    let mut phases = PhaseTimes {
+        scene: scene_start.elapsed().as_secs_f64() - bvh,
+        bvh,
+        ..PhaseTimes::default()
+    };
 
     // Render
-    let start_time = chrono::Local::now();
-    for j in (0..IMAGE_HEIGHT).rev() {
-        let mut threads = Vec::new();
-        eprintln!("Scan line {} / {}", IMAGE_HEIGHT - j, IMAGE_HEIGHT);
-        for i in 0..IMAGE_WIDTH {
-            let u = (i as f64 + random_double()) / (IMAGE_WIDTH - 1) as f64;
-            let v = (j as f64 + random_double()) / (IMAGE_HEIGHT - 1) as f64;
-            let ray = camera.get_ray(u, v);
-            let world = world.clone();
-            let handle = std::thread::spawn(move || -> Color {
-                let mut pixel_color = Color::BLACK;
-                for _ in 0..SAMPLES_PER_PIXEL {
-                    pixel_color = pixel_color + ray_color(&ray, &background, &world, MAX_DEPTH)
+    let settings = options.render_settings();
+    let render_start = Instant::now();
+    let framebuffer = match options.progressive_settings() {
+        Some(progressive) => {
+            if options.checkpoint.is_some() {
+                // The first Ctrl-C lets the current pass wind down so the
+                // checkpoint can be written, a second one quits right away.
+                ctrlc::set_handler(|| {
+                    if render::is_interrupted() {
+                        std::process::exit(130);
+                    }
+                    eprintln!("Interrupted, saving checkpoint...");
+                    render::interrupt();
+                })
+                .unwrap();
+            }
+            let mut state =
+                state.unwrap_or_else(|| RenderState::new(settings.width, settings.height));
+            let snapshot = |state: &RenderState| {
+                if save(&state.accumulator.to_framebuffer(), &options) {
+                    eprintln!("Snapshot after {} passes", state.passes);
                 }
-                pixel_color
-            });
-            threads.push(handle);
+                save_checkpoint(state, &options);
+            };
+            let completed = render_progressive(
+                &world,
+                &camera,
+                &background,
+                &settings,
+                &progressive,
+                &mut state,
+                snapshot,
+            );
+            save_checkpoint(&state, &options);
+            if !completed {
+                save(&state.accumulator.to_framebuffer(), &options);
+                eprintln!(
+                    "Stopped at {:.1} spp, continue with --resume",
+                    state.accumulator.average_samples()
+                );
+                std::process::exit(130);
+            }
+            if let Some(path) = &options.sample_heatmap {
+                if let Err(error) = save_image(&state.accumulator.sample_heatmap(), path) {
+                    eprintln!("Could not save {}: {}", path.display(), error);
+                }
+            }
+            state.accumulator.to_framebuffer()
         }
-        for thread in threads {
-            let pixel_color = thread.join().unwrap();
-            write_color(&pixel_color, SAMPLES_PER_PIXEL);
+        None => render::render(&world, &camera, &background, &settings),
+    };
+    let rendered_aovs = options.rendered_aovs();
+    if rendered_aovs.is_empty() {
+        phases.render = render_start.elapsed().as_secs_f64();
+        let output_start = Instant::now();
+        if !save(&framebuffer, &options) {
+            std::process::exit(1);
         }
+        eprintln!("Saved {}", options.output.display());
+        phases.output = output_start.elapsed().as_secs_f64();
+        report_stats(&phases, &options);
+        return;
+    }
+
+    // AOVs
+    let mut aovs = match render_aovs(
+        &world,
+        &camera,
+        &settings,
+        &rendered_aovs,
+        options.aov_samples,
+    ) {
+        Some(aovs) => aovs,
+        None => std::process::exit(130),
+    };
+    let framebuffer = if options.denoise {
+        eprintln!("Denoising...");
+        denoise(
+            &framebuffer,
+            &aovs,
+            &DenoiseSettings {
+                threads: options.threads,
+                ..DenoiseSettings::default()
+            },
+        )
+    } else {
+        framebuffer
+    };
+    aovs.retain(&options.aovs);
+    phases.render = render_start.elapsed().as_secs_f64();
+    let output_start = Instant::now();
+    let result = if options.aov_layers {
+        aov::save_exr_layers(&framebuffer, &aovs, &options.output)
+    } else if save(&framebuffer, &options) {
+        aov::save_aovs(&aovs, &options.output, options.output_format().unwrap())
+    } else {
+        std::process::exit(1);
+    };
+    if let Err(error) = result {
+        eprintln!("Could not save AOVs: {}", error);
+        std::process::exit(1);
     }
-    let end_time = chrono::Local::now();
-    eprintln!(
-        "Elapsed Time: {:?}",
-        end_time.signed_duration_since(start_time)
-    );
+    eprintln!("Saved {}", options.output.display());
+    phases.output = output_start.elapsed().as_secs_f64();
+    report_stats(&phases, &options);
 }
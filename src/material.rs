@@ -1,4 +1,4 @@
-use crate::{math::*, ray::*, texture::*};
+use crate::{math::*, ray::*, sampler::*, texture::*};
 
 #[derive(Clone)]
 pub enum Material {
@@ -7,72 +7,540 @@ pub enum Material {
     Dielectric(f64),
     DiffuseLight(Texture),
     Isotropic(Texture),
+    Microfacet(Box<Microfacet>),
 }
 
-pub fn scatter(material: &Material, ray: &Ray, hit: &RayHit) -> Option<(Ray, Color)> {
+// Microfacet
+// The metallic-roughness model of glTF: a GGX specular lobe with Schlick's
+// Fresnel over a Lambertian base, which metals don't have. Textures, when
+// given, scale the factors next to them.
+#[derive(Clone)]
+pub struct Microfacet {
+    pub base_color: Color,
+    pub base_color_texture: Option<Texture>,
+    pub metallic: f64,
+    pub roughness: f64,
+    // Roughness in the green channel, metallic in the blue one.
+    pub metallic_roughness_texture: Option<Texture>,
+    // Tangent space normals, followed by meshes with UVs.
+    pub normal_texture: Option<Texture>,
+    // Light the surface gives off on top of what it reflects, the texture scales
+    // it like the other ones.
+    pub emission: Color,
+    pub emission_texture: Option<Texture>,
+}
+impl Microfacet {
+    // Base colour, metallic and GGX alpha at a hit.
+    fn at(&self, hit: &RayHit) -> (Color, f64, f64) {
+        let mut base_color = self.base_color;
+        if let Some(texture) = &self.base_color_texture {
+            base_color = base_color * sample_hit_texture(texture, hit);
+        }
+        let (mut metallic, mut roughness) = (self.metallic, self.roughness);
+        if let Some(texture) = &self.metallic_roughness_texture {
+            let texel = sample_hit_texture(texture, hit);
+            roughness *= texel.y();
+            metallic *= texel.z();
+        }
+        // Perfectly smooth surfaces would need a specular lobe of their own.
+        let alpha = (roughness * roughness).max(1e-3);
+        (base_color, clamp(metallic, 0.0, 1.0), alpha)
+    }
+}
+
+// Lobes
+// Kinds of scattering a material does, as a set of flags. Diffuse lobes spread
+// light over the whole hemisphere (or sphere), glossy ones around a preferred
+// direction, and specular ones in a single direction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Lobes(u8);
+impl Lobes {
+    pub const NONE: Lobes = Lobes(0);
+    pub const DIFFUSE: Lobes = Lobes(1);
+    pub const GLOSSY: Lobes = Lobes(1 << 1);
+    pub const SPECULAR: Lobes = Lobes(1 << 2);
+
+    pub fn contains(&self, other: Lobes) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn union(&self, other: Lobes) -> Lobes {
+        Lobes(self.0 | other.0)
+    }
+    // Whether `eval` and `pdf` can be non-zero, i.e. whether lights can be
+    // sampled instead.
+    pub fn is_non_specular(&self) -> bool {
+        self.0 & (Lobes::DIFFUSE.0 | Lobes::GLOSSY.0) != 0
+    }
+}
+
+// BsdfSample
+// A direction picked by `sample`. `f` is the BSDF times the cosine at the
+// surface, as from `eval`, and `pdf` the solid angle density it was picked with.
+// Specular lobes have no density, so for those `pdf` is the probability of
+// picking this direction among the few possible ones instead.
+#[derive(Debug, Copy, Clone)]
+pub struct BsdfSample {
+    pub direction: Vec3,
+    pub f: Color,
+    pub pdf: f64,
+    pub lobe: Lobes,
+}
+impl BsdfSample {
+    // What the path throughput gets multiplied by.
+    pub fn weight(&self) -> Color {
+        self.f / self.pdf
+    }
+}
+
+// Directions follow the usual BSDF convention: `wo` points back along the
+// incoming ray, and `wi` away from the surface toward where the light comes
+// from. Both are unit vectors.
+pub fn lobes(material: &Material) -> Lobes {
     match material {
-        Material::Lambertian(texture) => {
-            let scatter_direction = *hit.normal() + Vec3::random_unit_vector();
-            let scattered = Ray::new(*hit.point(), scatter_direction, None);
-            let attenuation = sample_texture(&texture, hit.uv().0, hit.uv().1, hit.point());
-            Some((scattered, attenuation))
+        Material::Lambertian(_) | Material::Isotropic(_) => Lobes::DIFFUSE,
+        Material::Metal(_, roughness) if *roughness > 0.0 => Lobes::GLOSSY,
+        Material::Microfacet(_) => Lobes::DIFFUSE.union(Lobes::GLOSSY),
+        Material::Metal(..) | Material::Dielectric(_) => Lobes::SPECULAR,
+        Material::DiffuseLight(_) => Lobes::NONE,
+    }
+}
+// Picks a direction to continue a path in. None if the light is absorbed.
+pub fn sample(
+    material: &Material,
+    hit: &RayHit,
+    wo: &Vec3,
+    sampler: &mut Sampler,
+) -> Option<BsdfSample> {
+    let non_specular = |direction: Vec3, lobe: Lobes| -> Option<BsdfSample> {
+        let wi = normalize(&direction);
+        let pdf = pdf(material, hit, wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            direction: wi,
+            f: eval(material, hit, wo, &wi),
+            pdf,
+            lobe,
+        })
+    };
+    match material {
+        Material::Lambertian(_) => {
+            let direction = *hit.normal() + sample_unit_vector(sampler.get_2d());
+            non_specular(direction, Lobes::DIFFUSE)
         }
         Material::Metal(albedo, roughness) => {
-            let reflected = reflect(&normalize(ray.direction()), hit.normal());
-            let scattered = Ray::new(
-                *hit.point(),
-                reflected + Vec3::random_in_unit_sphere() * *roughness,
-                None,
-            );
-            let attenuation = *albedo;
-            if dot(scattered.direction(), hit.normal()) > 0.0 {
-                Some((scattered, attenuation))
-            } else {
-                None
+            let reflected = reflect(&(-*wo), hit.normal());
+            if *roughness <= 0.0 {
+                return Some(BsdfSample {
+                    direction: reflected,
+                    f: *albedo,
+                    pdf: 1.0,
+                    lobe: Lobes::SPECULAR,
+                });
             }
+            let direction =
+                reflected + sample_unit_ball(sampler.get_2d(), sampler.get_1d()) * *roughness;
+            if dot(&direction, hit.normal()) <= 0.0 {
+                return None;
+            }
+            non_specular(direction, Lobes::GLOSSY)
         }
         Material::Dielectric(refractive_index) => {
-            let attenuation = Color::WHITE;
             let etai_over_etat = if hit.front_face() {
                 1.0 / refractive_index
             } else {
                 *refractive_index
             };
-            let unit_direction = normalize(ray.direction());
-
-            let cos_theta = dot(&(-unit_direction), hit.normal()).min(1.0);
+            let unit_direction = -*wo;
+            let cos_theta = dot(wo, hit.normal()).min(1.0);
             let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-            let reflect_prob = schlick(cos_theta, etai_over_etat);
+            let reflected = BsdfSample {
+                direction: reflect(&unit_direction, hit.normal()),
+                f: Color::WHITE,
+                pdf: 1.0,
+                lobe: Lobes::SPECULAR,
+            };
             // Total Internal Reflection
             if etai_over_etat * sin_theta > 1.0 {
-                let reflected = reflect(&unit_direction, hit.normal());
-                let scattered = Ray::new(*hit.point(), reflected, None);
-                Some((scattered, attenuation))
-            } else if random_double() < reflect_prob {
-                let reflected = reflect(&unit_direction, hit.normal());
-                let scattered = Ray::new(*hit.point(), reflected, None);
-                Some((scattered, attenuation))
+                return Some(reflected);
+            }
+            let reflect_prob = schlick(cos_theta, etai_over_etat);
+            if sampler.get_1d() < reflect_prob {
+                Some(BsdfSample {
+                    f: Color::WHITE * reflect_prob,
+                    pdf: reflect_prob,
+                    ..reflected
+                })
             } else {
-                let refracted = refract(&unit_direction, hit.normal(), etai_over_etat);
-                let scattered = Ray::new(*hit.point(), refracted, None);
-                Some((scattered, attenuation))
+                Some(BsdfSample {
+                    direction: refract(&unit_direction, hit.normal(), etai_over_etat),
+                    f: Color::WHITE * (1.0 - reflect_prob),
+                    pdf: 1.0 - reflect_prob,
+                    lobe: Lobes::SPECULAR,
+                })
+            }
+        }
+        Material::Isotropic(_) => {
+            non_specular(sample_unit_vector(sampler.get_2d()), Lobes::DIFFUSE)
+        }
+        Material::Microfacet(microfacet) => {
+            let (base_color, metallic, alpha) = microfacet.at(hit);
+            let normal = hit.normal();
+            let (a, b) = orthonormal_basis(normal);
+            let u = sampler.get_2d();
+            if sampler.get_1d() < specular_probability(&base_color, metallic, dot(wo, normal)) {
+                // Half vectors are picked with density D(h) cos(theta_h).
+                let alpha2 = alpha * alpha;
+                let cos_theta = ((1.0 - u.0) / (1.0 + (alpha2 - 1.0) * u.0)).sqrt();
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * u.1;
+                let half =
+                    a * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + *normal * cos_theta;
+                non_specular(reflect(&(-*wo), &half), Lobes::GLOSSY)
+            } else {
+                let local = sample_cosine_hemisphere(u);
+                let direction = a * local.x() + b * local.y() + *normal * local.z();
+                non_specular(direction, Lobes::DIFFUSE)
+            }
+        }
+        Material::DiffuseLight(_) => None,
+    }
+}
+// The BSDF times the cosine at the surface, zero for specular lobes.
+pub fn eval(material: &Material, hit: &RayHit, wo: &Vec3, wi: &Vec3) -> Color {
+    match material {
+        Material::Lambertian(texture) | Material::Isotropic(texture) => {
+            sample_hit_texture(texture, hit) * pdf(material, hit, wo, wi)
+        }
+        // Fuzzy metal is defined by how it is sampled, every sample is weighted
+        // by the albedo. That makes the BSDF the albedo times the density over
+        // the cosine toward `wi`, which isn't reciprocal: swapping the
+        // directions changes it by the ratio of their cosines. `adjoint_eval`
+        // swaps them itself, so light and importance still meet in the middle.
+        Material::Metal(albedo, _) => *albedo * pdf(material, hit, wo, wi),
+        Material::Microfacet(microfacet) => {
+            let (base_color, metallic, alpha) = microfacet.at(hit);
+            let (cos_out, cos_in) = (dot(hit.normal(), wo), dot(hit.normal(), wi));
+            if cos_out <= 0.0 || cos_in <= 0.0 {
+                return Color::BLACK;
+            }
+            let half = normalize(&(*wo + *wi));
+            let f0 = Color::new(0.04, 0.04, 0.04) * (1.0 - metallic) + base_color * metallic;
+            let fresnel = schlick_color(&f0, dot(wo, &half));
+            let alpha2 = alpha * alpha;
+            let specular = fresnel
+                * (ggx(dot(hit.normal(), &half), alpha2)
+                    * smith_g1(cos_out, alpha2)
+                    * smith_g1(cos_in, alpha2)
+                    / (4.0 * cos_out * cos_in));
+            let diffuse = (Color::WHITE - fresnel) * base_color * ((1.0 - metallic) / PI);
+            (specular + diffuse) * cos_in
+        }
+        _ => Color::BLACK,
+    }
+}
+// Solid angle density of `sample` picking `wi`, zero for specular lobes.
+pub fn pdf(material: &Material, hit: &RayHit, wo: &Vec3, wi: &Vec3) -> f64 {
+    match material {
+        Material::Lambertian(_) => dot(hit.normal(), wi).max(0.0) / PI,
+        Material::Metal(_, roughness) if *roughness > 0.0 => {
+            if dot(wi, hit.normal()) <= 0.0 {
+                return 0.0;
             }
+            let reflected = reflect(&(-*wo), hit.normal());
+            fuzzy_reflection_pdf(&reflected, *roughness, wi)
         }
-        Material::Isotropic(texture) => {
-            let scattered = Ray::new(
-                *hit.point(),
-                Vec3::random_in_unit_sphere(),
-                Some(ray.time()),
-            );
-            let attenuation = sample_texture(texture, hit.uv().0, hit.uv().1, hit.point());
-            Some((scattered, attenuation))
+        Material::Isotropic(_) => 1.0 / (4.0 * PI),
+        Material::Microfacet(microfacet) => {
+            let (base_color, metallic, alpha) = microfacet.at(hit);
+            let (cos_out, cos_in) = (dot(hit.normal(), wo), dot(hit.normal(), wi));
+            if cos_out <= 0.0 || cos_in <= 0.0 {
+                return 0.0;
+            }
+            let half = normalize(&(*wo + *wi));
+            let specular = ggx(dot(hit.normal(), &half), alpha * alpha) * dot(hit.normal(), &half)
+                / (4.0 * dot(wo, &half));
+            let p = specular_probability(&base_color, metallic, cos_out);
+            p * specular + (1.0 - p) * cos_in / PI
         }
-        _ => None,
+        _ => 0.0,
+    }
+}
+// The BSDF with its directions swapped, times the cosine toward `wi`: how
+// importance or photons scatter. `wo` points toward where they came from.
+pub fn adjoint_eval(hit: &RayHit, wo: &Vec3, wi: &Vec3) -> Color {
+    let f = eval(hit.material(), hit, wi, wo);
+    if let Material::Isotropic(_) = hit.material() {
+        return f;
     }
+    let (cos_in, cos_out) = (dot(hit.normal(), wo), dot(hit.normal(), wi));
+    if cos_in <= 0.0 || cos_out <= 0.0 {
+        return Color::BLACK;
+    }
+    f * (cos_out / cos_in)
 }
 pub fn emitted(material: &Material, u: f64, v: f64, p: &Point3) -> Color {
     match material {
         Material::DiffuseLight(texture) => sample_texture(&texture, u, v, p),
+        Material::Microfacet(microfacet) => match &microfacet.emission_texture {
+            Some(texture) => microfacet.emission * sample_texture(texture, u, v, p),
+            None => microfacet.emission,
+        },
         _ => Color::BLACK,
     }
 }
+// Whether `emitted` can be anything but black, i.e. whether surfaces with the
+// material are lights.
+pub fn is_emissive(material: &Material) -> bool {
+    match material {
+        Material::DiffuseLight(_) => true,
+        Material::Microfacet(microfacet) => luminance(&microfacet.emission) > 0.0,
+        _ => false,
+    }
+}
+// Lights emit the same from both sides, cosine weighted on each. Picks a
+// direction to leave the light point `hit` in, with its solid angle density.
+pub fn sample_emission(hit: &RayHit, sampler: &mut Sampler) -> (Vec3, f64) {
+    let side = if sampler.get_1d() < 0.5 { 1.0 } else { -1.0 };
+    let normal = *hit.normal() * side;
+    let local = sample_cosine_hemisphere(sampler.get_2d());
+    let (a, b) = orthonormal_basis(&normal);
+    let direction = a * local.x() + b * local.y() + normal * local.z();
+    (direction, local.z() / (2.0 * PI))
+}
+pub fn emission_pdf(normal: &Vec3, direction: &Vec3) -> f64 {
+    dot(normal, direction).abs() / (2.0 * PI)
+}
+// Fuzzy reflections go toward a uniform point in the ball of radius `roughness`
+// around the tip of the mirrored direction. The density of a direction is the
+// part of the ball the ray along it passes through, weighted by t^2.
+fn fuzzy_reflection_pdf(reflected: &Vec3, roughness: f64, direction: &Vec3) -> f64 {
+    let c = dot(direction, reflected);
+    let discriminant = c * c - 1.0 + roughness * roughness;
+    if discriminant <= 0.0 {
+        return 0.0;
+    }
+    let t_far = c + discriminant.sqrt();
+    if t_far <= 0.0 {
+        return 0.0;
+    }
+    let t_near = (c - discriminant.sqrt()).max(0.0);
+    (t_far.powi(3) - t_near.powi(3)) / (4.0 * PI * roughness.powi(3))
+}
+// GGX (Trowbridge-Reitz) distribution of microfacet normals.
+fn ggx(cos_theta: f64, alpha2: f64) -> f64 {
+    if cos_theta <= 0.0 {
+        return 0.0;
+    }
+    let d = cos_theta * cos_theta * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * d * d)
+}
+// Smith's masking term for GGX, for one direction.
+fn smith_g1(cosine: f64, alpha2: f64) -> f64 {
+    2.0 * cosine / (cosine + (alpha2 + (1.0 - alpha2) * cosine * cosine).sqrt())
+}
+fn schlick_color(f0: &Color, cosine: f64) -> Color {
+    let weight = (1.0 - cosine.max(0.0)).powi(5);
+    *f0 + (Color::WHITE - *f0) * weight
+}
+// How often a microfacet surface samples its specular lobe rather than its
+// diffuse one, by how much each lobe reflects at normal incidence.
+fn specular_probability(base_color: &Color, metallic: f64, cos_out: f64) -> f64 {
+    let f0 = Color::new(0.04, 0.04, 0.04) * (1.0 - metallic) + *base_color * metallic;
+    let specular = luminance(&schlick_color(&f0, cos_out));
+    let diffuse = (1.0 - specular) * (1.0 - metallic) * luminance(base_color);
+    if specular + diffuse <= 0.0 {
+        return 1.0;
+    }
+    specular / (specular + diffuse)
+}
+// Base colour of the surface, as seen by the albedo AOV.
+pub fn albedo(hit: &RayHit) -> Color {
+    match hit.material() {
+        Material::Lambertian(texture) => sample_hit_texture(texture, hit),
+        Material::Metal(albedo, _) => *albedo,
+        Material::Dielectric(_) => Color::WHITE,
+        Material::DiffuseLight(texture) => sample_hit_texture(texture, hit),
+        Material::Isotropic(texture) => sample_hit_texture(texture, hit),
+        Material::Microfacet(microfacet) => match &microfacet.base_color_texture {
+            Some(texture) => microfacet.base_color * sample_hit_texture(texture, hit),
+            None => microfacet.base_color,
+        },
+    }
+}
+// Position of the material kind in the `Material` enum.
+pub fn material_index(material: &Material) -> usize {
+    match material {
+        Material::Lambertian(_) => 0,
+        Material::Metal(..) => 1,
+        Material::Dielectric(_) => 2,
+        Material::DiffuseLight(_) => 3,
+        Material::Isotropic(_) => 4,
+        Material::Microfacet(_) => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn materials() -> Vec<Material> {
+        vec![
+            Material::Lambertian(Texture::SolidColor(Color::new(0.2, 0.5, 0.8))),
+            Material::Metal(Color::new(0.9, 0.6, 0.3), 0.3),
+            Material::Metal(Color::new(0.9, 0.6, 0.3), 0.8),
+            Material::Microfacet(Box::new(Microfacet {
+                base_color: Color::new(0.8, 0.3, 0.2),
+                base_color_texture: None,
+                metallic: 0.3,
+                roughness: 0.5,
+                metallic_roughness_texture: None,
+                normal_texture: None,
+                emission: Color::BLACK,
+                emission_texture: None,
+            })),
+        ]
+    }
+    // A hit on the z = 0 plane from the side `wo` points to.
+    fn hit_from<'a>(material: &'a Material, wo: &Vec3) -> RayHit<'a> {
+        let point = Point3::new(0.0, 0.0, 0.0);
+        let ray = Ray::new(point + *wo, -*wo, None);
+        RayHit::new(
+            &ray,
+            point,
+            1.0,
+            material,
+            Vec3::new(0.0, 0.0, 1.0),
+            (0.5, 0.5),
+        )
+    }
+    fn direction(theta_degrees: f64, phi_degrees: f64) -> Vec3 {
+        let (theta, phi) = (theta_degrees.to_radians(), phi_degrees.to_radians());
+        Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        )
+    }
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1e-9)
+    }
+    fn sampler(seed: u64) -> Sampler {
+        Sampler::new(SamplerKind::Independent, seed, 0, (0, 0), 1)
+    }
+    // Whatever the directions come out as, to check them against.
+    fn probe(wi: &Vec3) -> f64 {
+        wi.x().max(0.0) + wi.z() * wi.z()
+    }
+
+    #[test]
+    fn samples_follow_eval_and_pdf() {
+        for material in materials() {
+            for &theta in [0.0, 40.0, 75.0].iter() {
+                let wo = direction(theta, 30.0);
+                let hit = hit_from(&material, &wo);
+                let mut sampler = sampler(1);
+                let samples = 100_000;
+                let (mut kept, mut probed) = (0, 0.0);
+                for _ in 0..samples {
+                    let sample = match sample(&material, &hit, &wo, &mut sampler) {
+                        Some(sample) => sample,
+                        None => continue,
+                    };
+                    let wi = sample.direction;
+                    assert!(close(sample.pdf, pdf(&material, &hit, &wo, &wi)));
+                    let f = eval(&material, &hit, &wo, &wi);
+                    for c in 0..3 {
+                        assert!(close(sample.f[c], f[c]));
+                    }
+                    kept += 1;
+                    probed += probe(&wi);
+                }
+
+                // The density over the whole sphere adds up to the share of
+                // samples that weren't absorbed, and it weighs any function of
+                // the direction the way the samples do.
+                let mut rng = Rng::new(2);
+                let points = 400_000;
+                let (mut total, mut weighted) = (0.0, 0.0);
+                for _ in 0..points {
+                    let u = (rng.random_double(), rng.random_double());
+                    let wi = sample_unit_vector(u);
+                    let density = pdf(&material, &hit, &wo, &wi) * 4.0 * PI;
+                    total += density;
+                    weighted += density * probe(&wi);
+                }
+                let name = format!(
+                    "material {} at {} degrees",
+                    material_index(&material),
+                    theta
+                );
+                let (total, weighted) = (total / points as f64, weighted / points as f64);
+                let kept_share = kept as f64 / samples as f64;
+                assert!(
+                    (total - kept_share).abs() < 0.02,
+                    "{}: {} vs {}",
+                    name,
+                    total,
+                    kept_share
+                );
+                let probed = probed / samples as f64;
+                assert!(
+                    (weighted - probed).abs() < 0.02,
+                    "{}: {} vs {}",
+                    name,
+                    weighted,
+                    probed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn densities_integrate_to_one() {
+        // Straight on, no sample of these goes below the surface.
+        for material in &materials()[..2] {
+            let wo = Vec3::new(0.0, 0.0, 1.0);
+            let hit = hit_from(material, &wo);
+            let mut rng = Rng::new(3);
+            let points = 400_000;
+            let total: f64 = (0..points)
+                .map(|_| {
+                    let wi = sample_unit_vector((rng.random_double(), rng.random_double()));
+                    pdf(material, &hit, &wo, &wi) * 4.0 * PI
+                })
+                .sum();
+            let total = total / points as f64;
+            assert!((total - 1.0).abs() < 0.02, "{}", total);
+        }
+    }
+
+    #[test]
+    fn adjoint_eval_swaps_the_directions() {
+        for material in materials() {
+            let mut non_zero = 0;
+            for &(theta_out, theta_in) in [(10.0, 20.0), (35.0, 60.0), (70.0, 15.0)].iter() {
+                let wo = direction(theta_out, 10.0);
+                let wi = direction(theta_in, 190.0 - theta_out);
+                let hit = hit_from(&material, &wo);
+                // As BSDF values, without the cosine at the end.
+                let adjoint = adjoint_eval(&hit, &wo, &wi) / wi.z();
+                let swapped = eval(&material, &hit, &wi, &wo) / wo.z();
+                for c in 0..3 {
+                    assert!(close(adjoint[c], swapped[c]));
+                }
+                non_zero += (luminance(&swapped) > 0.0) as usize;
+                // Only fuzzy metal isn't reciprocal.
+                if let Material::Metal(..) = material {
+                    continue;
+                }
+                let forward = eval(&material, &hit, &wo, &wi) / wi.z();
+                for c in 0..3 {
+                    assert!(close(forward[c], swapped[c]));
+                }
+            }
+            assert!(non_zero > 0);
+        }
+    }
+}
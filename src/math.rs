@@ -9,17 +9,6 @@ pub const PI: f64 = std::f64::consts::PI;
 pub fn degrees_to_radians(degrees: f64) -> f64 {
     return degrees * PI / 180.0;
 }
-pub fn random_range_int(min: i32, max: i32) -> i32 {
-    random_range_double(min as f64, (max + 1) as f64) as i32
-}
-pub fn random_double() -> f64 {
-    // Returns a random real in [0,1).
-    return rand::random();
-}
-pub fn random_range_double(min: f64, max: f64) -> f64 {
-    // Returns a random real in [min,max).
-    return min + (max - min) * random_double();
-}
 pub fn clamp<T: PartialOrd>(input: T, min: T, max: T) -> T {
     if input > max {
         return max;
@@ -29,6 +18,65 @@ pub fn clamp<T: PartialOrd>(input: T, min: T, max: T) -> T {
     input
 }
 
+// Rng
+// PCG32 (O'Neill, "PCG: A Family of Simple Fast Space-Efficient Statistically
+// Good Algorithms for Random Number Generation"). Every generator is built from
+// explicit seeds so renders and generated scenes can be reproduced exactly.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+    increment: u64,
+}
+impl Rng {
+    const MULTIPLIER: u64 = 6364136223846793005;
+
+    pub fn new(seed: u64) -> Self {
+        Rng::with_stream(seed, 0)
+    }
+    pub fn with_stream(seed: u64, stream: u64) -> Self {
+        let mut rng = Rng {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+    // A generator for one pixel of one pass, so that every sample of an image
+    // is decided by the seed alone and not by how the work was scheduled.
+    pub fn for_pixel(seed: u64, pass: u64, pixel: u64) -> Self {
+        Rng::with_stream(hash_seed(seed ^ hash_seed(pixel)), pass)
+    }
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(Rng::MULTIPLIER)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rotation = (old >> 59) as u32;
+        xorshifted.rotate_right(rotation)
+    }
+    pub fn random_double(&mut self) -> f64 {
+        // Returns a random real in [0,1).
+        self.next_u32() as f64 / 4294967296.0
+    }
+    pub fn random_range_double(&mut self, min: f64, max: f64) -> f64 {
+        // Returns a random real in [min,max).
+        min + (max - min) * self.random_double()
+    }
+    pub fn random_range_int(&mut self, min: i32, max: i32) -> i32 {
+        self.random_range_double(min as f64, (max + 1) as f64) as i32
+    }
+}
+// SplitMix64 finalizer, spreads nearby seeds far apart.
+pub fn hash_seed(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
 // Vector3
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Vec3(f64, f64, f64);
@@ -53,31 +101,35 @@ impl Vec3 {
     pub fn from(e0: f64, e1: f64, e2: f64) -> Self {
         Vec3(e0, e1, e2)
     }
-    pub fn random() -> Self {
-        Vec3(random_double(), random_double(), random_double())
+    pub fn random(rng: &mut Rng) -> Self {
+        Vec3(
+            rng.random_double(),
+            rng.random_double(),
+            rng.random_double(),
+        )
     }
-    pub fn random_range(min: f64, max: f64) -> Self {
+    pub fn random_range(rng: &mut Rng, min: f64, max: f64) -> Self {
         Vec3(
-            random_range_double(min, max),
-            random_range_double(min, max),
-            random_range_double(min, max),
+            rng.random_range_double(min, max),
+            rng.random_range_double(min, max),
+            rng.random_range_double(min, max),
         )
     }
-    pub fn random_in_unit_sphere() -> Self {
-        let mut p = Vec3::random_range(-1.0, 1.0);
+    pub fn random_in_unit_sphere(rng: &mut Rng) -> Self {
+        let mut p = Vec3::random_range(rng, -1.0, 1.0);
         while p.length_squared() < 1.0 {
-            p = Vec3::random_range(-1.0, 1.0);
+            p = Vec3::random_range(rng, -1.0, 1.0);
         }
         p
     }
-    pub fn random_unit_vector() -> Self {
-        let a = random_range_double(0.0, 2.0 * PI);
-        let z = random_range_double(-1.0, 1.0);
+    pub fn random_unit_vector(rng: &mut Rng) -> Self {
+        let a = rng.random_range_double(0.0, 2.0 * PI);
+        let z = rng.random_range_double(-1.0, 1.0);
         let r = (1.0 - z * z).sqrt();
         Vec3(r * a.cos(), r * a.sin(), z)
     }
-    pub fn random_in_hemisphere(normal: &Vec3) -> Self {
-        let in_unit_sphere = Vec3::random_in_unit_sphere();
+    pub fn random_in_hemisphere(rng: &mut Rng, normal: &Vec3) -> Self {
+        let in_unit_sphere = Vec3::random_in_unit_sphere(rng);
         // In the same hemisphere as the normal
         if dot(&in_unit_sphere, normal) > 0.0 {
             return in_unit_sphere;
@@ -85,16 +137,16 @@ impl Vec3 {
             return -in_unit_sphere;
         }
     }
-    pub fn random_in_unit_disk() -> Self {
+    pub fn random_in_unit_disk(rng: &mut Rng) -> Self {
         let mut p = Vec3::new(
-            random_range_double(-1.0, 1.0),
-            random_range_double(-1.0, 1.0),
+            rng.random_range_double(-1.0, 1.0),
+            rng.random_range_double(-1.0, 1.0),
             0.0,
         );
         while p.length_squared() > 1.0 {
             p = Vec3::new(
-                random_range_double(-1.0, 1.0),
-                random_range_double(-1.0, 1.0),
+                rng.random_range_double(-1.0, 1.0),
+                rng.random_range_double(-1.0, 1.0),
                 0.0,
             );
         }
@@ -118,6 +170,9 @@ impl Vec3 {
     pub fn abs(&self) -> Vec3 {
         Vec3(self.0.abs(), self.1.abs(), self.2.abs())
     }
+    pub fn is_finite(&self) -> bool {
+        self.0.is_finite() && self.1.is_finite() && self.2.is_finite()
+    }
 }
 pub fn normalize(v: &Vec3) -> Vec3 {
     v.clone() / v.length()
@@ -132,6 +187,17 @@ pub fn cross(u: &Vec3, v: &Vec3) -> Vec3 {
         u.0 * v.1 - u.1 * v.0,
     )
 }
+// Two unit vectors that make an orthonormal basis with the unit vector `n`
+// (Duff et al., "Building an Orthonormal Basis, Revisited").
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1f64.copysign(n.z());
+    let a = -1.0 / (sign + n.z());
+    let b = n.x() * n.y() * a;
+    (
+        Vec3::new(1.0 + sign * n.x() * n.x() * a, sign * b, -sign * n.x()),
+        Vec3::new(b, sign + n.y() * n.y() * a, -n.y()),
+    )
+}
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     *v - *n * dot(v, n) * 2.0
 }
@@ -142,6 +208,10 @@ pub fn refract(v: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
     let refracted = r_out_perp + r_out_parallel;
     refracted
 }
+pub fn luminance(color: &Color) -> f64 {
+    // Rec. 709 weights for linear RGB.
+    0.2126 * color.0 + 0.7152 * color.1 + 0.0722 * color.2
+}
 pub fn schlick(cosine: f64, refractive_index: f64) -> f64 {
     let r0 = (1.0 - refractive_index) / (1.0 + refractive_index);
     let r0 = r0 * r0;
@@ -205,3 +275,146 @@ impl IndexMut<i32> for Vec3 {
         }
     }
 }
+
+// Matrix4
+// Affine transforms as 4x4 row-major matrices, applied to column vectors.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Matrix4([[f64; 4]; 4]);
+impl Matrix4 {
+    pub const IDENTITY: Matrix4 = Matrix4([
+        [1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ]);
+
+    pub fn translation(offset: &Vec3) -> Self {
+        Matrix4([
+            [1.0, 0.0, 0.0, offset.0],
+            [0.0, 1.0, 0.0, offset.1],
+            [0.0, 0.0, 1.0, offset.2],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+    pub fn scaling(factors: &Vec3) -> Self {
+        Matrix4([
+            [factors.0, 0.0, 0.0, 0.0],
+            [0.0, factors.1, 0.0, 0.0],
+            [0.0, 0.0, factors.2, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+    // Counterclockwise about `axis`, looking down it toward the origin.
+    pub fn rotation(axis: &Vec3, degrees: f64) -> Self {
+        let Vec3(x, y, z) = normalize(axis);
+        let radians = degrees_to_radians(degrees);
+        let (s, c) = (radians.sin(), radians.cos());
+        let t = 1.0 - c;
+        Matrix4([
+            [c + x * x * t, x * y * t - z * s, x * z * t + y * s, 0.0],
+            [y * x * t + z * s, c + y * y * t, y * z * t - x * s, 0.0],
+            [z * x * t - y * s, z * y * t + x * s, c + z * z * t, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+    // From the columns, the order glTF and OpenGL store matrices in.
+    pub fn from_columns(columns: [[f64; 4]; 4]) -> Self {
+        Matrix4(columns).transpose()
+    }
+    pub fn transpose(&self) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.0[j][i];
+            }
+        }
+        Matrix4(m)
+    }
+    // Gauss-Jordan elimination with partial pivoting. None for singular
+    // matrices.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.0;
+        let mut inverse = Matrix4::IDENTITY.0;
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|i, j| {
+                    a[*i][column]
+                        .abs()
+                        .partial_cmp(&a[*j][column].abs())
+                        .unwrap()
+                })
+                .unwrap();
+            if a[pivot][column] == 0.0 || !a[pivot][column].is_finite() {
+                return None;
+            }
+            a.swap(column, pivot);
+            inverse.swap(column, pivot);
+            let scale = 1.0 / a[column][column];
+            for j in 0..4 {
+                a[column][j] *= scale;
+                inverse[column][j] *= scale;
+            }
+            for row in 0..4 {
+                let factor = a[row][column];
+                if row == column || factor == 0.0 {
+                    continue;
+                }
+                for j in 0..4 {
+                    a[row][j] -= factor * a[column][j];
+                    inverse[row][j] -= factor * inverse[column][j];
+                }
+            }
+        }
+        Some(Matrix4(inverse))
+    }
+    // Determinant of the upper 3x3 part, negative for transforms that mirror.
+    pub fn determinant3(&self) -> f64 {
+        let m = &self.0;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+    pub fn transform_point(&self, p: &Point3) -> Point3 {
+        let m = &self.0;
+        let x = m[0][0] * p.0 + m[0][1] * p.1 + m[0][2] * p.2 + m[0][3];
+        let y = m[1][0] * p.0 + m[1][1] * p.1 + m[1][2] * p.2 + m[1][3];
+        let z = m[2][0] * p.0 + m[2][1] * p.1 + m[2][2] * p.2 + m[2][3];
+        let w = m[3][0] * p.0 + m[3][1] * p.1 + m[3][2] * p.2 + m[3][3];
+        if w == 1.0 {
+            Vec3(x, y, z)
+        } else {
+            Vec3(x / w, y / w, z / w)
+        }
+    }
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        let m = &self.0;
+        Vec3(
+            m[0][0] * v.0 + m[0][1] * v.1 + m[0][2] * v.2,
+            m[1][0] * v.0 + m[1][1] * v.1 + m[1][2] * v.2,
+            m[2][0] * v.0 + m[2][1] * v.1 + m[2][2] * v.2,
+        )
+    }
+    // Normals go through the transpose of the inverse, so that they stay
+    // perpendicular to transformed surfaces. `self` is the inverse here. The
+    // result is not normalized.
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        let m = &self.0;
+        Vec3(
+            m[0][0] * n.0 + m[1][0] * n.1 + m[2][0] * n.2,
+            m[0][1] * n.0 + m[1][1] * n.1 + m[2][1] * n.2,
+            m[0][2] * n.0 + m[1][2] * n.1 + m[2][2] * n.2,
+        )
+    }
+}
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+    fn mul(self, other: Matrix4) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.0[i][k] * other.0[k][j]).sum();
+            }
+        }
+        Matrix4(m)
+    }
+}
@@ -0,0 +1,899 @@
+use crate::{aabb::*, material::*, math::*, ray::*, stats, texture::*};
+
+// Triangles and indexed triangle meshes. Rays are tested with the watertight
+// algorithm of Woop, Benthin and Wald ("Watertight Ray/Triangle Intersection"),
+// so rays never slip through the shared edge of two triangles. Each mesh keeps
+// its own BVH over its triangles, the scene BVH only sees its bounding box.
+
+// Most triangles in a mesh BVH leaf.
+const LEAF_TRIANGLES: usize = 4;
+// Deepest mesh BVH. Splits at the median, so that is plenty for any mesh that
+// fits in memory.
+const MAX_DEPTH: usize = 64;
+// Smallest cosine between a normal mapped normal and the direction back along
+// the ray.
+const MIN_FACING: f64 = 0.05;
+
+// A ray moved to the origin and sheared so that it points down the z axis. Once
+// the triangle is moved the same way, hits are found in 2D by the signs of the
+// edge functions, which are exact enough to agree on shared edges.
+struct WatertightRay {
+    origin: Point3,
+    axes: (i32, i32, i32),
+    shear: Vec3,
+}
+impl WatertightRay {
+    fn new(ray: &Ray) -> Self {
+        let direction = ray.direction();
+        let abs = direction.abs();
+        let kz = if abs.x() >= abs.y() && abs.x() >= abs.z() {
+            0
+        } else if abs.y() >= abs.z() {
+            1
+        } else {
+            2
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        // Keeps the winding of the triangle.
+        if direction[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        WatertightRay {
+            origin: *ray.origin(),
+            axes: (kx, ky, kz),
+            shear: Vec3::new(
+                direction[kx] / direction[kz],
+                direction[ky] / direction[kz],
+                1.0 / direction[kz],
+            ),
+        }
+    }
+    // Distance to the triangle and the barycentric coordinates of the hit.
+    fn intersect(&self, vertices: &[Point3; 3], t_min: f64, t_max: f64) -> Option<(f64, [f64; 3])> {
+        let (kx, ky, kz) = self.axes;
+        let shear = |vertex: &Point3| -> (f64, f64, f64) {
+            let p = *vertex - self.origin;
+            (
+                p[kx] - self.shear.x() * p[kz],
+                p[ky] - self.shear.y() * p[kz],
+                self.shear.z() * p[kz],
+            )
+        };
+        let (ax, ay, az) = shear(&vertices[0]);
+        let (bx, by, bz) = shear(&vertices[1]);
+        let (cx, cy, cz) = shear(&vertices[2]);
+        let u = cx * by - cy * bx;
+        let v = ax * cy - ay * cx;
+        let w = bx * ay - by * ax;
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+        let determinant = u + v + w;
+        if determinant == 0.0 {
+            return None;
+        }
+        let t = (u * az + v * bz + w * cz) / determinant;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        Some((t, [u / determinant, v / determinant, w / determinant]))
+    }
+}
+
+// Bounds of a triangle, a little thicker than the triangle so that triangles
+// lying in an axis plane still have some volume.
+fn triangle_bounds(vertices: &[Point3; 3]) -> AABB {
+    let mut min = vertices[0];
+    let mut max = vertices[0];
+    for vertex in &vertices[1..] {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(vertex[axis]);
+            max[axis] = max[axis].max(vertex[axis]);
+        }
+    }
+    let padding = Vec3::new(0.0001, 0.0001, 0.0001);
+    AABB::new(min - padding, max + padding)
+}
+fn triangle_area(vertices: &[Point3; 3]) -> f64 {
+    let v = vertices;
+    cross(&(v[1] - v[0]), &(v[2] - v[0])).length() / 2.0
+}
+// Corners
+// Vertex attributes at the corners of one triangle, None for those it doesn't
+// have.
+#[derive(Default)]
+struct Corners {
+    normals: Option<[Vec3; 3]>,
+    uvs: Option<[(f64, f64); 3]>,
+    tangents: Option<[[f64; 4]; 3]>,
+    colors: Option<[Color; 3]>,
+}
+
+fn interpolate(values: &[Vec3; 3], barycentrics: &[f64; 3]) -> Vec3 {
+    values[0] * barycentrics[0] + values[1] * barycentrics[1] + values[2] * barycentrics[2]
+}
+// UVs at the given barycentric coordinates. Without UVs, they are the
+// barycentric coordinates of the second and third vertex.
+fn interpolate_uv(uvs: Option<&[(f64, f64); 3]>, barycentrics: &[f64; 3]) -> (f64, f64) {
+    match uvs {
+        Some(uvs) => (
+            uvs[0].0 * barycentrics[0] + uvs[1].0 * barycentrics[1] + uvs[2].0 * barycentrics[2],
+            uvs[0].1 * barycentrics[0] + uvs[1].1 * barycentrics[1] + uvs[2].1 * barycentrics[2],
+        ),
+        None => (barycentrics[1], barycentrics[2]),
+    }
+}
+// The hit at the given distance and barycentric coordinates. The outside is
+// where the vertex normals point, or where the vertices go counterclockwise
+// for triangles without normals.
+fn surface_hit<'a>(
+    ray: &Ray,
+    vertices: &[Point3; 3],
+    corners: &Corners,
+    material: &'a Material,
+    (distance, barycentrics): (f64, [f64; 3]),
+) -> RayHit<'a> {
+    let point = interpolate(vertices, &barycentrics);
+    let geometric_normal = normalize(&cross(
+        &(vertices[1] - vertices[0]),
+        &(vertices[2] - vertices[0]),
+    ));
+    let uv = interpolate_uv(corners.uvs.as_ref(), &barycentrics);
+    let shading_normal = corners
+        .normals
+        .as_ref()
+        .map(|normals| interpolate(normals, &barycentrics))
+        .filter(|normal| normal.length_squared() > 0.0)
+        .map(|normal| normalize(&normal));
+    let outward_normal = match shading_normal {
+        Some(normal) if dot(&geometric_normal, &normal) < 0.0 => -geometric_normal,
+        _ => geometric_normal,
+    };
+    let normal_texture = match material {
+        Material::Microfacet(microfacet) => microfacet.normal_texture.as_ref(),
+        _ => None,
+    };
+    let mapped_normal = match (normal_texture, &corners.uvs) {
+        (Some(texture), Some(uvs)) => {
+            let normal = shading_normal.unwrap_or(outward_normal);
+            let texel = sample_texture(texture, uv.0, uv.1, &point);
+            let tangents = corners.tangents.as_ref();
+            tangent_frame(vertices, uvs, tangents, &normal, &barycentrics).map(
+                |(tangent, bitangent)| {
+                    let mapped = tangent * (2.0 * texel.x() - 1.0)
+                        + bitangent * (2.0 * texel.y() - 1.0)
+                        + normal * (2.0 * texel.z() - 1.0);
+                    // Bumps turned away from the ray would leave black rims
+                    // around silhouettes, they are bent back toward it.
+                    let wo = -*ray.direction();
+                    let mapped = normalize(&mapped) * dot(&outward_normal, &wo).signum();
+                    let facing = dot(&mapped, &wo);
+                    if facing < MIN_FACING {
+                        normalize(&(mapped + wo * (MIN_FACING - facing)))
+                    } else {
+                        mapped
+                    }
+                },
+            )
+        }
+        _ => None,
+    };
+    let hit = match mapped_normal.or(shading_normal) {
+        Some(normal) => {
+            RayHit::with_shading_normal(ray, point, distance, material, outward_normal, normal, uv)
+        }
+        None => RayHit::new(ray, point, distance, material, geometric_normal, uv),
+    };
+    hit.with_color(
+        corners
+            .colors
+            .as_ref()
+            .map(|colors| interpolate(colors, &barycentrics)),
+    )
+}
+// Unit tangent and bitangent around `normal`, for normal maps. The tangent
+// points toward increasing u and the bitangent toward increasing v, taken from
+// the vertex tangents when given (their w is the handedness) or from how the
+// UVs stretch over the triangle otherwise.
+fn tangent_frame(
+    vertices: &[Point3; 3],
+    uvs: &[(f64, f64); 3],
+    tangents: Option<&[[f64; 4]; 3]>,
+    normal: &Vec3,
+    barycentrics: &[f64; 3],
+) -> Option<(Vec3, Vec3)> {
+    let (tangent, handedness) = match tangents {
+        Some(tangents) => {
+            let corners =
+                [0, 1, 2].map(|i| Vec3::new(tangents[i][0], tangents[i][1], tangents[i][2]));
+            (interpolate(&corners, barycentrics), tangents[0][3].signum())
+        }
+        None => {
+            let (edge1, edge2) = (vertices[1] - vertices[0], vertices[2] - vertices[0]);
+            let (du1, dv1) = (uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1);
+            let (du2, dv2) = (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1);
+            let determinant = du1 * dv2 - du2 * dv1;
+            if determinant.abs() < 1e-12 {
+                return None;
+            }
+            let tangent = (edge1 * dv2 - edge2 * dv1) / determinant;
+            let bitangent = (edge2 * du1 - edge1 * du2) / determinant;
+            let handedness = dot(&cross(normal, &tangent), &bitangent).signum();
+            (tangent, handedness)
+        }
+    };
+    let tangent = tangent - *normal * dot(normal, &tangent);
+    if tangent.length_squared() <= 0.0 {
+        return None;
+    }
+    let tangent = normalize(&tangent);
+    Some((tangent, cross(normal, &tangent) * handedness))
+}
+// Barycentric coordinates of a uniformly distributed point on a triangle.
+fn sample_barycentrics((u, v): (f64, f64)) -> [f64; 3] {
+    let root = u.sqrt();
+    let b0 = 1.0 - root;
+    let b1 = v * root;
+    [b0, b1, 1.0 - b0 - b1]
+}
+
+// Triangle
+// Normals and UVs, when given, are per vertex and interpolated over the
+// triangle.
+#[derive(Clone)]
+pub struct Triangle {
+    vertices: [Point3; 3],
+    normals: Option<[Vec3; 3]>,
+    uvs: Option<[(f64, f64); 3]>,
+    material: Material,
+}
+impl Triangle {
+    pub fn new(
+        vertices: [Point3; 3],
+        normals: Option<[Vec3; 3]>,
+        uvs: Option<[(f64, f64); 3]>,
+        material: Material,
+    ) -> Self {
+        Triangle {
+            vertices,
+            normals,
+            uvs,
+            material,
+        }
+    }
+    pub fn vertices(&self) -> &[Point3; 3] {
+        &self.vertices
+    }
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
+    // Unit normal of the plane of the triangle.
+    pub fn normal(&self) -> Vec3 {
+        let v = &self.vertices;
+        normalize(&cross(&(v[1] - v[0]), &(v[2] - v[0])))
+    }
+    pub fn area(&self) -> f64 {
+        triangle_area(&self.vertices)
+    }
+    pub fn bounding_box(&self) -> AABB {
+        triangle_bounds(&self.vertices)
+    }
+    pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RayHit<'_>> {
+        WatertightRay::new(ray)
+            .intersect(&self.vertices, t_min, t_max)
+            .map(|intersection| {
+                let corners = Corners {
+                    normals: self.normals,
+                    uvs: self.uvs,
+                    ..Corners::default()
+                };
+                surface_hit(ray, &self.vertices, &corners, &self.material, intersection)
+            })
+    }
+    // Uniform point on the triangle for a uniform `u`, with its UVs.
+    pub fn sample(&self, u: (f64, f64)) -> (Point3, (f64, f64)) {
+        let barycentrics = sample_barycentrics(u);
+        (
+            interpolate(&self.vertices, &barycentrics),
+            interpolate_uv(self.uvs.as_ref(), &barycentrics),
+        )
+    }
+}
+
+// MeshNode
+// Node of a mesh BVH, stored depth first. A leaf holds `count` triangles from
+// `offset` on, an inner node has a count of 0, its first child right after it
+// and its second child at `offset`.
+#[derive(Clone)]
+struct MeshNode {
+    bounds: AABB,
+    offset: u32,
+    count: u32,
+    axis: u8,
+}
+
+// TriangleMesh
+// Triangles index into arrays of positions, normals, UVs, tangents and colours
+// shared by the whole mesh. Those are either empty or given for every position.
+// Tangents only matter to normal maps, their w is the handedness of the
+// bitangent. Colours are what `Texture::VertexColor` shows. Glowing meshes are
+// sampled as one light, which picks a triangle by its share of the area.
+#[derive(Clone)]
+pub struct TriangleMesh {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f64, f64)>,
+    tangents: Vec<[f64; 4]>,
+    colors: Vec<Color>,
+    triangles: Vec<[u32; 3]>,
+    material: Material,
+    nodes: Vec<MeshNode>,
+    // Running totals of the triangle areas, in BVH order. Only glowing meshes
+    // keep them.
+    area_cdf: Vec<f64>,
+}
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f64, f64)>,
+        tangents: Vec<[f64; 4]>,
+        colors: Vec<Color>,
+        mut triangles: Vec<[u32; 3]>,
+        material: Material,
+    ) -> Self {
+        if triangles.is_empty() {
+            panic!("A triangle mesh needs at least one triangle!");
+        }
+        if !normals.is_empty() && normals.len() != positions.len() {
+            panic!("A triangle mesh needs a normal for every position or none at all!");
+        }
+        if !uvs.is_empty() && uvs.len() != positions.len() {
+            panic!("A triangle mesh needs UVs for every position or none at all!");
+        }
+        if !tangents.is_empty() && tangents.len() != positions.len() {
+            panic!("A triangle mesh needs a tangent for every position or none at all!");
+        }
+        if !colors.is_empty() && colors.len() != positions.len() {
+            panic!("A triangle mesh needs a colour for every position or none at all!");
+        }
+        if triangles
+            .iter()
+            .flatten()
+            .any(|index| *index as usize >= positions.len())
+        {
+            panic!("Triangle mesh index out of bounds!");
+        }
+        let nodes = build_bvh(&mut triangles, &positions);
+        let mut area_cdf = Vec::new();
+        if is_emissive(&material) {
+            let mut total = 0.0;
+            area_cdf.extend(triangles.iter().map(|triangle| {
+                total += triangle_area(&corner_positions(triangle, &positions));
+                total
+            }));
+        }
+        TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            tangents,
+            colors,
+            triangles,
+            material,
+            nodes,
+            area_cdf,
+        }
+    }
+    pub fn positions(&self) -> &[Point3] {
+        &self.positions
+    }
+    pub fn normals(&self) -> &[Vec3] {
+        &self.normals
+    }
+    pub fn uvs(&self) -> &[(f64, f64)] {
+        &self.uvs
+    }
+    pub fn tangents(&self) -> &[[f64; 4]] {
+        &self.tangents
+    }
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+    // Triangles in BVH order, which is not the order they were given in.
+    pub fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+    // The triangle with the given index, on its own.
+    pub fn triangle(&self, index: usize) -> Triangle {
+        let corners = self.corners(index);
+        Triangle::new(
+            self.vertices(index),
+            corners.normals,
+            corners.uvs,
+            self.material.clone(),
+        )
+    }
+    pub fn bounding_box(&self) -> AABB {
+        self.nodes[0].bounds.clone()
+    }
+    pub fn area(&self) -> f64 {
+        match self.area_cdf.last() {
+            Some(area) => *area,
+            None => (0..self.triangle_count())
+                .map(|index| self.triangle_area(index))
+                .sum(),
+        }
+    }
+    pub fn triangle_area(&self, index: usize) -> f64 {
+        triangle_area(&self.vertices(index))
+    }
+    // Unit normal of the plane of a triangle.
+    pub fn triangle_normal(&self, index: usize) -> Vec3 {
+        let v = self.vertices(index);
+        normalize(&cross(&(v[1] - v[0]), &(v[2] - v[0])))
+    }
+    // Uniform point on a glowing mesh: `pick` chooses a triangle by its share of
+    // the area, `u` the point on it. Returns the point, the normal of its
+    // triangle and the UVs there.
+    pub fn sample(&self, pick: f64, u: (f64, f64)) -> (Point3, Vec3, (f64, f64)) {
+        let total = match self.area_cdf.last() {
+            Some(total) => *total,
+            None => panic!("Only glowing triangle meshes can be sampled!"),
+        };
+        let index = self
+            .area_cdf
+            .partition_point(|area| *area <= pick * total)
+            .min(self.triangle_count() - 1);
+        let barycentrics = sample_barycentrics(u);
+        (
+            interpolate(&self.vertices(index), &barycentrics),
+            self.triangle_normal(index),
+            interpolate_uv(self.corner_values(&self.uvs, index).as_ref(), &barycentrics),
+        )
+    }
+    fn vertices(&self, index: usize) -> [Point3; 3] {
+        let [a, b, c] = self.triangles[index];
+        [
+            self.positions[a as usize],
+            self.positions[b as usize],
+            self.positions[c as usize],
+        ]
+    }
+    fn corners(&self, index: usize) -> Corners {
+        Corners {
+            normals: self.corner_values(&self.normals, index),
+            uvs: self.corner_values(&self.uvs, index),
+            tangents: self.corner_values(&self.tangents, index),
+            colors: self.corner_values(&self.colors, index),
+        }
+    }
+    // Values of a per vertex attribute at the corners of a triangle, if the mesh
+    // has it.
+    fn corner_values<T: Copy>(&self, values: &[T], index: usize) -> Option<[T; 3]> {
+        if values.is_empty() {
+            return None;
+        }
+        let [a, b, c] = self.triangles[index];
+        Some([values[a as usize], values[b as usize], values[c as usize]])
+    }
+    pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RayHit<'_>> {
+        self.closest_triangle(ray, t_min, t_max)
+            .map(|(triangle, intersection)| {
+                surface_hit(
+                    ray,
+                    &self.vertices(triangle),
+                    &self.corners(triangle),
+                    &self.material,
+                    intersection,
+                )
+            })
+    }
+    // Index of the triangle the ray hits first, with the distance to it.
+    pub fn hit_triangle(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(usize, f64)> {
+        self.closest_triangle(ray, t_min, t_max)
+            .map(|(triangle, (distance, _))| (triangle, distance))
+    }
+    fn closest_triangle(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<(usize, (f64, [f64; 3]))> {
+        let watertight = WatertightRay::new(ray);
+        let mut closest = None;
+        let mut closest_distance = t_max;
+        let mut stack = [0; MAX_DEPTH];
+        let mut stack_size = 1;
+        // Counted here and recorded once, the walk is too hot to record as it goes.
+        let (mut nodes_visited, mut triangles_tested) = (0, 0);
+        while stack_size > 0 {
+            stack_size -= 1;
+            let index = stack[stack_size];
+            let node = &self.nodes[index];
+            nodes_visited += 1;
+            if !node.bounds.hit(ray, t_min, closest_distance) {
+                continue;
+            }
+            if node.count > 0 {
+                let first = node.offset as usize;
+                triangles_tested += node.count as u64;
+                for triangle in first..first + node.count as usize {
+                    let vertices = self.vertices(triangle);
+                    if let Some(intersection) =
+                        watertight.intersect(&vertices, t_min, closest_distance)
+                    {
+                        closest_distance = intersection.0;
+                        closest = Some((triangle, intersection));
+                    }
+                }
+            } else {
+                // The child on the side the ray comes from goes on top.
+                let (near, far) = if ray.direction()[node.axis as i32] < 0.0 {
+                    (node.offset as usize, index + 1)
+                } else {
+                    (index + 1, node.offset as usize)
+                };
+                stack[stack_size] = far;
+                stack[stack_size + 1] = near;
+                stack_size += 2;
+            }
+        }
+        stats::record(|stats| stats.add_mesh_traversal(nodes_visited, triangles_tested));
+        closest
+    }
+}
+
+// Sorts the triangles into BVH order and returns the nodes. The bounds and
+// centers of the triangles are worked out whenever they are needed instead of
+// being kept, so that building the BVH of a big mesh only takes memory for the
+// nodes and the keys of one split.
+fn build_bvh(triangles: &mut [[u32; 3]], positions: &[Point3]) -> Vec<MeshNode> {
+    let mut nodes = Vec::with_capacity(2 * triangles.len() / LEAF_TRIANGLES + 1);
+    let mut keyed = Vec::with_capacity(triangles.len());
+    build_node(&mut nodes, triangles, 0, positions, &mut keyed);
+    nodes
+}
+fn corner_positions(triangle: &[u32; 3], positions: &[Point3]) -> [Point3; 3] {
+    triangle.map(|index| positions[index as usize])
+}
+// Center of the bounds of a triangle along one axis.
+fn triangle_center(triangle: &[u32; 3], positions: &[Point3], axis: i32) -> f64 {
+    let [a, b, c] = corner_positions(triangle, positions).map(|p| p[axis]);
+    (a.min(b).min(c) + a.max(b).max(c)) / 2.0
+}
+// Adds the node over `triangles`, which start at `offset` in BVH order, and
+// then its children. Splits at the median of the widest axis of the triangle
+// centers, which are put next to the triangles in `keyed` so that finding it
+// doesn't look up positions all over the mesh.
+fn build_node(
+    nodes: &mut Vec<MeshNode>,
+    triangles: &mut [[u32; 3]],
+    offset: usize,
+    positions: &[Point3],
+    keyed: &mut Vec<(f32, [u32; 3])>,
+) {
+    // Bounds of the triangles and of their centers, in one pass.
+    let mut node_bounds = AABB::new(
+        Point3::new(INIFINITY, INIFINITY, INIFINITY),
+        Point3::new(-INIFINITY, -INIFINITY, -INIFINITY),
+    );
+    let mut min = Point3::new(INIFINITY, INIFINITY, INIFINITY);
+    let mut max = -min;
+    for triangle in triangles.iter() {
+        let bounds = triangle_bounds(&corner_positions(triangle, positions));
+        let center = (*bounds.min() + *bounds.max()) / 2.0;
+        node_bounds = surrounding_box(&node_bounds, &bounds);
+        for axis in 0..3 {
+            min[axis] = min[axis].min(center[axis]);
+            max[axis] = max[axis].max(center[axis]);
+        }
+    }
+    let index = nodes.len();
+    nodes.push(MeshNode {
+        bounds: node_bounds,
+        offset: offset as u32,
+        count: triangles.len() as u32,
+        axis: 0,
+    });
+    if triangles.len() <= LEAF_TRIANGLES {
+        return;
+    }
+    let extent = max - min;
+    let axis = if extent.x() >= extent.y() && extent.x() >= extent.z() {
+        0
+    } else if extent.y() >= extent.z() {
+        1
+    } else {
+        2
+    };
+    // Triangles around the same point can't be told apart.
+    if extent[axis] <= 0.0 {
+        return;
+    }
+    let middle = triangles.len() / 2;
+    keyed.clear();
+    keyed.extend(
+        triangles
+            .iter()
+            .map(|triangle| (triangle_center(triangle, positions, axis) as f32, *triangle)),
+    );
+    keyed.select_nth_unstable_by(middle, |a, b| a.0.total_cmp(&b.0));
+    for (triangle, (_, sorted)) in triangles.iter_mut().zip(keyed.iter()) {
+        *triangle = *sorted;
+    }
+    let (left, right) = triangles.split_at_mut(middle);
+    build_node(nodes, left, offset, positions, keyed);
+    let second = nodes.len();
+    build_node(nodes, right, offset + middle, positions, keyed);
+    nodes[index].offset = second as u32;
+    nodes[index].count = 0;
+    nodes[index].axis = axis as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray() -> Material {
+        Material::Lambertian(Texture::SolidColor(Color::new(0.5, 0.5, 0.5)))
+    }
+    fn random_point(rng: &mut Rng, scale: f64) -> Point3 {
+        Point3::new(
+            rng.random_range_double(-scale, scale),
+            rng.random_range_double(-scale, scale),
+            rng.random_range_double(-scale, scale),
+        )
+    }
+    // A ray from a random point above the triangles toward `target`.
+    fn ray_toward(rng: &mut Rng, target: &Point3) -> Ray {
+        let origin = random_point(rng, 3.0) + Vec3::new(0.0, 0.0, 5.0);
+        Ray::new(origin, *target - origin, Some(0.0))
+    }
+    fn mesh(positions: Vec<Point3>, triangles: Vec<[u32; 3]>) -> TriangleMesh {
+        TriangleMesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            triangles,
+            gray(),
+        )
+    }
+
+    #[test]
+    fn glowing_meshes_are_one_light_sampled_by_area() {
+        use crate::{geometry, sampler::*};
+        use std::sync::Arc;
+        let glow = || Material::DiffuseLight(Texture::SolidColor(Color::WHITE));
+        // A 2 by 1 rectangle cut into a triangle of half its area and two of a
+        // quarter.
+        let positions = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 0.0),
+            Point3::new(2.0, 1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+        ];
+        let triangles = vec![[0, 1, 4], [1, 2, 4], [0, 4, 3]];
+        let mesh = TriangleMesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            triangles,
+            glow(),
+        );
+        let mut world = geometry::HittableList::new();
+        world.add(geometry::Hittable::Mesh(Arc::new(mesh)));
+        assert_eq!(world.lights().len(), 1);
+        let light = &world.lights()[0];
+        assert!((geometry::area(light) - 2.0).abs() < 1e-12);
+
+        // Uniform by area, so every direction has the density it has toward
+        // the same rectangle in one piece.
+        let rectangle = geometry::Hittable::XYRect((0.0, 2.0), (0.0, 1.0), 0.0, glow());
+        let origin = Point3::new(0.3, 0.4, 2.0);
+        let mut sampler = Sampler::new(SamplerKind::Independent, 3, 0, (0, 0), 1);
+        let mut rng = Rng::new(3);
+        for _ in 0..1000 {
+            let target = Point3::new(
+                rng.random_range_double(-0.5, 2.5),
+                rng.random_range_double(-0.5, 1.5),
+                0.0,
+            );
+            let direction = target - origin;
+            let expected = geometry::pdf_value(&rectangle, &origin, &direction, &mut sampler);
+            let pdf = geometry::pdf_value(light, &origin, &direction, &mut sampler);
+            assert!(
+                (pdf - expected).abs() <= 1e-9 * expected,
+                "{} instead of {} toward {:?}",
+                pdf,
+                expected,
+                target
+            );
+        }
+
+        // Half of the points land on the big triangle.
+        let samples = 20_000;
+        let on_big_triangle = (0..samples)
+            .filter(|_| {
+                let point = *geometry::random_point(light, &mut sampler).point();
+                point.y() <= point.x() && point.y() <= 2.0 - point.x()
+            })
+            .count();
+        let share = on_big_triangle as f64 / samples as f64;
+        assert!((share - 0.5).abs() < 0.02, "{}", share);
+    }
+
+    #[test]
+    fn mesh_hits_count_nodes_and_triangles() {
+        let triangle = crate::geometry::HITTABLE_NAMES
+            .iter()
+            .position(|name| *name == "triangle")
+            .unwrap();
+        let counts = || {
+            let mut counts = (0, 0);
+            stats::record(|stats| {
+                counts = (stats.bvh_nodes_visited(), stats.ray_casts[triangle]);
+            });
+            counts
+        };
+        // A strip of 8 triangles along x, which splits into two leaves under
+        // the root.
+        let mut positions = Vec::new();
+        for i in 0..5 {
+            positions.push(Point3::new(i as f64, 0.0, 0.0));
+            positions.push(Point3::new(i as f64, 1.0, 0.0));
+        }
+        let triangles = (0..4)
+            .flat_map(|i| vec![[2 * i, 2 * i + 2, 2 * i + 3], [2 * i, 2 * i + 3, 2 * i + 1]])
+            .collect();
+        let strip = mesh(positions, triangles);
+        let down = |x: f64| {
+            Ray::new(
+                Point3::new(x, 0.5, 1.0),
+                Vec3::new(0.0, 0.0, -1.0),
+                Some(0.0),
+            )
+        };
+
+        let before = counts();
+        assert!(strip.hit(&down(0.5), 0.001, INIFINITY).is_some());
+        let after = counts();
+        // The root and both leaves come off the stack, but only the leaf under
+        // the ray has its triangles tested.
+        assert_eq!(after.0 - before.0, 3);
+        assert_eq!(after.1 - before.1, 4);
+
+        let before = counts();
+        assert!(strip.hit(&down(-1.0), 0.001, INIFINITY).is_none());
+        let after = counts();
+        assert_eq!(after.0 - before.0, 1);
+        assert_eq!(after.1 - before.1, 0);
+    }
+
+    #[test]
+    fn non_finite_positions_dont_stop_the_bvh() {
+        // Built from code, nothing checks the positions first.
+        let mut positions: Vec<Point3> = (0..12)
+            .map(|i| Point3::new(i as f64, (i % 2) as f64, 0.0))
+            .collect();
+        positions[5] = Point3::new(f64::NAN, 0.0, f64::INFINITY);
+        positions[6] = Point3::new(6.0, 0.0, -f64::INFINITY);
+        let triangles = (0..10).map(|i| [i, i + 1, i + 2]).collect();
+        let strip = mesh(positions, triangles);
+        let ray = Ray::new(
+            Point3::new(9.5, 0.4, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Some(0.0),
+        );
+        assert!(strip.hit(&ray, 0.001, INIFINITY).is_some());
+    }
+
+    #[test]
+    fn rays_along_a_shared_edge_hit() {
+        // A tilted parallelogram with coordinates that aren't exact in binary,
+        // split along the diagonal.
+        let (a, b, c) = (
+            Point3::new(-0.3, -0.7, 0.1),
+            Point3::new(0.9, -0.2, -0.3),
+            Point3::new(0.7, 0.8, 0.3),
+        );
+        let corners = [a, b, c, a + (c - b)];
+        let quad = mesh(corners.to_vec(), vec![[0, 1, 2], [0, 2, 3]]);
+        let mut rng = Rng::new(1);
+        for i in 0..10_000 {
+            let s = (i as f64 + 0.5) / 10_000.0;
+            let target = corners[0] + (corners[2] - corners[0]) * s;
+            let ray = ray_toward(&mut rng, &target);
+            let watertight = WatertightRay::new(&ray);
+            let hits = (0..2)
+                .filter(|triangle| {
+                    let vertices = quad.vertices(*triangle);
+                    watertight.intersect(&vertices, 0.001, INIFINITY).is_some()
+                })
+                .count();
+            assert!(hits > 0, "ray toward {:?} slipped through", target);
+            assert!(quad.hit(&ray, 0.001, INIFINITY).is_some());
+        }
+    }
+
+    #[test]
+    fn rays_at_a_shared_vertex_hit() {
+        // Six triangles around a center vertex.
+        let center = Point3::new(0.1, 0.2, 0.3);
+        let mut positions = vec![center];
+        for k in 0..6 {
+            let angle = k as f64 * PI / 3.0 + 0.1;
+            positions.push(center + Vec3::new(angle.cos(), angle.sin(), 0.2 * angle.sin()));
+        }
+        let triangles = (0..6).map(|k| [0, k + 1, (k + 1) % 6 + 1]).collect();
+        let fan = mesh(positions, triangles);
+        let mut rng = Rng::new(2);
+        for _ in 0..10_000 {
+            let ray = ray_toward(&mut rng, &center);
+            assert!(fan.hit(&ray, 0.001, INIFINITY).is_some());
+        }
+    }
+
+    #[test]
+    fn uvs_are_interpolated_with_barycentrics() {
+        let vertices = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 0.0),
+            Point3::new(0.0, 2.0, 0.0),
+        ];
+        let uvs = [(0.5, 0.0), (1.0, 0.0), (0.5, 1.0)];
+        let ray = Ray::new(Point3::new(0.5, 1.0, 1.0), Vec3::new(0.0, 0.0, -1.0), None);
+        // The hit is at barycentrics (0.25, 0.25, 0.5).
+        let textured = Triangle::new(vertices, None, Some(uvs), gray());
+        let hit = textured.hit(&ray, 0.001, INIFINITY).unwrap();
+        let (u, v) = hit.uv();
+        assert!((u - 0.625).abs() < 1e-12 && (v - 0.5).abs() < 1e-12);
+        assert!((hit.distance() - 1.0).abs() < 1e-12);
+        let plain = Triangle::new(vertices, None, None, gray());
+        let (u, v) = plain.hit(&ray, 0.001, INIFINITY).unwrap().uv();
+        assert!((u - 0.25).abs() < 1e-12 && (v - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn bvh_matches_a_linear_scan() {
+        let mut rng = Rng::new(3);
+        let mut positions = Vec::new();
+        let mut triangles = Vec::new();
+        for i in 0..500 {
+            let center = random_point(&mut rng, 2.0);
+            for _ in 0..3 {
+                positions.push(center + random_point(&mut rng, 0.3));
+            }
+            triangles.push([3 * i, 3 * i + 1, 3 * i + 2]);
+        }
+        let soup = mesh(positions, triangles);
+        for _ in 0..2_000 {
+            let target = random_point(&mut rng, 2.0);
+            let ray = Ray::new(random_point(&mut rng, 4.0), target, None);
+            let closest = (0..soup.triangle_count())
+                .filter_map(|i| {
+                    let triangle = soup.triangle(i);
+                    triangle
+                        .hit(&ray, 0.001, INIFINITY)
+                        .map(|hit| hit.distance())
+                })
+                .fold(INIFINITY, f64::min);
+            match soup.hit(&ray, 0.001, INIFINITY) {
+                Some(hit) => assert_eq!(hit.distance(), closest),
+                None => assert_eq!(closest, INIFINITY),
+            }
+        }
+    }
+}
@@ -45,14 +45,14 @@ pub struct Perlin {
 impl Perlin {
     const POINT_COUNT: usize = 256;
 
-    pub fn new() -> Self {
+    pub fn new(rng: &mut Rng) -> Self {
         let mut rand_vec = [Vec3::default(); Perlin::POINT_COUNT];
         for e in rand_vec.iter_mut() {
-            *e = Vec3::random_range(-1.0, 1.0);
+            *e = Vec3::random_range(rng, -1.0, 1.0);
         }
-        let perm_x = Perlin::perlin_generate_perm();
-        let perm_y = Perlin::perlin_generate_perm();
-        let perm_z = Perlin::perlin_generate_perm();
+        let perm_x = Perlin::perlin_generate_perm(rng);
+        let perm_y = Perlin::perlin_generate_perm(rng);
+        let perm_z = Perlin::perlin_generate_perm(rng);
         Perlin {
             perm_x,
             perm_y,
@@ -92,17 +92,18 @@ impl Perlin {
         acc.abs()
     }
 
-    fn perlin_generate_perm() -> [i32; Perlin::POINT_COUNT] {
+    fn perlin_generate_perm(rng: &mut Rng) -> [i32; Perlin::POINT_COUNT] {
         let mut p = [0; Perlin::POINT_COUNT];
         for i in 0..Perlin::POINT_COUNT {
             p[i] = i as i32;
         }
-        Perlin::permute(p);
+        Perlin::permute(&mut p, rng);
         p
     }
-    fn permute(mut p: [i32; Perlin::POINT_COUNT]) {
-        use rand::seq::SliceRandom;
-        let mut rng = rand::thread_rng();
-        p.shuffle(&mut rng);
+    fn permute(p: &mut [i32; Perlin::POINT_COUNT], rng: &mut Rng) {
+        for i in (1..Perlin::POINT_COUNT).rev() {
+            let target = rng.random_range_int(0, i as i32) as usize;
+            p.swap(i, target);
+        }
     }
 }
@@ -0,0 +1,518 @@
+use crate::{geometry::*, material::*, math::*, mesh::*, texture::*};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// Wavefront OBJ models with their MTL material libraries. Faces are split into
+// one triangle mesh per run of faces that share a group and a material;
+// polygons are triangulated as fans around their first corner.
+
+// Helper functions
+fn parse_error(path: &Path, line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, message),
+    )
+}
+// Calls `f` with the keyword and arguments of every line of a file, and the
+// number of the line. Comments are left out and lines that end in a backslash
+// go on in the next one.
+fn for_each_line<F>(text: &str, mut f: F) -> io::Result<()>
+where
+    F: FnMut(usize, &str, &[&str]) -> io::Result<()>,
+{
+    let mut call = |number: usize, line: &str| -> io::Result<()> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.split_first() {
+            Some((keyword, args)) => f(number, keyword, args),
+            None => Ok(()),
+        }
+    };
+    let mut pending: Option<(usize, String)> = None;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        match (line.trim_end().strip_suffix('\\'), pending.take()) {
+            (Some(start), started) => {
+                let (number, mut joined) = started.unwrap_or((index + 1, String::new()));
+                joined.push_str(start);
+                joined.push(' ');
+                pending = Some((number, joined));
+            }
+            (None, Some((number, mut joined))) => {
+                joined.push_str(line);
+                call(number, &joined)?;
+            }
+            (None, None) => call(index + 1, line)?,
+        }
+    }
+    match pending {
+        Some((number, line)) => call(number, &line),
+        None => Ok(()),
+    }
+}
+fn parse_f64(token: &str) -> Result<f64, String> {
+    token
+        .parse()
+        .map_err(|_| format!("Invalid number '{}'", token))
+}
+// The numbers after the keyword, at least `min` and at most `max` of them.
+fn parse_numbers(args: &[&str], min: usize, max: usize) -> Result<Vec<f64>, String> {
+    if args.len() < min || args.len() > max {
+        return Err(if min == max {
+            format!("Expected {} numbers, found {}", min, args.len())
+        } else {
+            format!("Expected {} to {} numbers, found {}", min, max, args.len())
+        });
+    }
+    args.iter().map(|arg| parse_f64(arg)).collect()
+}
+fn parse_color(args: &[&str]) -> Result<Color, String> {
+    // A single value is a gray.
+    let values = parse_numbers(args, 1, 3)?;
+    match values.len() {
+        1 => Ok(Color::new(values[0], values[0], values[0])),
+        3 => Ok(Color::new(values[0], values[1], values[2])),
+        _ => Err(String::from("Expected 1 or 3 color components")),
+    }
+}
+fn max_component(color: &Color) -> f64 {
+    color.x().max(color.y()).max(color.z())
+}
+
+// MtlMaterial
+// A material as an MTL file describes it, before it is turned into one of ours.
+struct MtlMaterial {
+    diffuse: Color,
+    specular: Color,
+    shininess: f64,
+    refractive_index: Option<f64>,
+    dissolve: f64,
+    emission: Color,
+    illumination: u32,
+    diffuse_map: Option<Image>,
+}
+impl MtlMaterial {
+    fn new() -> Self {
+        MtlMaterial {
+            diffuse: Color::new(0.8, 0.8, 0.8),
+            specular: Color::BLACK,
+            shininess: 0.0,
+            refractive_index: None,
+            dissolve: 1.0,
+            emission: Color::BLACK,
+            illumination: 2,
+            diffuse_map: None,
+        }
+    }
+    // Anything that glows is a light. See-through materials and the glass
+    // illumination models are dielectrics, materials that are more specular
+    // than diffuse are metals that get rougher as `Ns` goes down, and the rest
+    // is Lambertian.
+    fn to_material(&self) -> Material {
+        if max_component(&self.emission) > 0.0 {
+            return Material::DiffuseLight(self.emission.into());
+        }
+        if self.dissolve < 1.0 || [4, 6, 7, 9].contains(&self.illumination) {
+            let refractive_index = self.refractive_index.filter(|ni| *ni >= 1.0);
+            return Material::Dielectric(refractive_index.unwrap_or(1.5));
+        }
+        if let Some(image) = &self.diffuse_map {
+            return Material::Lambertian(Texture::Image(image.clone()));
+        }
+        if max_component(&self.specular) > max_component(&self.diffuse) {
+            let roughness = (2.0 / (self.shininess.max(0.0) + 2.0)).sqrt();
+            return Material::Metal(self.specular, roughness);
+        }
+        Material::Lambertian(self.diffuse.into())
+    }
+}
+
+// Reads the materials of an MTL file into `materials`.
+fn load_mtl(path: &Path, materials: &mut HashMap<String, Material>) -> io::Result<()> {
+    let text = fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for_each_line(&text, |number, keyword, args| {
+        let error = |message: &str| parse_error(path, number, message);
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return Err(error("newmtl needs a name"));
+            }
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material.to_material());
+            }
+            current = Some((args.join(" "), MtlMaterial::new()));
+            return Ok(());
+        }
+        let material = match &mut current {
+            Some((_, material)) => material,
+            None => return Err(error(&format!("{} before any newmtl", keyword))),
+        };
+        let result = match keyword {
+            "Kd" => parse_color(args).map(|color| material.diffuse = color),
+            "Ks" => parse_color(args).map(|color| material.specular = color),
+            "Ke" => parse_color(args).map(|color| material.emission = color),
+            "Ns" => parse_numbers(args, 1, 1).map(|ns| material.shininess = ns[0]),
+            "Ni" => parse_numbers(args, 1, 1).map(|ni| material.refractive_index = Some(ni[0])),
+            "d" => parse_numbers(args, 1, 1).map(|d| material.dissolve = d[0]),
+            "Tr" => parse_numbers(args, 1, 1).map(|tr| material.dissolve = 1.0 - tr[0]),
+            "illum" => {
+                parse_numbers(args, 1, 1).map(|illum| material.illumination = illum[0] as u32)
+            }
+            "map_Kd" => match args.last() {
+                // Options come before the file name.
+                Some(file_name) => match Image::load(directory.join(file_name)) {
+                    Ok(image) => {
+                        material.diffuse_map = Some(image);
+                        Ok(())
+                    }
+                    Err(image_error) => Err(format!("Can't load {}: {}", file_name, image_error)),
+                },
+                None => Err(String::from("map_Kd needs a file name")),
+            },
+            // Ambient color, other maps and the like have nothing to map onto.
+            _ => Ok(()),
+        };
+        result.map_err(|message| error(&message))
+    })?;
+    if let Some((name, material)) = current {
+        materials.insert(name, material.to_material());
+    }
+    Ok(())
+}
+
+// Position, UV and normal indices of a face corner.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+// Faces of one group and material.
+struct Segment {
+    material: Material,
+    triangles: Vec<[Corner; 3]>,
+}
+
+// The zero based index of a one based or negative (counted back from the end)
+// OBJ index into a list of `count` elements.
+fn resolve_index(token: &str, count: usize, kind: &str) -> Result<usize, String> {
+    let index: i64 = token
+        .parse()
+        .map_err(|_| format!("Invalid {} index '{}'", kind, token))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else {
+        count as i64 + index
+    };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!(
+            "{} index {} out of range, there are {} so far",
+            kind, index, count
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+// ObjParser
+struct ObjParser {
+    positions: Vec<Point3>,
+    uvs: Vec<(f64, f64)>,
+    normals: Vec<Vec3>,
+    materials: HashMap<String, Material>,
+    material: Material,
+    triangles: Vec<[Corner; 3]>,
+    segments: Vec<Segment>,
+}
+impl ObjParser {
+    fn new() -> Self {
+        ObjParser {
+            positions: Vec::new(),
+            uvs: Vec::new(),
+            normals: Vec::new(),
+            materials: HashMap::new(),
+            material: MtlMaterial::new().to_material(),
+            triangles: Vec::new(),
+            segments: Vec::new(),
+        }
+    }
+    // Ends the current run of faces.
+    fn finish_segment(&mut self) {
+        if !self.triangles.is_empty() {
+            self.segments.push(Segment {
+                material: self.material.clone(),
+                triangles: std::mem::take(&mut self.triangles),
+            });
+        }
+    }
+    fn parse_corner(&self, token: &str) -> Result<Corner, String> {
+        let mut parts = token.split('/');
+        let position = resolve_index(parts.next().unwrap_or(""), self.positions.len(), "Vertex")?;
+        let uv = match parts.next() {
+            Some("") | None => None,
+            Some(uv) => Some(resolve_index(uv, self.uvs.len(), "Texture coordinate")?),
+        };
+        let normal = match parts.next() {
+            Some("") | None => None,
+            Some(normal) => Some(resolve_index(normal, self.normals.len(), "Normal")?),
+        };
+        if parts.next().is_some() {
+            return Err(format!("Invalid face corner '{}'", token));
+        }
+        Ok((position, uv, normal))
+    }
+    fn parse_line(&mut self, keyword: &str, args: &[&str], directory: &Path) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        match keyword {
+            "v" => {
+                // A w or a vertex color may follow.
+                let v = parse_numbers(args, 3, 6).map_err(invalid)?;
+                let position = Point3::new(v[0], v[1], v[2]);
+                if !position.is_finite() {
+                    return Err(invalid(String::from("Position is not finite")));
+                }
+                self.positions.push(position);
+            }
+            "vt" => {
+                let vt = parse_numbers(args, 1, 3).map_err(invalid)?;
+                self.uvs.push((vt[0], vt.get(1).copied().unwrap_or(0.0)));
+            }
+            "vn" => {
+                let vn = parse_numbers(args, 3, 3).map_err(invalid)?;
+                self.normals.push(Vec3::new(vn[0], vn[1], vn[2]));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(invalid(format!(
+                        "A face needs at least 3 corners, found {}",
+                        args.len()
+                    )));
+                }
+                let corners = args
+                    .iter()
+                    .map(|arg| self.parse_corner(arg))
+                    .collect::<Result<Vec<Corner>, String>>()
+                    .map_err(invalid)?;
+                for i in 1..corners.len() - 1 {
+                    self.triangles
+                        .push([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            "g" | "o" => self.finish_segment(),
+            "usemtl" => {
+                // Exporters name materials they never wrote, those faces get the
+                // default material like faces without one.
+                let material = match self.materials.get(&args.join(" ")) {
+                    Some(material) => material.clone(),
+                    None => MtlMaterial::new().to_material(),
+                };
+                self.finish_segment();
+                self.material = material;
+            }
+            "mtllib" => {
+                for file_name in args {
+                    load_mtl(&directory.join(file_name), &mut self.materials).map_err(|error| {
+                        invalid(format!(
+                            "Can't load material library {}: {}",
+                            file_name, error
+                        ))
+                    })?;
+                }
+            }
+            // Smoothing groups, lines, points and curves.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// One mesh for the faces of a segment, with their corners merged into shared
+// vertices. Normals and UVs are kept if every corner has them.
+fn build_mesh(segment: Segment, parser: &ObjParser) -> TriangleMesh {
+    let corners = segment.triangles.iter().flatten();
+    let has_uvs = corners.clone().all(|(_, uv, _)| uv.is_some());
+    let has_normals = corners.clone().all(|(_, _, normal)| normal.is_some());
+    let mut vertices: HashMap<Corner, u32> = HashMap::new();
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut triangles = Vec::with_capacity(segment.triangles.len());
+    for triangle in &segment.triangles {
+        let mut indices = [0; 3];
+        for (index, corner) in indices.iter_mut().zip(triangle) {
+            let (position, uv, normal) = *corner;
+            let key = (
+                position,
+                uv.filter(|_| has_uvs),
+                normal.filter(|_| has_normals),
+            );
+            *index = *vertices.entry(key).or_insert_with(|| {
+                positions.push(parser.positions[position]);
+                if let Some(uv) = key.1 {
+                    uvs.push(parser.uvs[uv]);
+                }
+                if let Some(normal) = key.2 {
+                    normals.push(parser.normals[normal]);
+                }
+                positions.len() as u32 - 1
+            });
+        }
+        triangles.push(indices);
+    }
+    TriangleMesh::new(
+        positions,
+        normals,
+        uvs,
+        Vec::new(),
+        Vec::new(),
+        triangles,
+        segment.material,
+    )
+}
+
+// Parses the text of the OBJ file at `path`, which MTL files are found next to.
+fn parse_obj(text: &str, path: &Path) -> io::Result<ObjParser> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut parser = ObjParser::new();
+    for_each_line(text, |number, keyword, args| {
+        parser
+            .parse_line(keyword, args, directory)
+            .map_err(|error| parse_error(path, number, &error.to_string()))
+    })?;
+    parser.finish_segment();
+    Ok(parser)
+}
+
+// Loads the model in an OBJ file, with the materials of the MTL files it
+// names. Faces without a material, or with one no MTL file has, are
+// Lambertian in MTL's default gray.
+pub fn load_obj<P: AsRef<Path>>(path: P) -> io::Result<HittableList> {
+    let path = path.as_ref();
+    let mut parser = parse_obj(&fs::read_to_string(path)?, path)?;
+    let mut objects = HittableList::new();
+    for segment in std::mem::take(&mut parser.segments) {
+        objects.add(Hittable::Mesh(Arc::new(build_mesh(segment, &parser))));
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> ObjParser {
+        parse_obj(text, Path::new("model.obj")).unwrap()
+    }
+    fn positions(segment: &Segment) -> Vec<[usize; 3]> {
+        segment
+            .triangles
+            .iter()
+            .map(|[a, b, c]| [a.0, b.0, c.0])
+            .collect()
+    }
+    fn lambertian_color(material: &Material) -> (f64, f64, f64) {
+        match material {
+            Material::Lambertian(Texture::SolidColor(color)) => (color.x(), color.y(), color.z()),
+            _ => panic!("Expected a plain Lambertian material!"),
+        }
+    }
+
+    #[test]
+    fn negative_indices_count_back() {
+        let parser = parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 1\nvn 0 0 1\n\
+             f -3/-2/-1 -2/-1/-1 -1/-1/-1\n\
+             v 1 1 0\nf -4 -2 -1\n",
+        );
+        let triangles = &parser.segments[0].triangles;
+        assert_eq!(
+            triangles[0],
+            [
+                (0, Some(0), Some(0)),
+                (1, Some(1), Some(0)),
+                (2, Some(1), Some(0))
+            ]
+        );
+        assert_eq!(
+            triangles[1],
+            [(0, None, None), (2, None, None), (3, None, None)]
+        );
+    }
+
+    #[test]
+    fn polygons_become_fans() {
+        let parser = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0.5 2 0\nv 0 1 0\nf 1 2 3 4 5\n");
+        assert_eq!(
+            positions(&parser.segments[0]),
+            vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]
+        );
+    }
+
+    #[test]
+    fn groups_and_materials_start_segments() {
+        let parser = parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+             g first\nf 1 2 3\nf 3 2 1\n\
+             g second\nf 1 2 3\n\
+             usemtl default\nf 2 3 1\n\
+             s 1\nf 3 1 2\n",
+        );
+        let sizes: Vec<usize> = parser.segments.iter().map(|s| s.triangles.len()).collect();
+        assert_eq!(sizes, vec![2, 1, 2]);
+        assert_eq!(positions(&parser.segments[2]), vec![[1, 2, 0], [2, 0, 1]]);
+    }
+
+    #[test]
+    fn unknown_materials_fall_back_to_the_default() {
+        let parser = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl missing\nf 1 2 3\n");
+        let color = lambertian_color(&parser.segments[0].material);
+        assert_eq!(color, (0.8, 0.8, 0.8));
+    }
+
+    #[test]
+    fn mtl_materials_are_mapped() {
+        let directory = std::env::temp_dir().join("raytrace_rs_obj_test");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("materials.mtl"),
+            "newmtl red\nKd 1 0 0\n\
+             newmtl lamp\nKe 4 4 4\n\
+             newmtl glass\nd 0.5\nNi 1.3\n\
+             newmtl chrome\nKd 0.1 0.1 0.1\nKs 0.9 0.9 0.9\nNs 198\n",
+        )
+        .unwrap();
+        let text = "mtllib materials.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\n\
+                    usemtl red\nf 1 2 3\nusemtl lamp\nf 1 2 3\n\
+                    usemtl glass\nf 1 2 3\nusemtl chrome\nf 1 2 3\n";
+        let parser = parse_obj(text, &directory.join("model.obj")).unwrap();
+        let materials: Vec<&Material> = parser.segments.iter().map(|s| &s.material).collect();
+        assert_eq!(lambertian_color(materials[0]), (1.0, 0.0, 0.0));
+        assert!(matches!(materials[1], Material::DiffuseLight(_)));
+        assert!(matches!(materials[2], Material::Dielectric(ni) if *ni == 1.3));
+        match materials[3] {
+            Material::Metal(color, roughness) => {
+                assert_eq!(color.x(), 0.9);
+                assert!((roughness - 0.1).abs() < 1e-12);
+            }
+            _ => panic!("Expected a metal!"),
+        }
+    }
+
+    #[test]
+    fn errors_give_the_file_and_line() {
+        let text = "v 0 0 0\n# a comment\nv 1 0 \\\n  0\nf 1 2 3\n";
+        let error = parse_obj(text, Path::new("model.obj")).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "model.obj:5: Vertex index 3 out of range, there are 2 so far"
+        );
+    }
+
+    #[test]
+    fn non_finite_positions_give_the_line() {
+        let message = parse_obj("v 0 0 0\nv 1 nan 0\nv 0 1 0\n", Path::new("model.obj"))
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(message, "model.obj:2: Position is not finite");
+    }
+}
@@ -0,0 +1,394 @@
+extern crate image;
+use crate::{math::*, tonemap::*};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Helper functions
+fn image_error(error: image::ImageError) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+// Framebuffer
+#[derive(Clone)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Color::BLACK; width * height],
+        }
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    // Rows are stored top to bottom, the same order the image is written out.
+    pub fn get(&self, x: usize, y: usize) -> &Color {
+        &self.pixels[y * self.width + x]
+    }
+    pub fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[y * self.width + x] = color;
+    }
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+    pub fn to_rgb8(&self, display: &DisplaySettings) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 3);
+        for (index, pixel) in self.pixels.iter().enumerate() {
+            let color = display.display_color(pixel);
+            for c in 0..3 {
+                data.push(display.quantize8(color[c], index, c as usize));
+            }
+        }
+        data
+    }
+    pub fn to_rgb16(&self, display: &DisplaySettings) -> Vec<u16> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 3);
+        for pixel in &self.pixels {
+            let color = display.display_color(pixel);
+            for c in 0..3 {
+                data.push((65535.0 * color[c]).round() as u16);
+            }
+        }
+        data
+    }
+    pub fn to_rgb32f(&self) -> Vec<f32> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 3);
+        for pixel in &self.pixels {
+            for c in 0..3 {
+                data.push(pixel[c] as f32);
+            }
+        }
+        data
+    }
+}
+
+// OutputFormat
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Ppm,
+    Png8,
+    Png16,
+    Hdr,
+    Pfm,
+    Exr,
+}
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ppm" => Some(OutputFormat::Ppm),
+            "png" => Some(OutputFormat::Png8),
+            "hdr" => Some(OutputFormat::Hdr),
+            "pfm" => Some(OutputFormat::Pfm),
+            "exr" => Some(OutputFormat::Exr),
+            _ => None,
+        }
+    }
+}
+
+pub fn save_image<P: AsRef<Path>>(framebuffer: &Framebuffer, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    match OutputFormat::from_path(path) {
+        Some(format) => save_image_with_format(framebuffer, path, format),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported output format: {}", path.display()),
+        )),
+    }
+}
+pub fn save_image_with_format<P: AsRef<Path>>(
+    framebuffer: &Framebuffer,
+    path: P,
+    format: OutputFormat,
+) -> io::Result<()> {
+    save_display_image(framebuffer, path, format, &DisplaySettings::default())
+}
+// Display settings only apply to the 8 and 16-bit formats, the float formats
+// keep the linear values.
+pub fn save_display_image<P: AsRef<Path>>(
+    framebuffer: &Framebuffer,
+    path: P,
+    format: OutputFormat,
+    display: &DisplaySettings,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let width = framebuffer.width() as u32;
+    let height = framebuffer.height() as u32;
+    match format {
+        OutputFormat::Ppm => write_ppm(framebuffer, path, display),
+        OutputFormat::Png8 => {
+            image::RgbImage::from_raw(width, height, framebuffer.to_rgb8(display))
+                .unwrap()
+                .save_with_format(path, image::ImageFormat::Png)
+                .map_err(image_error)
+        }
+        OutputFormat::Png16 => image::ImageBuffer::<image::Rgb<u16>, _>::from_raw(
+            width,
+            height,
+            framebuffer.to_rgb16(display),
+        )
+        .unwrap()
+        .save_with_format(path, image::ImageFormat::Png)
+        .map_err(image_error),
+        OutputFormat::Hdr => {
+            let data: Vec<image::Rgb<f32>> = framebuffer
+                .to_rgb32f()
+                .chunks(3)
+                .map(|c| image::Rgb([c[0], c[1], c[2]]))
+                .collect();
+            let writer = BufWriter::new(File::create(path)?);
+            image::hdr::HdrEncoder::new(writer)
+                .encode(&data, framebuffer.width(), framebuffer.height())
+                .map_err(image_error)
+        }
+        OutputFormat::Pfm => write_pfm(framebuffer, path),
+        OutputFormat::Exr => {
+            let rgb = framebuffer.to_rgb32f();
+            let channel =
+                |c: usize| -> Vec<f32> { rgb.iter().skip(c).step_by(3).cloned().collect() };
+            write_exr(
+                path,
+                framebuffer.width(),
+                framebuffer.height(),
+                &[("R", channel(0)), ("G", channel(1)), ("B", channel(2))],
+            )
+        }
+    }
+}
+
+fn write_ppm(framebuffer: &Framebuffer, path: &Path, display: &DisplaySettings) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "P3")?;
+    writeln!(writer, "{} {}", framebuffer.width(), framebuffer.height())?;
+    writeln!(writer, "255")?;
+    for rgb in framebuffer.to_rgb8(display).chunks(3) {
+        writeln!(writer, "{} {} {}", rgb[0], rgb[1], rgb[2])?;
+    }
+    writer.flush()
+}
+
+fn write_pfm(framebuffer: &Framebuffer, path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    // A negative scale marks little-endian data, scanlines go bottom to top.
+    write!(
+        writer,
+        "PF\n{} {}\n-1.0\n",
+        framebuffer.width(),
+        framebuffer.height()
+    )?;
+    for y in (0..framebuffer.height()).rev() {
+        for x in 0..framebuffer.width() {
+            let pixel = framebuffer.get(x, y);
+            for c in 0..3 {
+                writer.write_all(&(pixel[c] as f32).to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+// Writes an uncompressed scanline OpenEXR file with one 32-bit float channel per
+// entry. Each channel holds `width * height` values, rows top to bottom.
+pub fn write_exr<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    channels: &[(&str, Vec<f32>)],
+) -> io::Result<()> {
+    const FLOAT: i32 = 2;
+    // The channel list has to be sorted by name, and so does the pixel data.
+    let mut channels: Vec<&(&str, Vec<f32>)> = channels.iter().collect();
+    channels.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut header = Vec::new();
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+    let mut chlist = Vec::new();
+    for (name, _) in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&FLOAT.to_le_bytes());
+        chlist.extend_from_slice(&[0, 0, 0, 0]); // pLinear + reserved
+        chlist.extend_from_slice(&1i32.to_le_bytes()); // xSampling
+        chlist.extend_from_slice(&1i32.to_le_bytes()); // ySampling
+    }
+    chlist.push(0);
+    let mut window = Vec::new();
+    for v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    attribute("channels", "chlist", &chlist);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&20000630i32.to_le_bytes())?;
+    writer.write_all(&2i32.to_le_bytes())?;
+    writer.write_all(&header)?;
+
+    // One block per scanline: y, byte count, then each channel's row in turn.
+    let line_size = channels.len() * width * 4;
+    let first_block = 8 + header.len() + height * 8;
+    for y in 0..height {
+        let offset = first_block + y * (8 + line_size);
+        writer.write_all(&(offset as u64).to_le_bytes())?;
+    }
+    for y in 0..height {
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&(line_size as i32).to_le_bytes())?;
+        for (_, data) in &channels {
+            for value in &data[y * width..(y + 1) * width] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::fs;
+
+    fn temp_path(name: &str, extension: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("raytrace_rs_output_{}_test.{}", name, extension))
+    }
+    fn i32_at(bytes: &[u8], at: usize) -> i32 {
+        i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+    fn f32_at(bytes: &[u8], at: usize) -> f32 {
+        f32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+    // Values that aren't exact in binary, and some that aren't displayable.
+    fn framebuffer(width: usize, height: usize) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let v = (y * width + x) as f64;
+                framebuffer.set(x, y, Color::new(v + 0.1, -v / 3.0, 1e5 * v));
+            }
+        }
+        framebuffer
+    }
+
+    #[test]
+    fn exr_files_have_the_layout_of_the_spec() {
+        let path = temp_path("layout", "exr");
+        let channel = |base: f32| -> Vec<f32> { (0..4).map(|i| base + i as f32 / 8.0).collect() };
+        // Given out of order, stored sorted by name.
+        let channels = [
+            ("R", channel(1.0)),
+            ("G", channel(2.0)),
+            ("B", channel(3.0)),
+        ];
+        write_exr(&path, 2, 2, &channels).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(i32_at(&bytes, 0), 20000630);
+        assert_eq!(i32_at(&bytes, 4), 2);
+        // Attributes are a name, a type, a size and the value, up to an empty
+        // name.
+        let mut at = 8;
+        let mut chlist = None;
+        let read_string = |at: &mut usize| -> String {
+            let end = *at + bytes[*at..].iter().position(|byte| *byte == 0).unwrap();
+            let string = String::from_utf8(bytes[*at..end].to_vec()).unwrap();
+            *at = end + 1;
+            string
+        };
+        loop {
+            let name = read_string(&mut at);
+            if name.is_empty() {
+                break;
+            }
+            let kind = read_string(&mut at);
+            let size = i32_at(&bytes, at) as usize;
+            at += 4;
+            if name == "channels" {
+                assert_eq!(kind, "chlist");
+                chlist = Some(bytes[at..at + size].to_vec());
+            }
+            at += size;
+        }
+        let chlist = chlist.expect("No channels attribute");
+        let mut expected = Vec::new();
+        for name in ["B", "G", "R"].iter() {
+            expected.extend_from_slice(name.as_bytes());
+            expected.push(0);
+            // 32-bit float, linear flag and reserved bytes, x and y sampling.
+            expected.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        }
+        expected.push(0);
+        assert_eq!(chlist, expected);
+
+        // The offset table points at every scanline, and the last one ends the
+        // file.
+        let table = at;
+        let line_size = 3 * 2 * 4;
+        for y in 0..2 {
+            let at = table + 8 * y;
+            let offset = u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+            assert_eq!(offset, table + 2 * 8 + y * (8 + line_size));
+            assert_eq!(i32_at(&bytes, offset), y as i32);
+            assert_eq!(i32_at(&bytes, offset + 4), line_size as i32);
+            for (c, (_, data)) in [&channels[2], &channels[1], &channels[0]]
+                .iter()
+                .enumerate()
+            {
+                for x in 0..2 {
+                    let value = f32_at(&bytes, offset + 8 + 4 * (c * 2 + x));
+                    assert_eq!(value, data[y * 2 + x]);
+                }
+            }
+        }
+        assert_eq!(table + 2 * 8 + 2 * (8 + line_size), bytes.len());
+    }
+
+    #[test]
+    fn pfm_files_round_trip() {
+        let (width, height) = (3, 2);
+        let original = framebuffer(width, height);
+        let path = temp_path("round_trip", "pfm");
+        save_image(&original, &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header = "PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header.as_bytes());
+        let data = &bytes[header.len()..];
+        assert_eq!(data.len(), width * height * 3 * 4);
+        // Rows go bottom to top.
+        for y in 0..height {
+            for x in 0..width {
+                let at = 12 * ((height - 1 - y) * width + x);
+                let expected = original.get(x, y);
+                for c in 0..3 {
+                    assert_eq!(f32_at(data, at + 4 * c as usize), expected[c] as f32);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,480 @@
+use crate::{camera::*, geometry::*, material::*, math::*, ray::*, render::*, sampler::*, stats};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+// Photon mapping, after Jensen's "Realistic Image Synthesis Using Photon
+// Mapping". Before every pass, photons are shot from the lights and left on the
+// diffuse surfaces they bounce off. Camera paths follow specular and glossy
+// bounces up to the first diffuse surface, where direct light comes from shadow
+// rays as in `ray_color` and the light that bounced at least once, caustics
+// included, from the photons around the hit point.
+// The progressive variant is Knaus and Zwicker's take on stochastic progressive
+// photon mapping: every pass is an independent estimate with a smaller gather
+// radius than the one before, so the bias of their average goes to zero.
+
+// PhotonSettings
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PhotonSettings {
+    // Photons shot before every pass.
+    pub photons: usize,
+    // Radius photons are gathered in, where progressive passes start. 0 picks
+    // one from the size of the scene.
+    pub radius: f64,
+}
+
+// Share of the squared radius that each progressive pass keeps, more or less;
+// alpha in Knaus and Zwicker.
+const PROGRESSIVE_ALPHA: f64 = 2.0 / 3.0;
+// Photons shot with one sampler. Batches always split the same way, so the
+// photon map doesn't depend on the thread count.
+const PHOTON_BATCH: usize = 4096;
+
+// Photon
+#[derive(Debug, Copy, Clone)]
+struct Photon {
+    point: Point3,
+    // Toward where the photon came from.
+    direction: Vec3,
+    power: Color,
+}
+
+// PhotonMap
+// Photons in a kd-tree laid out in place: every range of the array holds the
+// median along its widest axis in the middle, the photons below it on the left
+// and the ones above it on the right.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    radius: f64,
+}
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>, radius: f64) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build_kd_tree(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+        }
+    }
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn for_each_near<F: FnMut(&Photon)>(&self, point: &Point3, f: &mut F) {
+        self.visit(0, self.photons.len(), point, f);
+    }
+    fn visit<F: FnMut(&Photon)>(&self, start: usize, end: usize, point: &Point3, f: &mut F) {
+        if start >= end {
+            return;
+        }
+        let middle = start + (end - start) / 2;
+        let photon = &self.photons[middle];
+        let radius_squared = self.radius * self.radius;
+        if (photon.point - *point).length_squared() <= radius_squared {
+            f(photon);
+        }
+        let axis = self.axes[middle] as i32;
+        let offset = point[axis] - photon.point[axis];
+        let (near, far) = if offset < 0.0 {
+            ((start, middle), (middle + 1, end))
+        } else {
+            ((middle + 1, end), (start, middle))
+        };
+        self.visit(near.0, near.1, point, f);
+        if offset * offset <= radius_squared {
+            self.visit(far.0, far.1, point, f);
+        }
+    }
+    // Light reflected toward `wo` at `hit` by the photons within the gather
+    // radius, taken as spread evenly over its disc.
+    pub fn radiance(&self, hit: &RayHit, wo: &Vec3) -> Color {
+        let mut sum = Color::BLACK;
+        self.for_each_near(hit.point(), &mut |photon| {
+            let cosine = dot(hit.normal(), &photon.direction);
+            if cosine > 0.0 {
+                let f = eval(hit.material(), hit, wo, &photon.direction) / cosine;
+                sum = sum + f * photon.power;
+            }
+        });
+        sum / (PI * self.radius * self.radius)
+    }
+}
+fn build_kd_tree(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let mut min = photons[0].point;
+    let mut max = photons[0].point;
+    for photon in photons.iter() {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(photon.point[axis]);
+            max[axis] = max[axis].max(photon.point[axis]);
+        }
+    }
+    let extent = max - min;
+    let axis = if extent.x() >= extent.y() && extent.x() >= extent.z() {
+        0
+    } else if extent.y() >= extent.z() {
+        1
+    } else {
+        2
+    };
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| {
+        a.point[axis].partial_cmp(&b.point[axis]).unwrap()
+    });
+    axes[middle] = axis as u8;
+    let (left, right) = photons.split_at_mut(middle);
+    let (left_axes, right_axes) = axes.split_at_mut(middle);
+    build_kd_tree(left, left_axes);
+    build_kd_tree(&mut right[1..], &mut right_axes[1..]);
+}
+
+// Surfaces that keep photons and gather them. Media would need a volume
+// estimate, their light comes from the surfaces behind them.
+fn is_diffuse_surface(material: &Material) -> bool {
+    match material {
+        Material::Isotropic(_) => false,
+        _ => lobes(material).contains(Lobes::DIFFUSE),
+    }
+}
+
+// Gather radius of the pass with the given index, shrinking from pass to pass
+// for the progressive variant.
+pub fn gather_radius(
+    world: &HittableList,
+    camera: &Camera,
+    integrator: Integrator,
+    pass: u64,
+) -> f64 {
+    let (photon_settings, progressive) = match integrator {
+        Integrator::Photon(photon_settings) => (photon_settings, false),
+        Integrator::Sppm(photon_settings) => (photon_settings, true),
+        _ => return 0.0,
+    };
+    let mut radius_squared = if photon_settings.radius > 0.0 {
+        photon_settings.radius * photon_settings.radius
+    } else {
+        let shutter = (camera.time(0.0), camera.time(1.0));
+        match world.bounding_box(shutter.0, shutter.1) {
+            Some(bounds) => ((*bounds.max() - *bounds.min()).length() / 100.0).powi(2),
+            None => 0.0,
+        }
+    };
+    if progressive {
+        for i in 1..=pass {
+            radius_squared *= (i as f64 - 1.0 + PROGRESSIVE_ALPHA) / i as f64;
+        }
+    }
+    radius_squared.sqrt()
+}
+
+// Where photons start from, with the power they carry before dividing by the
+// number of photons.
+fn emit_photon(
+    world: &HittableList,
+    camera: &Camera,
+    background: &Color,
+    sampler: &mut Sampler,
+) -> Option<(Ray, Color)> {
+    let time = camera.time(sampler.get_1d());
+    let lights = world.lights();
+    // The background is one more light to pick.
+    let emitters = lights.len() + (luminance(background) > 0.0) as usize;
+    if emitters == 0 {
+        return None;
+    }
+    let index = ((sampler.get_1d() * emitters as f64) as usize).min(emitters - 1);
+    if index < lights.len() {
+        let hit = random_point(&lights[index], sampler);
+        let (direction, pdf_dir) = sample_emission(&hit, sampler);
+        if pdf_dir <= 0.0 {
+            return None;
+        }
+        let cosine = dot(hit.normal(), &direction).abs();
+        let radiance = emitted(hit.material(), hit.uv().0, hit.uv().1, hit.point());
+        let pdf = pdf_dir / (emitters as f64 * area(&lights[index]));
+        let ray = Ray::new(*hit.point(), direction, Some(time));
+        return Some((ray, radiance * (cosine / pdf)));
+    }
+    // Background photons come from every direction, through a disc that faces
+    // them and covers the scene's bounding sphere.
+    let bounds = world.bounding_box(camera.time(0.0), camera.time(1.0))?;
+    let center = (*bounds.min() + *bounds.max()) / 2.0;
+    let radius = (*bounds.max() - *bounds.min()).length() / 2.0;
+    let direction = sample_unit_vector(sampler.get_2d());
+    let (a, b) = orthonormal_basis(&direction);
+    let disc = sample_unit_disk(sampler.get_2d()) * radius;
+    let origin = center - direction * radius + a * disc.x() + b * disc.y();
+    let pdf = 1.0 / (emitters as f64 * 4.0 * PI * PI * radius * radius);
+    Some((Ray::new(origin, direction, Some(time)), *background / pdf))
+}
+
+// Follows one photon through the world, leaving a copy of it on every diffuse
+// surface after the first one it reaches.
+fn trace_photon(
+    world: &HittableList,
+    camera: &Camera,
+    background: &Color,
+    settings: &RenderSettings,
+    scale: f64,
+    sampler: &mut Sampler,
+    photons: &mut Vec<Photon>,
+) {
+    let (mut ray, power) = match emit_photon(world, camera, background, sampler) {
+        Some(emitted) => emitted,
+        None => return,
+    };
+    let power = power * scale;
+    let mut beta = Color::WHITE;
+    for depth in 0..settings.max_depth {
+        stats::record(|stats| stats.secondary_rays += 1);
+        let hit = match world.hit(&ray, 0.001, INIFINITY, sampler) {
+            Some(hit) => hit,
+            None => return,
+        };
+        let wo = -*ray.direction();
+        if depth > 0 && is_diffuse_surface(hit.material()) {
+            photons.push(Photon {
+                point: *hit.point(),
+                direction: wo,
+                power: power * beta,
+            });
+        }
+        let bsdf_sample = match sample(hit.material(), &hit, &wo, sampler) {
+            Some(bsdf_sample) => bsdf_sample,
+            None => return,
+        };
+        beta = if bsdf_sample.lobe.contains(Lobes::SPECULAR) {
+            beta * bsdf_sample.weight()
+        } else {
+            beta * adjoint_eval(&hit, &wo, &bsdf_sample.direction) / bsdf_sample.pdf
+        };
+        if depth + 1 >= ROULETTE_DEPTH {
+            let survival = beta.x().max(beta.y()).max(beta.z()).min(0.95);
+            if sampler.get_1d() >= survival {
+                return;
+            }
+            beta = beta / survival;
+        }
+        ray = Ray::new(*hit.point(), bsdf_sample.direction, Some(ray.time()));
+    }
+}
+
+// Shoots the photons of the pass with the given index, spread over the worker
+// threads, and builds the map to gather them from.
+pub fn trace_photons(
+    world: &HittableList,
+    camera: &Camera,
+    background: &Color,
+    settings: &RenderSettings,
+    pass: u64,
+) -> PhotonMap {
+    let photon_count = match settings.integrator {
+        Integrator::Photon(photon_settings) | Integrator::Sppm(photon_settings) => {
+            photon_settings.photons
+        }
+        _ => 0,
+    };
+    let radius = gather_radius(world, camera, settings.integrator, pass);
+    let scale = 1.0 / photon_count.max(1) as f64;
+    let batch_count = photon_count.div_ceil(PHOTON_BATCH);
+    let next_batch = AtomicUsize::new(0);
+    let mut batches = Vec::with_capacity(batch_count);
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..settings.worker_count() {
+            let sender = sender.clone();
+            let next_batch = &next_batch;
+            scope.spawn(move || loop {
+                let batch = next_batch.fetch_add(1, Ordering::Relaxed);
+                if batch >= batch_count {
+                    stats::flush();
+                    break;
+                }
+                // Photon samplers get rows below the image, away from the
+                // pixels' sequences.
+                let mut sampler = Sampler::new(
+                    settings.sampler,
+                    settings.seed,
+                    pass,
+                    (0, settings.height + batch),
+                    settings.width,
+                );
+                let size = PHOTON_BATCH.min(photon_count - batch * PHOTON_BATCH);
+                let mut photons = Vec::new();
+                for i in 0..size {
+                    let sample_index = pass * PHOTON_BATCH as u64 + i as u64;
+                    sampler.start_sample(sample_index, i as u32, size as u32);
+                    trace_photon(
+                        world,
+                        camera,
+                        background,
+                        settings,
+                        scale,
+                        &mut sampler,
+                        &mut photons,
+                    );
+                }
+                sender.send((batch, photons)).unwrap();
+            });
+        }
+        drop(sender);
+        batches.extend(receiver.iter());
+    });
+    batches.sort_by_key(|(batch, _)| *batch);
+    let photons = batches
+        .into_iter()
+        .flat_map(|(_, photons)| photons)
+        .collect();
+    PhotonMap::new(photons, radius)
+}
+
+// Radiance along a camera ray, estimated from `photon_map` at the first diffuse
+// surface the ray reaches through specular and glossy bounces. Everything up to
+// there, and the direct light at that surface, is path traced as in `ray_color`.
+pub fn photon_color(
+    ray: &Ray,
+    background: &Color,
+    world: &HittableList,
+    photon_map: &PhotonMap,
+    settings: &RenderSettings,
+    sampler: &mut Sampler,
+) -> Color {
+    let (max_depth, heuristic) = (settings.max_depth, settings.mis_heuristic);
+    let mut color = Color::BLACK;
+    let mut throughput = Color::WHITE;
+    let mut ray = *ray;
+    let mut bsdf_pdf = None;
+    let mut path_depth = 0;
+    for depth in 0..max_depth {
+        if depth > 0 {
+            stats::record(|stats| stats.secondary_rays += 1);
+        }
+        let hit = match world.hit(&ray, 0.001, INIFINITY, sampler) {
+            Some(hit) => hit,
+            None => {
+                color = color + throughput * *background;
+                break;
+            }
+        };
+        path_depth += 1;
+        let emission = emitted(hit.material(), hit.uv().0, hit.uv().1, hit.point());
+        if luminance(&emission) > 0.0 {
+            let weight = match bsdf_pdf {
+                Some(bsdf_pdf) => {
+                    let light_pdf = world.lights_pdf_value(ray.origin(), ray.direction(), sampler);
+                    heuristic.weight(bsdf_pdf, light_pdf)
+                }
+                None => 1.0,
+            };
+            color = color + throughput * emission * weight;
+        }
+        if depth + 1 >= max_depth {
+            break;
+        }
+        if lobes(hit.material()).is_non_specular() && !world.lights().is_empty() {
+            color = color + throughput * sample_direct_light(&ray, &hit, world, heuristic, sampler);
+        }
+        let wo = -*ray.direction();
+        let bsdf_sample = match sample(hit.material(), &hit, &wo, sampler) {
+            Some(bsdf_sample) => bsdf_sample,
+            None => break,
+        };
+        if is_diffuse_surface(hit.material()) {
+            color = color + throughput * photon_map.radiance(&hit, &wo);
+            // The bounce only picks up emitters, which the shadow ray above
+            // shares with, and the background, which it doesn't.
+            let throughput = throughput * bsdf_sample.weight();
+            let next = Ray::new(*hit.point(), bsdf_sample.direction, Some(ray.time()));
+            stats::record(|stats| stats.secondary_rays += 1);
+            match world.hit(&next, 0.001, INIFINITY, sampler) {
+                Some(light) => {
+                    let (u, v) = light.uv();
+                    let emission = emitted(light.material(), u, v, light.point());
+                    if luminance(&emission) > 0.0 {
+                        let light_pdf =
+                            world.lights_pdf_value(next.origin(), next.direction(), sampler);
+                        let weight = heuristic.weight(bsdf_sample.pdf, light_pdf);
+                        color = color + throughput * emission * weight;
+                    }
+                }
+                None => color = color + throughput * *background,
+            }
+            break;
+        }
+        bsdf_pdf = if bsdf_sample.lobe.contains(Lobes::SPECULAR) {
+            None
+        } else {
+            Some(bsdf_sample.pdf)
+        };
+        throughput = throughput * bsdf_sample.weight();
+        if depth + 1 >= ROULETTE_DEPTH {
+            let survival = throughput
+                .x()
+                .max(throughput.y())
+                .max(throughput.z())
+                .min(0.95);
+            if sampler.get_1d() >= survival {
+                break;
+            }
+            throughput = throughput / survival;
+        }
+        ray = Ray::new(*hit.point(), bsdf_sample.direction, Some(ray.time()));
+    }
+    stats::record(|stats| stats.add_path(path_depth));
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Photons tagged with their index in the red power, half of them on a
+    // coarse grid so that many share a coordinate with the median.
+    fn random_photons(rng: &mut Rng, count: usize) -> Vec<Photon> {
+        (0..count)
+            .map(|i| {
+                let mut coordinate = || {
+                    let x = 10.0 * rng.random_double();
+                    if i % 2 == 0 {
+                        x.floor()
+                    } else {
+                        x
+                    }
+                };
+                Photon {
+                    point: Point3::new(coordinate(), coordinate(), coordinate()),
+                    direction: Vec3::UP,
+                    power: Color::new(i as f64, 0.0, 0.0),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn kd_tree_finds_the_photons_a_linear_search_finds() {
+        let mut rng = Rng::new(11);
+        for &(count, radius) in &[(0, 1.0), (1, 1.0), (2, 0.5), (500, 0.7), (2000, 2.5)] {
+            let photons = random_photons(&mut rng, count);
+            let map = PhotonMap::new(photons.clone(), radius);
+            for _ in 0..200 {
+                let point = Point3::new(
+                    12.0 * rng.random_double() - 1.0,
+                    12.0 * rng.random_double() - 1.0,
+                    (12.0 * rng.random_double() - 1.0).floor(),
+                );
+                let mut expected: Vec<usize> = photons
+                    .iter()
+                    .filter(|photon| (photon.point - point).length_squared() <= radius * radius)
+                    .map(|photon| photon.power.x() as usize)
+                    .collect();
+                let mut found = Vec::new();
+                map.for_each_near(&point, &mut |photon| found.push(photon.power.x() as usize));
+                expected.sort_unstable();
+                found.sort_unstable();
+                assert_eq!(found, expected, "{} photons around {:?}", count, point);
+            }
+        }
+    }
+}
@@ -0,0 +1,688 @@
+use crate::{material::*, math::*, mesh::*};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+// Stanford PLY meshes, as ASCII or as little or big endian binary. Of the
+// elements the header declares, vertices give positions and, when they have
+// them, normals, UVs and colours, and faces give vertex index lists, which are
+// triangulated as fans around their first corner. Everything else is read past.
+// The body is parsed as it streams in, so only the mesh itself is kept.
+
+// Vertex properties we know, by the names they go by, in the order x, y, z,
+// nx, ny, nz, u, v, red, green, blue.
+const VERTEX_PROPERTIES: [&[&str]; 11] = [
+    &["x"],
+    &["y"],
+    &["z"],
+    &["nx"],
+    &["ny"],
+    &["nz"],
+    &["u", "s", "texture_u", "texture_s"],
+    &["v", "t", "texture_v", "texture_t"],
+    &["red", "diffuse_red"],
+    &["green", "diffuse_green"],
+    &["blue", "diffuse_blue"],
+];
+const FIRST_COLOR: usize = 8;
+
+// Helper functions
+fn parse_error(path: &Path, line: Option<usize>, message: &str) -> io::Error {
+    let location = match line {
+        Some(line) => format!("{}:{}", path.display(), line),
+        None => path.display().to_string(),
+    };
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", location, message),
+    )
+}
+fn parse_count(token: &str) -> Result<usize, String> {
+    token
+        .parse()
+        .map_err(|_| format!("Invalid element count '{}'", token))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+// Scalar
+#[derive(Clone, Copy)]
+enum Scalar {
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+    Float64,
+}
+impl Scalar {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "char" | "int8" => Ok(Scalar::Int8),
+            "uchar" | "uint8" => Ok(Scalar::Uint8),
+            "short" | "int16" => Ok(Scalar::Int16),
+            "ushort" | "uint16" => Ok(Scalar::Uint16),
+            "int" | "int32" => Ok(Scalar::Int32),
+            "uint" | "uint32" => Ok(Scalar::Uint32),
+            "float" | "float32" => Ok(Scalar::Float32),
+            "double" | "float64" => Ok(Scalar::Float64),
+            _ => Err(format!("Unknown property type '{}'", name)),
+        }
+    }
+    fn size(self) -> usize {
+        match self {
+            Scalar::Int8 | Scalar::Uint8 => 1,
+            Scalar::Int16 | Scalar::Uint16 => 2,
+            Scalar::Int32 | Scalar::Uint32 | Scalar::Float32 => 4,
+            Scalar::Float64 => 8,
+        }
+    }
+    // Colours stored as integers go from 0 to the largest value of the type.
+    fn color_scale(self) -> f64 {
+        match self {
+            Scalar::Int8 => 1.0 / i8::MAX as f64,
+            Scalar::Uint8 => 1.0 / u8::MAX as f64,
+            Scalar::Int16 => 1.0 / i16::MAX as f64,
+            Scalar::Uint16 => 1.0 / u16::MAX as f64,
+            Scalar::Int32 => 1.0 / i32::MAX as f64,
+            Scalar::Uint32 => 1.0 / u32::MAX as f64,
+            Scalar::Float32 | Scalar::Float64 => 1.0,
+        }
+    }
+}
+
+// Property
+// A list property has the type of its length and the type of its items.
+struct Property {
+    name: String,
+    scalar: Scalar,
+    list: Option<Scalar>,
+}
+
+// Element
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+impl Element {
+    // Fewest bytes one of these elements takes in the body, to tell counts
+    // that can't be right before memory is set aside for them.
+    fn min_size(&self, format: Format) -> u64 {
+        self.properties
+            .iter()
+            .map(|property| match (format, property.list) {
+                (Format::Ascii, _) => 2,
+                (_, Some(count)) => count.size() as u64,
+                (_, None) => property.scalar.size() as u64,
+            })
+            .sum()
+    }
+}
+
+// Header
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    lines: usize,
+    size: u64,
+}
+fn read_header<R: BufRead>(reader: &mut R, path: &Path) -> io::Result<Header> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut size = 0;
+    let mut bytes = Vec::new();
+    let mut number = 0;
+    loop {
+        number += 1;
+        bytes.clear();
+        let read = reader.read_until(b'\n', &mut bytes)?;
+        if read == 0 {
+            return Err(parse_error(path, None, "The header has no end_header"));
+        }
+        size += read as u64;
+        let line = String::from_utf8_lossy(&bytes);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if number == 1 {
+            if tokens != ["ply"] {
+                return Err(parse_error(path, None, "Not a PLY file"));
+            }
+            continue;
+        }
+        let error = |message: String| parse_error(path, Some(number), &message);
+        match tokens.as_slice() {
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", name, version] => {
+                if *version != "1.0" {
+                    return Err(error(format!("Unsupported PLY version '{}'", version)));
+                }
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(error(format!("Unknown format '{}'", name))),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: parse_count(count).map_err(error)?,
+                properties: Vec::new(),
+            }),
+            ["property", kind @ .., name] => {
+                let property = match kind {
+                    [scalar] => Property {
+                        name: name.to_string(),
+                        scalar: Scalar::parse(scalar).map_err(error)?,
+                        list: None,
+                    },
+                    ["list", count, scalar] => Property {
+                        name: name.to_string(),
+                        scalar: Scalar::parse(scalar).map_err(error)?,
+                        list: Some(Scalar::parse(count).map_err(error)?),
+                    },
+                    _ => return Err(error(String::from("Invalid property"))),
+                };
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(property),
+                    None => return Err(error(String::from("Property before any element"))),
+                }
+            }
+            ["end_header"] => {
+                return match format {
+                    Some(format) => Ok(Header {
+                        format,
+                        elements,
+                        lines: number,
+                        size,
+                    }),
+                    None => Err(error(String::from("The header has no format"))),
+                };
+            }
+            _ => return Err(error(format!("Unexpected line '{}'", line.trim()))),
+        }
+    }
+}
+
+// Body
+// Reads the values of the body one at a time, as whitespace separated text or
+// as binary numbers.
+struct Body<R> {
+    reader: R,
+    format: Format,
+    line: String,
+    position: usize,
+    line_number: usize,
+}
+impl<R: BufRead> Body<R> {
+    // The line of the last value read, binary files have none.
+    fn line_number(&self) -> Option<usize> {
+        match self.format {
+            Format::Ascii => Some(self.line_number),
+            _ => None,
+        }
+    }
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        match self.format {
+            Format::Ascii => {
+                let token = self.next_token()?;
+                token
+                    .parse()
+                    .map_err(|_| format!("Invalid number '{}'", token))
+            }
+            _ => self.read_binary(scalar),
+        }
+    }
+    fn next_token(&mut self) -> Result<&str, String> {
+        loop {
+            let rest = self.line[self.position..].trim_start();
+            if !rest.is_empty() {
+                let start = self.line.len() - rest.len();
+                let end = start + rest.find(char::is_whitespace).unwrap_or(rest.len());
+                self.position = end;
+                return Ok(&self.line[start..end]);
+            }
+            self.line.clear();
+            self.position = 0;
+            self.line_number += 1;
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return Err(String::from("Unexpected end of file")),
+                Ok(_) => {}
+                Err(error) => return Err(error.to_string()),
+            }
+        }
+    }
+    fn read_binary(&mut self, scalar: Scalar) -> Result<f64, String> {
+        let mut buffer = [0; 8];
+        let b = &mut buffer[..scalar.size()];
+        self.reader.read_exact(b).map_err(|error| {
+            if error.kind() == io::ErrorKind::UnexpectedEof {
+                String::from("Unexpected end of file")
+            } else {
+                error.to_string()
+            }
+        })?;
+        if self.format == Format::BinaryBigEndian {
+            b.reverse();
+        }
+        Ok(match scalar {
+            Scalar::Int8 => i8::from_le_bytes([b[0]]) as f64,
+            Scalar::Uint8 => b[0] as f64,
+            Scalar::Int16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::Uint16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::Int32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::Uint32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::Float32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::Float64 => f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+        })
+    }
+    // The length of a list property, then its items are read by the caller.
+    fn read_length(&mut self, scalar: Scalar) -> Result<usize, String> {
+        let length = self.read(scalar)?;
+        if length < 0.0 || length.fract() != 0.0 {
+            return Err(format!("Invalid list length {}", length));
+        }
+        Ok(length as usize)
+    }
+    fn skip(&mut self, property: &Property) -> Result<(), String> {
+        let length = match property.list {
+            Some(count) => self.read_length(count)?,
+            None => 1,
+        };
+        for _ in 0..length {
+            self.read(property.scalar)?;
+        }
+        Ok(())
+    }
+}
+
+// Where each vertex property goes in VERTEX_PROPERTIES and what it is scaled
+// by, None for those that are read past.
+fn vertex_slots(element: &Element) -> Result<Vec<Option<(usize, f64)>>, String> {
+    let slots: Vec<Option<(usize, f64)>> = element
+        .properties
+        .iter()
+        .map(|property| {
+            let slot = VERTEX_PROPERTIES
+                .iter()
+                .position(|names| names.contains(&property.name.as_str()))
+                .filter(|_| property.list.is_none())?;
+            let scale = if slot >= FIRST_COLOR {
+                property.scalar.color_scale()
+            } else {
+                1.0
+            };
+            Some((slot, scale))
+        })
+        .collect();
+    if !(0..3).all(|slot| slots.iter().flatten().any(|(found, _)| *found == slot)) {
+        return Err(String::from("Vertices need x, y and z properties"));
+    }
+    Ok(slots)
+}
+// The face property with the vertex indices.
+fn index_list(element: &Element) -> Result<usize, String> {
+    element
+        .properties
+        .iter()
+        .position(|property| {
+            property.list.is_some()
+                && (property.name == "vertex_indices" || property.name == "vertex_index")
+        })
+        .ok_or_else(|| String::from("Faces need a vertex_indices list"))
+}
+
+// PlyMesh
+// What has been read of the mesh so far.
+#[derive(Default)]
+struct PlyMesh {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f64, f64)>,
+    colors: Vec<Color>,
+    triangles: Vec<[u32; 3]>,
+}
+impl PlyMesh {
+    fn read_vertices<R: BufRead>(
+        &mut self,
+        body: &mut Body<R>,
+        element: &Element,
+        slots: &[Option<(usize, f64)>],
+    ) -> Result<(), String> {
+        let has = |mut range: std::ops::Range<usize>| {
+            range.all(|slot| slots.iter().flatten().any(|(found, _)| *found == slot))
+        };
+        let (has_normals, has_uvs, has_colors) = (has(3..6), has(6..8), has(8..11));
+        self.positions.reserve_exact(element.count);
+        if has_normals {
+            self.normals.reserve_exact(element.count);
+        }
+        if has_uvs {
+            self.uvs.reserve_exact(element.count);
+        }
+        if has_colors {
+            self.colors.reserve_exact(element.count);
+        }
+        let mut values = [0.0; 11];
+        for _ in 0..element.count {
+            for (property, slot) in element.properties.iter().zip(slots) {
+                match slot {
+                    Some((slot, scale)) => values[*slot] = body.read(property.scalar)? * scale,
+                    None => body.skip(property)?,
+                }
+            }
+            let v = &values;
+            let position = Point3::new(v[0], v[1], v[2]);
+            if !position.is_finite() {
+                return Err(format!(
+                    "Vertex {} position is not finite",
+                    self.positions.len()
+                ));
+            }
+            self.positions.push(position);
+            if has_normals {
+                self.normals.push(Vec3::new(v[3], v[4], v[5]));
+            }
+            if has_uvs {
+                self.uvs.push((v[6], v[7]));
+            }
+            if has_colors {
+                self.colors.push(Color::new(v[8], v[9], v[10]));
+            }
+        }
+        Ok(())
+    }
+    // Faces with fewer than three distinct corners are left out.
+    fn read_faces<R: BufRead>(
+        &mut self,
+        body: &mut Body<R>,
+        element: &Element,
+        (indices, vertex_count): (usize, usize),
+    ) -> Result<(), String> {
+        self.triangles.reserve(element.count);
+        for _ in 0..element.count {
+            for (index, property) in element.properties.iter().enumerate() {
+                if index != indices {
+                    body.skip(property)?;
+                    continue;
+                }
+                let length = body.read_length(property.list.unwrap())?;
+                let mut corners = [0; 3];
+                for corner in 0..length {
+                    let vertex = body.read(property.scalar)?;
+                    if vertex < 0.0 || vertex.fract() != 0.0 || vertex >= vertex_count as f64 {
+                        return Err(format!("Invalid vertex index {}", vertex));
+                    }
+                    if corner < 2 {
+                        corners[corner] = vertex as u32;
+                        continue;
+                    }
+                    corners[2] = vertex as u32;
+                    let [a, b, c] = corners;
+                    if a != b && b != c && c != a {
+                        self.triangles.push(corners);
+                    }
+                    corners[1] = corners[2];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Loads the mesh in a PLY file, all of it in one material. Give the material a
+// `Texture::VertexColor` to show the colours of the vertices.
+pub fn load_ply<P: AsRef<Path>>(path: P, material: Material) -> io::Result<TriangleMesh> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let header = read_header(&mut reader, path)?;
+    let error = |message: &str| parse_error(path, None, message);
+    let elements = &header.elements;
+    let vertices = match elements.iter().position(|element| element.name == "vertex") {
+        Some(vertices) => vertices,
+        None => return Err(error("No vertex element")),
+    };
+    let faces = match elements.iter().position(|element| element.name == "face") {
+        Some(faces) => faces,
+        None => return Err(error("No face element")),
+    };
+    let slots = vertex_slots(&elements[vertices]).map_err(|message| error(&message))?;
+    let indices = index_list(&elements[faces]).map_err(|message| error(&message))?;
+    let vertex_count = elements[vertices].count;
+    for element in elements {
+        // Elements without properties take no room, so nothing bounds their
+        // count.
+        if element.properties.is_empty() && element.count > 0 {
+            return Err(error(&format!(
+                "{} {} elements have no properties",
+                element.count, element.name
+            )));
+        }
+        let size = element.min_size(header.format);
+        if (element.count as u64).saturating_mul(size) > file_size - header.size {
+            return Err(error(&format!(
+                "{} {} elements can't fit in the file",
+                element.count, element.name
+            )));
+        }
+    }
+    let mut body = Body {
+        reader,
+        format: header.format,
+        line: String::new(),
+        position: 0,
+        line_number: header.lines,
+    };
+    let mut mesh = PlyMesh::default();
+    for (index, element) in elements.iter().enumerate() {
+        if index == vertices {
+            mesh.read_vertices(&mut body, element, &slots)
+        } else if index == faces {
+            mesh.read_faces(&mut body, element, (indices, vertex_count))
+        } else {
+            (0..element.count)
+                .try_for_each(|_| element.properties.iter().try_for_each(|p| body.skip(p)))
+        }
+        .map_err(|message| parse_error(path, body.line_number(), &message))?;
+    }
+    if mesh.triangles.is_empty() {
+        return Err(error("No faces with three distinct corners"));
+    }
+    Ok(TriangleMesh::new(
+        mesh.positions,
+        mesh.normals,
+        mesh.uvs,
+        Vec::new(),
+        mesh.colors,
+        mesh.triangles,
+        material,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A quad and a triangle, with an element we don't know in between.
+    const POSITIONS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.5, 0.0],
+        [0.0, 1.5, 0.0],
+        [0.5, 2.0, -0.25],
+    ];
+    const COLORS: [[u8; 3]; 5] = [
+        [255, 0, 0],
+        [0, 255, 0],
+        [0, 0, 255],
+        [51, 102, 153],
+        [0, 0, 0],
+    ];
+    const FACES: [&[u32]; 2] = [&[0, 1, 2, 3], &[3, 2, 4]];
+
+    fn header(format: &str, faces: usize) -> String {
+        format!(
+            "ply\nformat {} 1.0\ncomment a test\nelement vertex 5\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element edge 1\nproperty int vertex1\nproperty int vertex2\n\
+             element face {}\nproperty list uchar int vertex_indices\nend_header\n",
+            format, faces
+        )
+    }
+    fn ascii(faces: &[&[u32]]) -> Vec<u8> {
+        let mut text = header("ascii", faces.len());
+        for (p, c) in POSITIONS.iter().zip(&COLORS) {
+            text += &format!("{} {} {} {} {} {}\n", p[0], p[1], p[2], c[0], c[1], c[2]);
+        }
+        text += "0 1\n";
+        for face in faces {
+            let indices: Vec<String> = face.iter().map(|index| index.to_string()).collect();
+            text += &format!("{} {}\n", face.len(), indices.join(" "));
+        }
+        text.into_bytes()
+    }
+    // The header may claim a different number of faces than there are.
+    fn binary(big_endian: bool, header_faces: usize) -> Vec<u8> {
+        let format = if big_endian {
+            "binary_big_endian"
+        } else {
+            "binary_little_endian"
+        };
+        let mut bytes = header(format, header_faces).into_bytes();
+        let push = |bytes: &mut Vec<u8>, mut word: [u8; 4]| {
+            if big_endian {
+                word.reverse();
+            }
+            bytes.extend(word);
+        };
+        for (p, c) in POSITIONS.iter().zip(&COLORS) {
+            p.iter().for_each(|x| push(&mut bytes, x.to_le_bytes()));
+            bytes.extend(c);
+        }
+        push(&mut bytes, 0i32.to_le_bytes());
+        push(&mut bytes, 1i32.to_le_bytes());
+        for face in &FACES {
+            bytes.push(face.len() as u8);
+            face.iter()
+                .for_each(|index| push(&mut bytes, index.to_le_bytes()));
+        }
+        bytes
+    }
+    fn write(name: &str, bytes: &[u8]) -> PathBuf {
+        let directory = std::env::temp_dir().join("raytrace_rs_ply_test");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+    fn load(name: &str, bytes: &[u8]) -> io::Result<TriangleMesh> {
+        load_ply(
+            write(name, bytes),
+            Material::Lambertian(Texture::VertexColor),
+        )
+    }
+    fn error(name: &str, bytes: &[u8]) -> String {
+        load(name, bytes).err().unwrap().to_string()
+    }
+    // Triangles in the order they were read rather than in BVH order.
+    fn sorted_triangles(mesh: &TriangleMesh) -> Vec<[u32; 3]> {
+        let mut triangles = mesh.triangles().to_vec();
+        triangles.sort_unstable();
+        triangles
+    }
+
+    #[test]
+    fn formats_load_the_same_mesh() {
+        let meshes = [
+            load("tiny_ascii.ply", &ascii(&FACES)).unwrap(),
+            load("tiny_le.ply", &binary(false, FACES.len())).unwrap(),
+            load("tiny_be.ply", &binary(true, FACES.len())).unwrap(),
+        ];
+        for mesh in &meshes {
+            for (position, expected) in mesh.positions().iter().zip(&POSITIONS) {
+                let expected = expected.map(f64::from);
+                assert_eq!([position.x(), position.y(), position.z()], expected);
+            }
+            for (color, expected) in mesh.colors().iter().zip(&COLORS) {
+                let expected = expected.map(|c| c as f64 / 255.0);
+                assert_eq!([color.x(), color.y(), color.z()], expected);
+            }
+            assert_eq!(mesh.positions().len(), 5);
+            assert_eq!(mesh.colors().len(), 5);
+            assert!(mesh.normals().is_empty() && mesh.uvs().is_empty());
+            assert_eq!(
+                sorted_triangles(mesh),
+                vec![[0, 1, 2], [0, 2, 3], [3, 2, 4]]
+            );
+        }
+    }
+
+    #[test]
+    fn polygons_become_fans() {
+        // A pentagon, and a quad with a repeated corner.
+        let mesh = load("fans.ply", &ascii(&[&[0, 1, 2, 4, 3], &[0, 1, 1, 2]])).unwrap();
+        assert_eq!(
+            sorted_triangles(&mesh),
+            vec![[0, 1, 2], [0, 1, 2], [0, 2, 4], [0, 4, 3]]
+        );
+    }
+
+    #[test]
+    fn counts_that_cant_fit_are_rejected() {
+        let message = error("big_count.ply", &binary(false, 9999));
+        assert!(
+            message.ends_with(": 9999 face elements can't fit in the file"),
+            "{}",
+            message
+        );
+        let text = String::from_utf8(ascii(&FACES)).unwrap().replace(
+            "element vertex",
+            "element junk 18446744073709551615\nelement vertex",
+        );
+        let message = error("empty_element.ply", text.as_bytes());
+        assert!(
+            message.ends_with(": 18446744073709551615 junk elements have no properties"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn bad_indices_give_the_line() {
+        let path = write("bad_index.ply", &ascii(&[&[0, 1, 2], &[3, 2, 7]]));
+        let message = load_ply(&path, Material::Lambertian(Texture::VertexColor))
+            .err()
+            .unwrap()
+            .to_string();
+        // 16 header lines, 5 vertices, an edge and a face come before it.
+        let expected = format!("{}:24: Invalid vertex index 7", path.display());
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn non_finite_positions_give_the_line() {
+        let text = String::from_utf8(ascii(&FACES)).unwrap();
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        // The second vertex.
+        lines[17] = format!("inf {}", lines[17].split_once(' ').unwrap().1);
+        let path = write("infinite.ply", (lines.join("\n") + "\n").as_bytes());
+        let message = load_ply(&path, Material::Lambertian(Texture::VertexColor))
+            .err()
+            .unwrap()
+            .to_string();
+        let expected = format!("{}:18: Vertex 1 position is not finite", path.display());
+        assert_eq!(message, expected);
+    }
+}
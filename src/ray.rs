@@ -30,6 +30,7 @@ impl Ray {
 }
 
 // RayHit
+#[derive(Clone)]
 pub struct RayHit<'a> {
     point: Point3,
     distance: f64,
@@ -37,6 +38,7 @@ pub struct RayHit<'a> {
     normal: Vec3,
     uv: (f64, f64),
     front_face: bool,
+    color: Option<Color>,
 }
 impl<'a> RayHit<'a> {
     pub fn new(
@@ -60,8 +62,43 @@ impl<'a> RayHit<'a> {
             normal,
             uv,
             front_face,
+            color: None,
         }
     }
+    // A hit whose side is told by the geometric normal but that shades with
+    // `shading_normal`, turned to the same side.
+    pub fn with_shading_normal(
+        ray: &Ray,
+        point: Point3,
+        distance: f64,
+        material: &'a Material,
+        outward_normal: Vec3,
+        shading_normal: Vec3,
+        uv: (f64, f64),
+    ) -> Self {
+        let mut hit = RayHit::new(ray, point, distance, material, outward_normal, uv);
+        let facing = if dot(&shading_normal, &hit.normal) < 0.0 {
+            -shading_normal
+        } else {
+            shading_normal
+        };
+        hit.normal = facing;
+        hit
+    }
+    // The same hit, taken into another space where it is at `point`,
+    // `distance` along the ray, with `normal` still facing the ray.
+    pub fn transformed(self, point: Point3, distance: f64, normal: Vec3) -> Self {
+        RayHit {
+            point,
+            distance,
+            normal,
+            ..self
+        }
+    }
+    // The same hit, with the vertex colour of the surface there.
+    pub fn with_color(self, color: Option<Color>) -> Self {
+        RayHit { color, ..self }
+    }
     pub fn point(&self) -> &Point3 {
         &self.point
     }
@@ -80,4 +117,7 @@ impl<'a> RayHit<'a> {
     pub fn front_face(&self) -> bool {
         self.front_face
     }
+    pub fn color(&self) -> Option<Color> {
+        self.color
+    }
 }
@@ -0,0 +1,1058 @@
+use crate::{
+    bdpt::*, camera::*, filter::*, geometry::*, material::*, math::*, output::*, photon::*, ray::*,
+    sampler::*, stats,
+};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Instant;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Bounces after which paths may be ended by Russian roulette.
+pub const ROULETTE_DEPTH: i32 = 3;
+
+// MisHeuristic
+// How light sampling and BSDF sampling share the paths both of them can make.
+// Each strategy's sample is weighted by its density against the other's.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MisHeuristic {
+    Balance,
+    // The balance heuristic on squared densities, which leans harder toward
+    // the better strategy.
+    Power,
+}
+impl MisHeuristic {
+    pub const NAMES: &'static [&'static str] = &["balance", "power"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "balance" => Some(MisHeuristic::Balance),
+            "power" => Some(MisHeuristic::Power),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            MisHeuristic::Balance => "balance",
+            MisHeuristic::Power => "power",
+        }
+    }
+    // Weight of a sample taken with density `pdf` when the other strategy would
+    // have made it with density `other_pdf`.
+    pub fn weight(&self, pdf: f64, other_pdf: f64) -> f64 {
+        let (a, b) = match self {
+            MisHeuristic::Balance => (pdf, other_pdf),
+            MisHeuristic::Power => (pdf * pdf, other_pdf * other_pdf),
+        };
+        if a + b <= 0.0 {
+            return 0.0;
+        }
+        a / (a + b)
+    }
+}
+
+// Integrator
+// How the radiance of a camera ray is estimated.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Integrator {
+    // Unidirectional path tracing, `ray_color`.
+    Path,
+    // Bidirectional path tracing, `bdpt_color`.
+    Bdpt,
+    // Photon mapping, `photon_color`, with a new photon map every pass.
+    Photon(PhotonSettings),
+    // Progressive photon mapping, which also shrinks the gather radius every
+    // pass.
+    Sppm(PhotonSettings),
+}
+impl Integrator {
+    pub const NAMES: &'static [&'static str] = &["path", "bdpt", "photon", "sppm"];
+
+    // `photons` only matters to the photon mapping ones.
+    pub fn from_name(name: &str, photons: PhotonSettings) -> Option<Self> {
+        match name {
+            "path" => Some(Integrator::Path),
+            "bdpt" => Some(Integrator::Bdpt),
+            "photon" => Some(Integrator::Photon(photons)),
+            "sppm" => Some(Integrator::Sppm(photons)),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Integrator::Path => "path",
+            Integrator::Bdpt => "bdpt",
+            Integrator::Photon(_) => "photon",
+            Integrator::Sppm(_) => "sppm",
+        }
+    }
+    pub fn photon_settings(&self) -> Option<PhotonSettings> {
+        match self {
+            Integrator::Photon(photons) | Integrator::Sppm(photons) => Some(*photons),
+            _ => None,
+        }
+    }
+}
+
+// Light scattered toward the ray at `hit` from a shadow ray toward a point on
+// one of the world's lights. The light density is that of picking the direction
+// through any light, so whichever emitter the shadow ray ends up on is weighted
+// right.
+pub fn sample_direct_light(
+    ray: &Ray,
+    hit: &RayHit,
+    world: &HittableList,
+    heuristic: MisHeuristic,
+    sampler: &mut Sampler,
+) -> Color {
+    let direction = world.random_light_direction(hit.point(), sampler);
+    let light_pdf = world.lights_pdf_value(hit.point(), &direction, sampler);
+    if light_pdf <= 0.0 {
+        return Color::BLACK;
+    }
+    let wo = -*ray.direction();
+    let wi = normalize(&direction);
+    let f = eval(hit.material(), hit, &wo, &wi);
+    if luminance(&f) <= 0.0 {
+        return Color::BLACK;
+    }
+    let shadow_ray = Ray::new(*hit.point(), direction, Some(ray.time()));
+    stats::record(|stats| stats.shadow_rays += 1);
+    match world.hit(&shadow_ray, 0.001, INIFINITY, sampler) {
+        Some(light) => {
+            let (u, v) = light.uv();
+            let radiance = emitted(light.material(), u, v, light.point());
+            let bsdf_pdf = pdf(hit.material(), hit, &wo, &wi);
+            f * radiance * (heuristic.weight(light_pdf, bsdf_pdf) / light_pdf)
+        }
+        None => Color::BLACK,
+    }
+}
+
+// Follows a path of at most `max_depth` rays, adding up the light it picks up on
+// the way, weighted by the path throughput. Past ROULETTE_DEPTH, a path survives
+// each bounce with a probability that follows its throughput, and survivors are
+// weighted up to make up for the ones that stopped.
+// Light reaches every non-specular surface twice, through a shadow ray toward
+// the world's lights and through the next bounce hitting one, and `heuristic`
+// weighs the two against each other.
+pub fn ray_color(
+    ray: &Ray,
+    background: &Color,
+    world: &HittableList,
+    max_depth: i32,
+    heuristic: MisHeuristic,
+    sampler: &mut Sampler,
+) -> Color {
+    let mut color = Color::BLACK;
+    let mut throughput = Color::WHITE;
+    let mut ray = *ray;
+    // Density the last bounce picked the ray with, None when lights couldn't
+    // have been sampled instead: for camera rays and specular bounces.
+    let mut bsdf_pdf = None;
+    let mut path_depth = 0;
+    for depth in 0..max_depth {
+        if depth > 0 {
+            stats::record(|stats| stats.secondary_rays += 1);
+        }
+        let hit = match world.hit(&ray, 0.001, INIFINITY, sampler) {
+            Some(hit) => hit,
+            None => {
+                color = color + throughput * *background;
+                break;
+            }
+        };
+        path_depth += 1;
+        let emission = emitted(hit.material(), hit.uv().0, hit.uv().1, hit.point());
+        if luminance(&emission) > 0.0 {
+            let weight = match bsdf_pdf {
+                Some(bsdf_pdf) => {
+                    let light_pdf = world.lights_pdf_value(ray.origin(), ray.direction(), sampler);
+                    heuristic.weight(bsdf_pdf, light_pdf)
+                }
+                None => 1.0,
+            };
+            color = color + throughput * emission * weight;
+        }
+        let lobes = lobes(hit.material());
+        if lobes.is_non_specular() && !world.lights().is_empty() && depth + 1 < max_depth {
+            color = color + throughput * sample_direct_light(&ray, &hit, world, heuristic, sampler);
+        }
+        let wo = -*ray.direction();
+        let bsdf_sample = match sample(hit.material(), &hit, &wo, sampler) {
+            Some(bsdf_sample) => bsdf_sample,
+            None => break,
+        };
+        bsdf_pdf = if bsdf_sample.lobe.contains(Lobes::SPECULAR) {
+            None
+        } else {
+            Some(bsdf_sample.pdf)
+        };
+        throughput = throughput * bsdf_sample.weight();
+        if depth + 1 >= ROULETTE_DEPTH {
+            let survival = throughput
+                .x()
+                .max(throughput.y())
+                .max(throughput.z())
+                .min(0.95);
+            if sampler.get_1d() >= survival {
+                break;
+            }
+            throughput = throughput / survival;
+        }
+        ray = Ray::new(*hit.point(), bsdf_sample.direction, Some(ray.time()));
+    }
+    stats::record(|stats| stats.add_path(path_depth));
+    color
+}
+
+// Tile
+#[derive(Debug, Copy, Clone)]
+pub struct Tile {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+impl Tile {
+    pub fn new(x0: usize, y0: usize, x1: usize, y1: usize) -> Self {
+        Tile { x0, y0, x1, y1 }
+    }
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+    // Pixel coordinates covered by the tile, row by row.
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize)> {
+        let (x0, x1) = (self.x0, self.x1);
+        (self.y0..self.y1).flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+    }
+}
+pub fn generate_tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y0 in (0..height).step_by(tile_size) {
+        for x0 in (0..width).step_by(tile_size) {
+            tiles.push(Tile::new(
+                x0,
+                y0,
+                (x0 + tile_size).min(width),
+                (y0 + tile_size).min(height),
+            ));
+        }
+    }
+    tiles
+}
+
+// RenderSettings
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: i32,
+    pub max_depth: i32,
+    pub tile_size: usize,
+    // Number of worker threads, 0 picks one per available core.
+    pub threads: usize,
+    pub seed: u64,
+    pub filter: Filter,
+    pub sampler: SamplerKind,
+    pub mis_heuristic: MisHeuristic,
+    pub integrator: Integrator,
+}
+impl RenderSettings {
+    pub const DEFAULT_TILE_SIZE: usize = 16;
+
+    pub fn worker_count(&self) -> usize {
+        if self.threads > 0 {
+            return self.threads;
+        }
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    }
+}
+
+// ProgressiveSettings
+#[derive(Debug, Clone)]
+pub struct ProgressiveSettings {
+    pub samples_per_pass: i32,
+    // Snapshot after this many passes, 0 disables it.
+    pub snapshot_passes: u32,
+    // Snapshot once this many seconds have passed since the last one, 0 disables it.
+    pub snapshot_seconds: f64,
+    pub adaptive: Option<AdaptiveSettings>,
+}
+
+// AdaptiveSettings
+// The first pass gives every pixel `min_samples`. Later passes only go to pixels
+// whose relative standard error is still above `threshold`, up to `max_samples`,
+// until the image has used the budget of `samples_per_pixel` on average.
+#[derive(Debug, Clone)]
+pub struct AdaptiveSettings {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f64,
+}
+
+// RenderState
+#[derive(Clone)]
+pub struct RenderState {
+    pub accumulator: Accumulator,
+    // Finished passes, which also picks the seeds of the next one.
+    pub passes: u32,
+}
+impl RenderState {
+    pub fn new(width: usize, height: usize) -> Self {
+        RenderState {
+            accumulator: Accumulator::new(width, height),
+            passes: 0,
+        }
+    }
+}
+
+// Pass
+// How many samples each pixel gets in one pass over the image.
+pub struct Pass {
+    index: u64,
+    sample_counts: Vec<u32>,
+}
+impl Pass {
+    pub fn uniform(index: u64, width: usize, height: usize, samples: u32) -> Self {
+        Pass {
+            index,
+            sample_counts: vec![samples; width * height],
+        }
+    }
+    pub fn adaptive(
+        index: u64,
+        accumulator: &Accumulator,
+        samples: u32,
+        adaptive: &AdaptiveSettings,
+    ) -> Self {
+        let mut sample_counts = vec![0; accumulator.width() * accumulator.height()];
+        for y in 0..accumulator.height() {
+            for x in 0..accumulator.width() {
+                let taken = accumulator.sample_count(x, y);
+                if taken < adaptive.max_samples
+                    && accumulator.relative_error(x, y) > adaptive.threshold
+                {
+                    sample_counts[y * accumulator.width() + x] =
+                        samples.min(adaptive.max_samples - taken);
+                }
+            }
+        }
+        Pass {
+            index,
+            sample_counts,
+        }
+    }
+    pub fn total_samples(&self) -> u64 {
+        self.sample_counts.iter().map(|n| *n as u64).sum()
+    }
+}
+
+// Accumulator
+// Per-pixel sums of the samples taken in each pixel, plus the sum of squared
+// luminance so that the variance of every pixel can be estimated while the
+// render runs. The image itself comes from the film, the filter weighted sums
+// of all samples close enough to each pixel, plus the light film, the sums of
+// the light tracing paths that landed in each pixel.
+#[derive(Clone)]
+pub struct Accumulator {
+    width: usize,
+    height: usize,
+    sums: Vec<Color>,
+    square_sums: Vec<f64>,
+    sample_counts: Vec<u32>,
+    film: Vec<Color>,
+    film_weights: Vec<f64>,
+    light_film: Vec<Color>,
+}
+impl Accumulator {
+    pub fn new(width: usize, height: usize) -> Self {
+        Accumulator {
+            width,
+            height,
+            sums: vec![Color::BLACK; width * height],
+            square_sums: vec![0.0; width * height],
+            sample_counts: vec![0; width * height],
+            film: vec![Color::BLACK; width * height],
+            film_weights: vec![0.0; width * height],
+            light_film: vec![Color::BLACK; width * height],
+        }
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn from_raw(
+        width: usize,
+        height: usize,
+        sums: Vec<Color>,
+        square_sums: Vec<f64>,
+        sample_counts: Vec<u32>,
+        film: Vec<Color>,
+        film_weights: Vec<f64>,
+    ) -> Self {
+        assert_eq!(sums.len(), width * height);
+        assert_eq!(square_sums.len(), width * height);
+        assert_eq!(sample_counts.len(), width * height);
+        assert_eq!(film.len(), width * height);
+        assert_eq!(film_weights.len(), width * height);
+        Accumulator {
+            width,
+            height,
+            sums,
+            square_sums,
+            sample_counts,
+            film,
+            film_weights,
+            light_film: vec![Color::BLACK; width * height],
+        }
+    }
+    pub fn with_light_film(self, light_film: Vec<Color>) -> Self {
+        assert_eq!(light_film.len(), self.width * self.height);
+        Accumulator { light_film, ..self }
+    }
+    pub fn sums(&self) -> &[Color] {
+        &self.sums
+    }
+    pub fn square_sums(&self) -> &[f64] {
+        &self.square_sums
+    }
+    pub fn sample_counts(&self) -> &[u32] {
+        &self.sample_counts
+    }
+    pub fn film(&self) -> &[Color] {
+        &self.film
+    }
+    pub fn film_weights(&self) -> &[f64] {
+        &self.film_weights
+    }
+    pub fn light_film(&self) -> &[Color] {
+        &self.light_film
+    }
+    pub fn add(&mut self, x: usize, y: usize, sum: Color, square_sum: f64, samples: u32) {
+        let index = y * self.width + x;
+        self.sums[index] = self.sums[index] + sum;
+        self.square_sums[index] += square_sum;
+        self.sample_counts[index] += samples;
+    }
+    pub fn splat(&mut self, film_tile: &FilmTile) {
+        for (n, (sum, weight)) in film_tile.sums.iter().zip(&film_tile.weights).enumerate() {
+            let (x, y) = (
+                film_tile.x0 + n % film_tile.width,
+                film_tile.y0 + n / film_tile.width,
+            );
+            let index = y * self.width + x;
+            self.film[index] = self.film[index] + *sum;
+            self.film_weights[index] += weight;
+        }
+    }
+    pub fn add_light_film(&mut self, light_film: &[Color]) {
+        for (sum, color) in self.light_film.iter_mut().zip(light_film) {
+            *sum = *sum + *color;
+        }
+    }
+    pub fn sum(&self, x: usize, y: usize) -> &Color {
+        &self.sums[y * self.width + x]
+    }
+    pub fn sample_count(&self, x: usize, y: usize) -> u32 {
+        self.sample_counts[y * self.width + x]
+    }
+    pub fn total_samples(&self) -> u64 {
+        self.sample_counts.iter().map(|n| *n as u64).sum()
+    }
+    pub fn average_samples(&self) -> f64 {
+        self.total_samples() as f64 / (self.width * self.height) as f64
+    }
+    pub fn mean(&self, x: usize, y: usize) -> Color {
+        match self.sample_count(x, y) {
+            0 => Color::BLACK,
+            n => *self.sum(x, y) / n as f64,
+        }
+    }
+    // Unbiased sample variance of the pixel luminance.
+    pub fn variance(&self, x: usize, y: usize) -> f64 {
+        let n = self.sample_count(x, y) as f64;
+        if n < 2.0 {
+            return INIFINITY;
+        }
+        let index = y * self.width + x;
+        let mean = luminance(&self.sums[index]) / n;
+        ((self.square_sums[index] / n - mean * mean) * n / (n - 1.0)).max(0.0)
+    }
+    // Standard error of the mean luminance relative to the luminance itself. The
+    // small offset keeps near-black pixels from demanding endless samples.
+    pub fn relative_error(&self, x: usize, y: usize) -> f64 {
+        let n = self.sample_count(x, y) as f64;
+        let standard_error = (self.variance(x, y) / n).sqrt();
+        standard_error / (luminance(&self.mean(x, y)) + 0.01)
+    }
+    // Filtered value of a pixel. Filters with negative lobes can leave a pixel
+    // with no usable weight, that one falls back to its own samples.
+    pub fn filtered(&self, x: usize, y: usize) -> Color {
+        let index = y * self.width + x;
+        if self.film_weights[index] > 1e-8 {
+            self.film[index] / self.film_weights[index]
+        } else {
+            self.mean(x, y)
+        }
+    }
+    pub fn to_framebuffer(&self) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(self.width, self.height);
+        // Every camera sample traced one light path, which could have landed
+        // anywhere on the film.
+        let light_paths = self.total_samples().max(1) as f64;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let light = self.light_film[y * self.width + x] / light_paths;
+                framebuffer.set(x, y, self.filtered(x, y) + light);
+            }
+        }
+        framebuffer
+    }
+    // False colour image of the samples taken per pixel, from blue for the
+    // fewest samples through green to red for the most.
+    pub fn sample_heatmap(&self) -> Framebuffer {
+        let min = *self.sample_counts.iter().min().unwrap_or(&0) as f64;
+        let max = *self.sample_counts.iter().max().unwrap_or(&0) as f64;
+        let mut framebuffer = Framebuffer::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let t = if max > min {
+                    (self.sample_count(x, y) as f64 - min) / (max - min)
+                } else {
+                    0.0
+                };
+                let color = if t < 0.5 {
+                    Color::BLUE * (1.0 - 2.0 * t) + Color::GREEN * (2.0 * t)
+                } else {
+                    Color::GREEN * (2.0 - 2.0 * t) + Color::RED * (2.0 * t - 1.0)
+                };
+                framebuffer.set(x, y, color);
+            }
+        }
+        framebuffer
+    }
+}
+
+// Per-pixel result of one tile: sum of samples, sum of squared luminance and
+// the number of samples.
+type PixelSamples = (Color, f64, u32);
+// Radiance of a light tracing path and the index of the pixel it landed in.
+type LightSplat = (usize, Color);
+
+// FilmTile
+// Filter weighted samples of one tile. It reaches past the tile by as many
+// pixels as the filter does, so neighbouring tiles overlap.
+pub struct FilmTile {
+    x0: usize,
+    y0: usize,
+    width: usize,
+    height: usize,
+    sums: Vec<Color>,
+    weights: Vec<f64>,
+}
+impl FilmTile {
+    pub fn new(tile: &Tile, settings: &RenderSettings) -> Self {
+        let reach = settings.filter.pixel_reach();
+        let x0 = tile.x0.saturating_sub(reach);
+        let y0 = tile.y0.saturating_sub(reach);
+        let width = (tile.x1 + reach).min(settings.width) - x0;
+        let height = (tile.y1 + reach).min(settings.height) - y0;
+        FilmTile {
+            x0,
+            y0,
+            width,
+            height,
+            sums: vec![Color::BLACK; width * height],
+            weights: vec![0.0; width * height],
+        }
+    }
+    // Adds a sample at film position (`x`, `y`), in pixels from the top left
+    // corner, to every pixel the filter reaches.
+    pub fn add_sample(&mut self, x: f64, y: f64, color: &Color, filter: &Filter) {
+        let radius = filter.radius();
+        let first_x = ((x - 0.5 - radius).ceil().max(self.x0 as f64)) as usize;
+        let first_y = ((y - 0.5 - radius).ceil().max(self.y0 as f64)) as usize;
+        let last_x = ((x - 0.5 + radius).floor() as isize).min((self.x0 + self.width) as isize - 1);
+        let last_y =
+            ((y - 0.5 + radius).floor() as isize).min((self.y0 + self.height) as isize - 1);
+        for py in first_y as isize..=last_y {
+            for px in first_x as isize..=last_x {
+                let weight = filter.weight(px as f64 + 0.5 - x, py as f64 + 0.5 - y);
+                if weight == 0.0 {
+                    continue;
+                }
+                let index = (py as usize - self.y0) * self.width + (px as usize - self.x0);
+                self.sums[index] = self.sums[index] + *color * weight;
+                self.weights[index] += weight;
+            }
+        }
+    }
+}
+
+fn render_tile(
+    tile: &Tile,
+    (world, camera, background): (&HittableList, &Camera, &Color),
+    settings: &RenderSettings,
+    pass: &Pass,
+    taken: &[u32],
+    photon_map: Option<&PhotonMap>,
+) -> (Vec<PixelSamples>, FilmTile, Vec<LightSplat>) {
+    let mut pixels = Vec::with_capacity(tile.width() * tile.height());
+    let mut film_tile = FilmTile::new(tile, settings);
+    let mut splats = Vec::new();
+    for (i, y) in tile.pixels() {
+        // Framebuffer rows run top to bottom while v runs bottom to top.
+        let j = settings.height - 1 - y;
+        let index = y * settings.width + i;
+        let samples = pass.sample_counts[index];
+        let mut sampler = Sampler::new(
+            settings.sampler,
+            settings.seed,
+            pass.index,
+            (i, y),
+            settings.width,
+        );
+        let mut pixel_color = Color::BLACK;
+        let mut square_sum = 0.0;
+        for s in 0..samples {
+            // Sequences carry on from the samples of earlier passes.
+            sampler.start_sample(taken[index] as u64 + s as u64, s, samples);
+            let (du, dv) = sampler.get_2d();
+            let u = (i as f64 + du) / (settings.width - 1) as f64;
+            let v = (j as f64 + dv) / (settings.height - 1) as f64;
+            let ray = camera.get_ray(u, v, &mut sampler);
+            let color = match settings.integrator {
+                Integrator::Path => ray_color(
+                    &ray,
+                    background,
+                    world,
+                    settings.max_depth,
+                    settings.mis_heuristic,
+                    &mut sampler,
+                ),
+                Integrator::Bdpt => bdpt_color(
+                    &ray,
+                    background,
+                    world,
+                    camera,
+                    settings,
+                    &mut sampler,
+                    &mut splats,
+                ),
+                Integrator::Photon(_) | Integrator::Sppm(_) => photon_color(
+                    &ray,
+                    background,
+                    world,
+                    photon_map.unwrap(),
+                    settings,
+                    &mut sampler,
+                ),
+            };
+            pixel_color = pixel_color + color;
+            square_sum += luminance(&color) * luminance(&color);
+            film_tile.add_sample(i as f64 + du, y as f64 + 1.0 - dv, &color, &settings.filter);
+        }
+        pixels.push((pixel_color, square_sum, samples));
+    }
+    (pixels, film_tile, splats)
+}
+
+// Asks running passes to stop handing out tiles, e.g. from a Ctrl-C handler.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Hands the tiles of the image out to the worker threads and passes what
+// `render_tile` makes of each to `consume`, in image order so that overlapping
+// results always add up the same way. `progress` is called with the number of
+// finished tiles and the tile count. Returns false if the render was
+// interrupted, after some of the tiles may have been consumed.
+pub fn render_tiles_in_order<T, R, F, C>(
+    settings: &RenderSettings,
+    render_tile: R,
+    mut progress: F,
+    mut consume: C,
+) -> bool
+where
+    T: Send,
+    R: Fn(&Tile) -> T + Sync,
+    F: FnMut(usize, usize),
+    C: FnMut(Tile, T),
+{
+    let tiles = generate_tiles(settings.width, settings.height, settings.tile_size);
+    let next_tile = AtomicUsize::new(0);
+    let mut consumed = 0;
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..settings.worker_count() {
+            let sender = sender.clone();
+            let tiles = &tiles;
+            let next_tile = &next_tile;
+            let render_tile = &render_tile;
+            scope.spawn(move || loop {
+                let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                if index >= tiles.len() || is_interrupted() {
+                    stats::flush();
+                    break;
+                }
+                sender.send((index, render_tile(&tiles[index]))).unwrap();
+            });
+        }
+        drop(sender);
+        // Tiles finish in any order, the ones that are early wait here.
+        let mut waiting = BTreeMap::new();
+        for (finished, (index, result)) in receiver.iter().enumerate() {
+            progress(finished + 1, tiles.len());
+            waiting.insert(index, result);
+            while let Some(result) = waiting.remove(&consumed) {
+                consume(tiles[consumed], result);
+                consumed += 1;
+            }
+        }
+    });
+    consumed == tiles.len()
+}
+
+// Same as `render_tiles_in_order`, but collects the results. Returns None if the
+// render was interrupted.
+pub fn render_tiles<T, R, F>(
+    settings: &RenderSettings,
+    render_tile: R,
+    progress: F,
+) -> Option<Vec<(Tile, T)>>
+where
+    T: Send,
+    R: Fn(&Tile) -> T + Sync,
+    F: FnMut(usize, usize),
+{
+    let mut results = Vec::new();
+    let finished = render_tiles_in_order(settings, render_tile, progress, |tile, result| {
+        results.push((tile, result))
+    });
+    if finished {
+        Some(results)
+    } else {
+        None
+    }
+}
+
+// Adds the samples asked for by `pass` to the accumulator, calling `progress`
+// with the number of finished tiles and the tile count. Returns false and leaves
+// the accumulator untouched if the pass was interrupted.
+pub fn render_pass<F: FnMut(usize, usize)>(
+    world: &HittableList,
+    camera: &Camera,
+    background: &Color,
+    settings: &RenderSettings,
+    pass: &Pass,
+    accumulator: &mut Accumulator,
+    progress: F,
+) -> bool {
+    let taken = accumulator.sample_counts();
+    let photon_map = match settings.integrator {
+        Integrator::Photon(_) | Integrator::Sppm(_) => Some(trace_photons(
+            world, camera, background, settings, pass.index,
+        )),
+        _ => None,
+    };
+    let scene = (world, camera, background);
+    let render_tile =
+        |tile: &Tile| render_tile(tile, scene, settings, pass, taken, photon_map.as_ref());
+    // Light tracing paths of a tile land all over the image, they are summed up
+    // as the tiles come in rather than kept around per tile.
+    let mut results = Vec::new();
+    let mut light_film = Vec::new();
+    let finished = render_tiles_in_order(
+        settings,
+        render_tile,
+        progress,
+        |tile, (pixels, film_tile, splats)| {
+            if !splats.is_empty() && light_film.is_empty() {
+                light_film = vec![Color::BLACK; settings.width * settings.height];
+            }
+            for (index, color) in splats {
+                light_film[index] = light_film[index] + color;
+            }
+            results.push((tile, pixels, film_tile));
+        },
+    );
+    // A partial pass would leave pixels with uneven sample counts, drop it.
+    if !finished {
+        return false;
+    }
+    for (tile, pixels, film_tile) in results {
+        for ((x, y), (sum, square_sum, samples)) in tile.pixels().zip(pixels) {
+            accumulator.add(x, y, sum, square_sum, samples);
+        }
+        accumulator.splat(&film_tile);
+    }
+    accumulator.add_light_film(&light_film);
+    true
+}
+
+pub fn render(
+    world: &HittableList,
+    camera: &Camera,
+    background: &Color,
+    settings: &RenderSettings,
+) -> Framebuffer {
+    let mut accumulator = Accumulator::new(settings.width, settings.height);
+    let pass = Pass::uniform(
+        0,
+        settings.width,
+        settings.height,
+        settings.samples_per_pixel as u32,
+    );
+    render_pass(
+        world,
+        camera,
+        background,
+        settings,
+        &pass,
+        &mut accumulator,
+        // One line that keeps being overwritten, ended once every tile is in.
+        |finished, total| {
+            eprint!("\rTile {} / {}", finished, total);
+            if finished == total {
+                eprintln!();
+            }
+        },
+    );
+    accumulator.to_framebuffer()
+}
+
+// Keeps rendering passes into `state` until it holds `settings.samples_per_pixel`
+// samples per pixel on average, handing it to `snapshot` whenever one is due.
+// Returns false if the render was interrupted before that.
+pub fn render_progressive<F: FnMut(&RenderState)>(
+    world: &HittableList,
+    camera: &Camera,
+    background: &Color,
+    settings: &RenderSettings,
+    progressive: &ProgressiveSettings,
+    state: &mut RenderState,
+    mut snapshot: F,
+) -> bool {
+    let samples_per_pass = progressive.samples_per_pass.max(1) as u32;
+    let pixel_count = (settings.width * settings.height) as u64;
+    let budget = settings.samples_per_pixel.max(0) as u64 * pixel_count;
+    let mut last_snapshot = Instant::now();
+    while state.accumulator.total_samples() < budget {
+        let index = state.passes as u64;
+        let pass = match &progressive.adaptive {
+            Some(adaptive) if state.passes > 0 => {
+                Pass::adaptive(index, &state.accumulator, samples_per_pass, adaptive)
+            }
+            Some(adaptive) => {
+                Pass::uniform(index, settings.width, settings.height, adaptive.min_samples)
+            }
+            None => {
+                let remaining = (budget - state.accumulator.total_samples()) / pixel_count;
+                let samples = samples_per_pass.min(remaining.max(1) as u32);
+                Pass::uniform(index, settings.width, settings.height, samples)
+            }
+        };
+        if pass.total_samples() == 0 {
+            // Every pixel has converged or reached the sample limit.
+            break;
+        }
+        if !render_pass(
+            world,
+            camera,
+            background,
+            settings,
+            &pass,
+            &mut state.accumulator,
+            |_, _| {},
+        ) {
+            return false;
+        }
+        state.passes += 1;
+        eprintln!(
+            "Pass {} ({:.1} / {} spp)",
+            state.passes,
+            state.accumulator.average_samples(),
+            settings.samples_per_pixel
+        );
+        if state.accumulator.total_samples() >= budget {
+            break;
+        }
+        let due_by_passes = progressive.snapshot_passes > 0
+            && state.passes.is_multiple_of(progressive.snapshot_passes);
+        let due_by_time = progressive.snapshot_seconds > 0.0
+            && last_snapshot.elapsed().as_secs_f64() >= progressive.snapshot_seconds;
+        if due_by_passes || due_by_time {
+            snapshot(state);
+            last_snapshot = Instant::now();
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::*;
+
+    // Average radiance reflected by a diffuse floor lit by `light`. A dim light
+    // far away is always in the list of lights, so every floor hit takes a
+    // shadow ray.
+    fn floor_radiance(light: Hittable) -> f64 {
+        let mut world = HittableList::new();
+        let floor = Material::Lambertian(Texture::SolidColor(Color::new(0.5, 0.5, 0.5)));
+        world.add(Hittable::XZRect((-50.0, 50.0), (-50.0, 50.0), 0.0, floor));
+        let dim = Material::DiffuseLight(Texture::SolidColor(Color::new(0.01, 0.01, 0.01)));
+        world.add(Hittable::Sphere(Point3::new(40.0, 5.0, 0.0), 0.5, dim));
+        world.add(light);
+        let samples = 20_000;
+        let mut sampler = Sampler::new(SamplerKind::Independent, 7, 0, (0, 0), 1);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), -Vec3::UP, Some(0.5));
+        let mut sum = 0.0;
+        for i in 0..samples {
+            sampler.start_sample(i as u64, i, samples);
+            let color = ray_color(
+                &ray,
+                &Color::BLACK,
+                &world,
+                2,
+                MisHeuristic::Power,
+                &mut sampler,
+            );
+            sum += luminance(&color);
+        }
+        sum / samples as f64
+    }
+
+    // Emitters that lights can't be sampled on, like moving spheres, are only
+    // found by bounces, which must still count them after a shadow ray.
+    #[test]
+    fn unlisted_emitters_light_the_scene() {
+        let center = Point3::new(2.0, 2.0, 0.0);
+        let glow = || Material::DiffuseLight(Texture::SolidColor(Color::new(4.0, 4.0, 4.0)));
+        let listed = floor_radiance(Hittable::Sphere(center, 1.0, glow()));
+        let moving = Hittable::MovingSphere((center, center), 1.0, glow(), (0.0, 1.0));
+        let unlisted = floor_radiance(moving);
+        assert!(listed > 0.1);
+        assert!(
+            (unlisted - listed).abs() < 0.05 * listed,
+            "{} vs {}",
+            unlisted,
+            listed
+        );
+    }
+
+    fn render_cornell_box(
+        threads: usize,
+        seed: u64,
+        samples_per_pixel: i32,
+        samples_per_pass: i32,
+        integrator: Integrator,
+    ) -> Vec<[f64; 3]> {
+        let (world, camera, background) = crate::scene::cornell_box(1.0, &mut Rng::new(seed));
+        let settings = RenderSettings {
+            width: 24,
+            height: 24,
+            samples_per_pixel,
+            max_depth: 8,
+            tile_size: 8,
+            threads,
+            seed,
+            // Samples near tile borders land in the tiles next to them too.
+            filter: Filter::from_name("mitchell", Some(2.0)).unwrap(),
+            sampler: SamplerKind::Independent,
+            mis_heuristic: MisHeuristic::Power,
+            integrator,
+        };
+        let progressive = ProgressiveSettings {
+            samples_per_pass,
+            snapshot_passes: 0,
+            snapshot_seconds: 0.0,
+            adaptive: None,
+        };
+        let mut state = RenderState::new(settings.width, settings.height);
+        render_progressive(
+            &world,
+            &camera,
+            &background,
+            &settings,
+            &progressive,
+            &mut state,
+            |_| {},
+        );
+        state
+            .accumulator
+            .to_framebuffer()
+            .pixels()
+            .iter()
+            .map(|color| [color.x(), color.y(), color.z()])
+            .collect()
+    }
+
+    #[test]
+    fn renders_only_depend_on_the_seed() {
+        let render = |threads, seed| render_cornell_box(threads, seed, 8, 8, Integrator::Path);
+        let single = render(1, 5);
+        assert_eq!(single, render(3, 5));
+        assert_ne!(single, render(3, 6));
+    }
+
+    // Mean of each channel over the whole image.
+    fn mean_color(pixels: &[[f64; 3]]) -> [f64; 3] {
+        let mut sum = [0.0; 3];
+        for pixel in pixels {
+            for c in 0..3 {
+                sum[c] += pixel[c];
+            }
+        }
+        sum.map(|channel| channel / pixels.len() as f64)
+    }
+
+    fn assert_converges_to_path(
+        integrator: Integrator,
+        samples_per_pixel: i32,
+        samples_per_pass: i32,
+        tolerance: f64,
+    ) {
+        let name = integrator.name();
+        let reference = mean_color(&render_cornell_box(0, 3, 256, 256, Integrator::Path));
+        let pixels = render_cornell_box(0, 3, samples_per_pixel, samples_per_pass, integrator);
+        let mean = mean_color(&pixels);
+        for c in 0..3 {
+            assert!(
+                (mean[c] - reference[c]).abs() < tolerance * reference[c],
+                "{} {:?} vs path {:?}",
+                name,
+                mean,
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn bdpt_converges_to_path_tracing() {
+        assert_converges_to_path(Integrator::Bdpt, 256, 256, 0.03);
+    }
+
+    #[test]
+    fn photon_mapping_converges_to_path_tracing() {
+        let photons = PhotonSettings {
+            photons: 10_000,
+            radius: 0.0,
+        };
+        assert_converges_to_path(Integrator::Photon(photons), 64, 64, 0.05);
+        // Sixteen passes, each with a smaller gather radius.
+        assert_converges_to_path(Integrator::Sppm(photons), 64, 4, 0.05);
+    }
+}
@@ -0,0 +1,579 @@
+use crate::math::*;
+use std::sync::OnceLock;
+
+// SamplerKind
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SamplerKind {
+    // Plain uniform random numbers.
+    Independent,
+    // Jittered strata over the samples of each pass, shuffled per dimension. 2D
+    // values form a grid when the pass has a square number of samples, otherwise
+    // each axis is stratified on its own.
+    Stratified,
+    // Halton points, randomly shifted per pixel.
+    Halton,
+    // 2D Sobol points with hash based Owen scrambling (Burley 2020).
+    Sobol,
+    // Sobol points shifted by a blue-noise mask, which spreads the error across
+    // the image as blue noise instead of white noise.
+    BlueNoise,
+}
+impl SamplerKind {
+    pub const NAMES: &'static [&'static str] =
+        &["independent", "stratified", "halton", "sobol", "blue-noise"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            "blue-noise" => Some(SamplerKind::BlueNoise),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+            SamplerKind::BlueNoise => "blue-noise",
+        }
+    }
+}
+
+// Sampler
+// Hands out the sample dimensions of one pixel sample, one at a time: the camera
+// takes the first ones for the pixel position, lens and time, then each bounce
+// takes what it needs. Past the dimensions a sequence has, or when the number of
+// dimensions a path uses isn't fixed, the values are uniform random numbers.
+pub struct Sampler {
+    kind: SamplerKind,
+    seed: u64,
+    rng: Rng,
+    pixel: (usize, usize),
+    pixel_index: u64,
+    // Index of the sample among all samples of the pixel, across passes.
+    sample_index: u64,
+    // Index of the sample within its pass, and the number of samples in the pass.
+    pass_sample: u32,
+    pass_samples: u32,
+    dimension: u32,
+}
+impl Sampler {
+    // Low-discrepancy values go to this many dimensions, the rest are random.
+    const MAX_DIMENSIONS: u32 = 64;
+
+    pub fn new(
+        kind: SamplerKind,
+        seed: u64,
+        pass: u64,
+        pixel: (usize, usize),
+        width: usize,
+    ) -> Self {
+        let pixel_index = (pixel.1 * width + pixel.0) as u64;
+        Sampler {
+            kind,
+            seed,
+            rng: Rng::for_pixel(seed, pass, pixel_index),
+            pixel,
+            pixel_index,
+            sample_index: 0,
+            pass_sample: 0,
+            pass_samples: 1,
+            dimension: 0,
+        }
+    }
+    pub fn start_sample(&mut self, sample_index: u64, pass_sample: u32, pass_samples: u32) {
+        self.sample_index = sample_index;
+        self.pass_sample = pass_sample;
+        self.pass_samples = pass_samples.max(1);
+        self.dimension = 0;
+    }
+    pub fn kind(&self) -> SamplerKind {
+        self.kind
+    }
+    // Random numbers that aren't tied to a sample dimension.
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+    fn dimension_hash(&self, dimension: u32) -> u64 {
+        hash_seed(self.seed ^ hash_seed(self.pixel_index ^ ((dimension as u64) << 40)))
+    }
+    // Strata are shuffled differently in every pass.
+    fn stratum_hash(&self, dimension: u32) -> u32 {
+        let pass_start = self.sample_index - self.pass_sample as u64;
+        (self.dimension_hash(dimension) ^ hash_seed(pass_start)) as u32
+    }
+
+    pub fn get_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        if dimension >= Sampler::MAX_DIMENSIONS {
+            return self.rng.random_double();
+        }
+        match self.kind {
+            SamplerKind::Independent => self.rng.random_double(),
+            SamplerKind::Stratified => {
+                let hash = self.stratum_hash(dimension);
+                let stratum = permute(self.pass_sample, self.pass_samples, hash);
+                (stratum as f64 + self.rng.random_double()) / self.pass_samples as f64
+            }
+            SamplerKind::Halton => {
+                let shift = to_unit(self.dimension_hash(dimension) as u32);
+                fract(radical_inverse(PRIMES[dimension as usize], self.sample_index) + shift)
+            }
+            SamplerKind::Sobol => {
+                let hash = self.dimension_hash(dimension) as u32;
+                sobol_owen_1d(self.sample_index as u32, hash)
+            }
+            SamplerKind::BlueNoise => {
+                let hash = hash_seed(self.seed ^ dimension as u64) as u32;
+                let shift = blue_noise_shift(self.pixel, dimension);
+                fract(sobol_owen_1d(self.sample_index as u32, hash) + shift)
+            }
+        }
+    }
+
+    pub fn get_2d(&mut self) -> (f64, f64) {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        if dimension + 1 >= Sampler::MAX_DIMENSIONS {
+            return (self.rng.random_double(), self.rng.random_double());
+        }
+        match self.kind {
+            SamplerKind::Independent => (self.rng.random_double(), self.rng.random_double()),
+            SamplerKind::Stratified => {
+                let hash = self.stratum_hash(dimension);
+                let columns = (self.pass_samples as f64).sqrt().round() as u32;
+                if columns * columns == self.pass_samples {
+                    let cell = permute(self.pass_sample, self.pass_samples, hash);
+                    (
+                        ((cell % columns) as f64 + self.rng.random_double()) / columns as f64,
+                        ((cell / columns) as f64 + self.rng.random_double()) / columns as f64,
+                    )
+                } else {
+                    // A grid would leave cells empty, stratify each axis on its
+                    // own instead, with its own shuffle.
+                    let count = self.pass_samples as f64;
+                    let x = permute(self.pass_sample, self.pass_samples, hash);
+                    let y = permute(self.pass_sample, self.pass_samples, hash_u32(hash, 2));
+                    (
+                        (x as f64 + self.rng.random_double()) / count,
+                        (y as f64 + self.rng.random_double()) / count,
+                    )
+                }
+            }
+            SamplerKind::Halton => {
+                let hash = self.dimension_hash(dimension);
+                let shift = (to_unit(hash as u32), to_unit((hash >> 32) as u32));
+                (
+                    fract(radical_inverse(PRIMES[dimension as usize], self.sample_index) + shift.0),
+                    fract(
+                        radical_inverse(PRIMES[dimension as usize + 1], self.sample_index)
+                            + shift.1,
+                    ),
+                )
+            }
+            SamplerKind::Sobol => {
+                let hash = self.dimension_hash(dimension) as u32;
+                sobol_owen_2d(self.sample_index as u32, hash)
+            }
+            SamplerKind::BlueNoise => {
+                let hash = hash_seed(self.seed ^ dimension as u64) as u32;
+                let (x, y) = sobol_owen_2d(self.sample_index as u32, hash);
+                (
+                    fract(x + blue_noise_shift(self.pixel, dimension)),
+                    fract(y + blue_noise_shift(self.pixel, dimension + 1)),
+                )
+            }
+        }
+    }
+}
+
+// Helper functions
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+fn to_unit(bits: u32) -> f64 {
+    bits as f64 / 4294967296.0
+}
+fn fract(x: f64) -> f64 {
+    // Stays below 1 even when rounding would land on it.
+    (x - x.floor()).min(1.0 - f64::EPSILON)
+}
+fn radical_inverse(base: u64, mut index: u64) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut factor = inverse_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base) as f64 * factor;
+        index /= base;
+        factor *= inverse_base;
+    }
+    result
+}
+// Kensler's hashed permutation: a bijection of [0, length) picked by `seed`.
+fn permute(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            return i.wrapping_add(seed) % length;
+        }
+    }
+}
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+fn sobol_first(index: u32) -> u32 {
+    index.reverse_bits()
+}
+fn sobol_second(mut index: u32) -> u32 {
+    // The generator matrix of the second Sobol dimension, built column by column.
+    let mut result = 0;
+    let mut direction = 1u32 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    result
+}
+fn sobol_owen_1d(index: u32, seed: u32) -> f64 {
+    let index = nested_uniform_scramble(index, seed);
+    to_unit(nested_uniform_scramble(
+        sobol_first(index),
+        hash_u32(seed, 1),
+    ))
+}
+fn sobol_owen_2d(index: u32, seed: u32) -> (f64, f64) {
+    let index = nested_uniform_scramble(index, seed);
+    (
+        to_unit(nested_uniform_scramble(
+            sobol_first(index),
+            hash_u32(seed, 1),
+        )),
+        to_unit(nested_uniform_scramble(
+            sobol_second(index),
+            hash_u32(seed, 2),
+        )),
+    )
+}
+fn hash_u32(seed: u32, salt: u64) -> u32 {
+    hash_seed(((seed as u64) << 8) | salt) as u32
+}
+
+// Blue-noise mask
+const MASK_SIZE: usize = 64;
+
+fn blue_noise_shift(pixel: (usize, usize), dimension: u32) -> f64 {
+    // Every dimension reads the mask at a different offset, so the shifts of
+    // different dimensions don't line up.
+    let offset = hash_seed(dimension as u64);
+    let x = (pixel.0 + (offset as usize % MASK_SIZE)) % MASK_SIZE;
+    let y = (pixel.1 + ((offset >> 32) as usize % MASK_SIZE)) % MASK_SIZE;
+    blue_noise_mask()[y * MASK_SIZE + x]
+}
+
+// A tileable blue-noise mask made with Ulichney's void-and-cluster method, with
+// every value in [0,1) used once.
+pub fn blue_noise_mask() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let ranks = void_and_cluster(MASK_SIZE);
+        let count = (MASK_SIZE * MASK_SIZE) as f64;
+        ranks
+            .iter()
+            .map(|rank| (*rank as f64 + 0.5) / count)
+            .collect()
+    })
+}
+
+fn void_and_cluster(size: usize) -> Vec<usize> {
+    const SIGMA: f64 = 1.5;
+    let count = size * size;
+    // Gaussian falloff over toroidal distances, shared by every point.
+    let mut falloff = vec![0.0; count];
+    for dy in 0..size {
+        for dx in 0..size {
+            let x = dx.min(size - dx) as f64;
+            let y = dy.min(size - dy) as f64;
+            falloff[dy * size + dx] = (-(x * x + y * y) / (2.0 * SIGMA * SIGMA)).exp();
+        }
+    }
+    let mut energy = vec![0.0; count];
+    let mut set = vec![false; count];
+    let update = |energy: &mut Vec<f64>, point: usize, sign: f64| {
+        let (px, py) = (point % size, point / size);
+        for y in 0..size {
+            for x in 0..size {
+                let dx = (x + size - px) % size;
+                let dy = (y + size - py) % size;
+                energy[y * size + x] += sign * falloff[dy * size + dx];
+            }
+        }
+    };
+    let tightest_cluster = |energy: &[f64], set: &[bool]| -> usize {
+        (0..count)
+            .filter(|i| set[*i])
+            .max_by(|a, b| energy[*a].partial_cmp(&energy[*b]).unwrap())
+            .unwrap()
+    };
+    let largest_void = |energy: &[f64], set: &[bool]| -> usize {
+        (0..count)
+            .filter(|i| !set[*i])
+            .min_by(|a, b| energy[*a].partial_cmp(&energy[*b]).unwrap())
+            .unwrap()
+    };
+
+    // Initial pattern: a tenth of the pixels at random, then move the point in
+    // the tightest cluster to the largest void until that changes nothing.
+    let mut rng = Rng::new(0);
+    let initial = count / 10;
+    let mut placed = 0;
+    while placed < initial {
+        let point = rng.next_u32() as usize % count;
+        if !set[point] {
+            set[point] = true;
+            update(&mut energy, point, 1.0);
+            placed += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&energy, &set);
+        set[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&energy, &set);
+        set[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; count];
+    // Rank the initial points from the last to the first, taking out the
+    // tightest cluster each time.
+    let mut removed_set = set.clone();
+    let mut removed_energy = energy.clone();
+    for rank in (0..initial).rev() {
+        let cluster = tightest_cluster(&removed_energy, &removed_set);
+        removed_set[cluster] = false;
+        update(&mut removed_energy, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+    // Fill the largest voids for the rest.
+    for rank in initial..count {
+        let void = largest_void(&energy, &set);
+        set[void] = true;
+        update(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+    ranks
+}
+
+// Sample warping
+// Uniform point on the unit disk, using Shirley's concentric mapping.
+pub fn sample_unit_disk((u, v): (f64, f64)) -> Vec3 {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return Vec3::default();
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+// Uniform direction.
+pub fn sample_unit_vector((u, v): (f64, f64)) -> Vec3 {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+// Direction in the hemisphere around +z with a density proportional to the
+// cosine to +z, by projecting a point on the unit disk up onto it.
+pub fn sample_cosine_hemisphere(sample: (f64, f64)) -> Vec3 {
+    let d = sample_unit_disk(sample);
+    let z = (1.0 - d.x() * d.x() - d.y() * d.y()).max(0.0).sqrt();
+    Vec3::new(d.x(), d.y(), z)
+}
+// Uniform direction inside the cone around +z whose half-angle has the cosine
+// `cos_theta_max`.
+pub fn sample_cone((u, v): (f64, f64), cos_theta_max: f64) -> Vec3 {
+    let z = 1.0 - u * (1.0 - cos_theta_max);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+// Uniform point inside the unit sphere.
+pub fn sample_unit_ball(direction: (f64, f64), radius: f64) -> Vec3 {
+    sample_unit_vector(direction) * radius.cbrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+
+    // The 2D values of the first dimension pair for every sample of one pass.
+    fn points_2d(kind: SamplerKind, pixel: (usize, usize), count: u32) -> Vec<(f64, f64)> {
+        let mut sampler = Sampler::new(kind, 7, 0, pixel, 16);
+        (0..count)
+            .map(|i| {
+                sampler.start_sample(i as u64, i, count);
+                sampler.get_2d()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn values_are_in_the_unit_interval() {
+        for &kind in KINDS.iter() {
+            for pixel in [(0, 0), (5, 3), (15, 15)].iter() {
+                let mut sampler = Sampler::new(kind, 3, 1, *pixel, 16);
+                for i in 0..64 {
+                    sampler.start_sample(64 + i, i as u32, 64);
+                    // Runs past the low-discrepancy dimensions into random ones.
+                    for _ in 0..40 {
+                        let value = sampler.get_1d();
+                        assert!((0.0..1.0).contains(&value), "{:?} gave {}", kind, value);
+                        let (u, v) = sampler.get_2d();
+                        assert!((0.0..1.0).contains(&u), "{:?} gave {}", kind, u);
+                        assert!((0.0..1.0).contains(&v), "{:?} gave {}", kind, v);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn samplers_only_depend_on_their_arguments() {
+        for &kind in KINDS.iter() {
+            let mut a = Sampler::new(kind, 11, 2, (4, 9), 16);
+            let mut b = Sampler::new(kind, 11, 2, (4, 9), 16);
+            for i in 0..16 {
+                a.start_sample(i, i as u32, 16);
+                b.start_sample(i, i as u32, 16);
+                for _ in 0..40 {
+                    assert_eq!(a.get_1d(), b.get_1d(), "{:?}", kind);
+                    assert_eq!(a.get_2d(), b.get_2d(), "{:?}", kind);
+                }
+            }
+            let mut other_pixel = Sampler::new(kind, 11, 2, (5, 9), 16);
+            other_pixel.start_sample(0, 0, 16);
+            a.start_sample(0, 0, 16);
+            assert_ne!(a.get_2d(), other_pixel.get_2d(), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn stratified_points_fill_every_stratum() {
+        // A square count fills every cell of the grid once.
+        let points = points_2d(SamplerKind::Stratified, (2, 1), 16);
+        let mut cells = [0; 16];
+        for (u, v) in points {
+            cells[(v * 4.0) as usize * 4 + (u * 4.0) as usize] += 1;
+        }
+        assert_eq!(cells, [1; 16]);
+
+        // Any other count fills every stratum of each axis once.
+        for &count in [2, 8, 12].iter() {
+            let points = points_2d(SamplerKind::Stratified, (2, 1), count);
+            let mut columns = vec![0; count as usize];
+            let mut rows = vec![0; count as usize];
+            for (u, v) in points {
+                columns[(u * count as f64) as usize] += 1;
+                rows[(v * count as f64) as usize] += 1;
+            }
+            assert_eq!(columns, vec![1; count as usize]);
+            assert_eq!(rows, vec![1; count as usize]);
+        }
+    }
+
+    #[test]
+    fn sobol_points_fill_every_elementary_interval() {
+        for &kind in [SamplerKind::Sobol, SamplerKind::BlueNoise].iter() {
+            for log_count in 0..=8 {
+                let count = 1u32 << log_count;
+                let points = points_2d(kind, (3, 6), count);
+                // Every split of the square into `count` equal boxes gets one
+                // point per box. The blue-noise shift moves the boxes along,
+                // wrapping around the square.
+                let shift = match kind {
+                    SamplerKind::BlueNoise => {
+                        (blue_noise_shift((3, 6), 0), blue_noise_shift((3, 6), 1))
+                    }
+                    _ => (0.0, 0.0),
+                };
+                for log_columns in 0..=log_count {
+                    let columns = 1 << log_columns;
+                    let rows = count as usize / columns;
+                    let mut boxes = vec![0; count as usize];
+                    for (u, v) in points.iter() {
+                        let u = fract(u - shift.0 + 1.0);
+                        let v = fract(v - shift.1 + 1.0);
+                        let column = ((u * columns as f64) as usize).min(columns - 1);
+                        let row = ((v * rows as f64) as usize).min(rows - 1);
+                        boxes[row * columns + column] += 1;
+                    }
+                    assert_eq!(
+                        boxes,
+                        vec![1; count as usize],
+                        "{:?} with {} points in {} columns",
+                        kind,
+                        count,
+                        columns
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,66 @@
-use crate::{aabb::*, camera::*, geometry::*, material::*, math::*, noise::*, texture::*};
+use crate::{
+    aabb::*, camera::*, geometry::*, gltf_import::*, material::*, math::*, noise::*, texture::*,
+};
+use std::io;
 use std::sync::Arc;
 
-pub fn ballz() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub const SCENES: &[&str] = &[
+    "ballz",
+    "two_spheres",
+    "two_perlin_spheres",
+    "earth",
+    "simple_light",
+    "cornell_box",
+    "cornell_smoke",
+    "final_scene",
+];
+
+pub fn from_name(
+    name: &str,
+    aspect_ratio: f64,
+    rng: &mut Rng,
+) -> Option<(Arc<HittableList>, Arc<Camera>, Color)> {
+    match name {
+        "ballz" => Some(ballz(aspect_ratio, rng)),
+        "two_spheres" => Some(two_spheres(aspect_ratio, rng)),
+        "two_perlin_spheres" => Some(two_perlin_spheres(aspect_ratio, rng)),
+        "earth" => Some(earth(aspect_ratio, rng)),
+        "simple_light" => Some(simple_light(aspect_ratio, rng)),
+        "cornell_box" => Some(cornell_box(aspect_ratio, rng)),
+        "cornell_smoke" => Some(cornell_smoke(aspect_ratio, rng)),
+        "final_scene" => Some(final_scene(aspect_ratio, rng)),
+        _ => None,
+    }
+}
+// Names that `from_file` loads instead of one of the scenes here.
+pub fn is_scene_file(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".gltf") || name.ends_with(".glb")
+}
+// A glTF scene, under the sky unless it has lights of its own.
+pub fn from_file(
+    path: &str,
+    aspect_ratio: f64,
+    rng: &mut Rng,
+) -> io::Result<(Arc<HittableList>, Arc<Camera>, Color)> {
+    let (objects, camera) = load_gltf(path, aspect_ratio)?;
+    if objects.bounding_box(0.0, 1.0).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: Nothing to render", path),
+        ));
+    }
+    let background = if objects.lights().is_empty() {
+        Color::new(0.7, 0.8, 1.0)
+    } else {
+        Color::BLACK
+    };
+    let mut world = HittableList::new();
+    world.add(objects.to_bhv(0.0, 1.0, rng));
+    Ok((Arc::new(world), Arc::new(camera), background))
+}
+
+pub fn ballz(aspect_ratio: f64, rng: &mut Rng) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut objects = HittableList::new();
 
@@ -16,18 +75,18 @@ pub fn ballz() -> (Arc<HittableList>, Arc<Camera>, Color) {
 
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat = random_double();
+            let choose_mat = rng.random_double();
             let center = Point3::new(
-                a as f64 + 0.9 * random_double(),
+                a as f64 + 0.9 * rng.random_double(),
                 0.2,
-                b as f64 + 0.9 * random_double(),
+                b as f64 + 0.9 * rng.random_double(),
             );
             if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                 if choose_mat < 0.8 {
                     // diffuse
-                    let albedo = Color::random() * Color::random();
+                    let albedo = Color::random(rng) * Color::random(rng);
                     let sphere_material = Material::Lambertian(albedo.into());
-                    let center2 = center + Vec3::new(0.0, random_range_double(0.0, 0.5), 0.0);
+                    let center2 = center + Vec3::new(0.0, rng.random_range_double(0.0, 0.5), 0.0);
                     objects.add(Hittable::MovingSphere(
                         (center, center2),
                         0.2,
@@ -36,8 +95,8 @@ pub fn ballz() -> (Arc<HittableList>, Arc<Camera>, Color) {
                     ));
                 } else if choose_mat < 0.95 {
                     // metal
-                    let albedo = Color::random_range(0.5, 1.0);
-                    let fuzz = random_range_double(0.0, 0.5);
+                    let albedo = Color::random_range(rng, 0.5, 1.0);
+                    let fuzz = rng.random_range_double(0.0, 0.5);
                     let sphere_material = Material::Metal(albedo, fuzz);
                     objects.add(Hittable::Sphere(center, 0.2, sphere_material));
                 } else {
@@ -72,7 +131,7 @@ pub fn ballz() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         20.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
@@ -85,7 +144,7 @@ pub fn ballz() -> (Arc<HittableList>, Arc<Camera>, Color) {
     )
 }
 
-pub fn two_spheres() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub fn two_spheres(aspect_ratio: f64, _rng: &mut Rng) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut objects = HittableList::new();
 
@@ -114,7 +173,7 @@ pub fn two_spheres() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         20.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
@@ -126,11 +185,14 @@ pub fn two_spheres() -> (Arc<HittableList>, Arc<Camera>, Color) {
     )
 }
 
-pub fn two_perlin_spheres() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub fn two_perlin_spheres(
+    aspect_ratio: f64,
+    rng: &mut Rng,
+) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut objects = HittableList::new();
 
-    let perlin = Perlin::new();
+    let perlin = Perlin::new(rng);
     let checker = Material::Lambertian(Texture::Marble(perlin, 4.0));
     objects.add(Hittable::Sphere(
         Point3::new(0.0, -1000.0, 0.0),
@@ -153,7 +215,7 @@ pub fn two_perlin_spheres() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         20.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
@@ -165,7 +227,7 @@ pub fn two_perlin_spheres() -> (Arc<HittableList>, Arc<Camera>, Color) {
     )
 }
 
-pub fn earth() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub fn earth(aspect_ratio: f64, _rng: &mut Rng) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut objects = HittableList::new();
 
@@ -183,7 +245,7 @@ pub fn earth() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         20.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
@@ -195,11 +257,11 @@ pub fn earth() -> (Arc<HittableList>, Arc<Camera>, Color) {
     )
 }
 
-pub fn simple_light() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub fn simple_light(aspect_ratio: f64, rng: &mut Rng) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut objects = HittableList::new();
 
-    let perlin = Perlin::new();
+    let perlin = Perlin::new(rng);
     let checker = Material::Lambertian(Texture::Marble(perlin, 4.0));
     objects.add(Hittable::Sphere(
         Point3::new(0.0, -1000.0, 0.0),
@@ -231,7 +293,7 @@ pub fn simple_light() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         20.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
@@ -239,7 +301,7 @@ pub fn simple_light() -> (Arc<HittableList>, Arc<Camera>, Color) {
     (Arc::new(objects), Arc::new(camera), Color::BLACK)
 }
 
-pub fn cornell_box() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub fn cornell_box(aspect_ratio: f64, _rng: &mut Rng) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut objects = HittableList::new();
 
@@ -278,16 +340,14 @@ pub fn cornell_box() -> (Arc<HittableList>, Arc<Camera>, Color) {
         Point3::new(165.0, 330.0, 165.0),
         white.clone(),
     ));
-    box1 = Hittable::RotateY(Box::new(box1), 15.0);
-    box1 = Hittable::Translate(Box::new(box1), Vec3::new(265.0, 0.0, 295.0));
+    box1 = box1.rotate_y(15.0).translate(Vec3::new(265.0, 0.0, 295.0));
     objects.add(box1);
     let mut box2 = Hittable::Cube(Cuboid::new(
         Point3::new(0.0, 0.0, 0.0),
         Point3::new(165.0, 165.0, 165.0),
         white.clone(),
     ));
-    box2 = Hittable::RotateY(Box::new(box2), -18.0);
-    box2 = Hittable::Translate(Box::new(box2), Vec3::new(130.0, 0.0, 65.0));
+    box2 = box2.rotate_y(-18.0).translate(Vec3::new(130.0, 0.0, 65.0));
     objects.add(box2);
 
     // Camera
@@ -300,7 +360,7 @@ pub fn cornell_box() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         40.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
@@ -308,7 +368,7 @@ pub fn cornell_box() -> (Arc<HittableList>, Arc<Camera>, Color) {
     (Arc::new(objects), Arc::new(camera), Color::BLACK)
 }
 
-pub fn cornell_smoke() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub fn cornell_smoke(aspect_ratio: f64, _rng: &mut Rng) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut objects = HittableList::new();
 
@@ -349,8 +409,7 @@ pub fn cornell_smoke() -> (Arc<HittableList>, Arc<Camera>, Color) {
         Point3::new(165.0, 330.0, 165.0),
         white.clone(),
     ));
-    box1 = Hittable::RotateY(Box::new(box1), 15.0);
-    box1 = Hittable::Translate(Box::new(box1), Vec3::new(265.0, 0.0, 295.0));
+    box1 = box1.rotate_y(15.0).translate(Vec3::new(265.0, 0.0, 295.0));
     box1 = Hittable::ConstantMedium(Box::new(box1), 0.01, smoke);
     objects.add(box1);
     let mut box2 = Hittable::Cube(Cuboid::new(
@@ -358,8 +417,7 @@ pub fn cornell_smoke() -> (Arc<HittableList>, Arc<Camera>, Color) {
         Point3::new(165.0, 165.0, 165.0),
         white.clone(),
     ));
-    box2 = Hittable::RotateY(Box::new(box2), -18.0);
-    box2 = Hittable::Translate(Box::new(box2), Vec3::new(130.0, 0.0, 65.0));
+    box2 = box2.rotate_y(-18.0).translate(Vec3::new(130.0, 0.0, 65.0));
     box2 = Hittable::ConstantMedium(Box::new(box2), 0.01, fog);
     objects.add(box2);
 
@@ -373,7 +431,7 @@ pub fn cornell_smoke() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         40.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
@@ -381,7 +439,7 @@ pub fn cornell_smoke() -> (Arc<HittableList>, Arc<Camera>, Color) {
     (Arc::new(objects), Arc::new(camera), Color::BLACK)
 }
 
-pub fn final_scene() -> (Arc<HittableList>, Arc<Camera>, Color) {
+pub fn final_scene(aspect_ratio: f64, rng: &mut Rng) -> (Arc<HittableList>, Arc<Camera>, Color) {
     // World
     let mut boxes1 = HittableList::new();
     let ground = Material::Lambertian(Color::new(0.48, 0.83, 0.53).into());
@@ -394,7 +452,7 @@ pub fn final_scene() -> (Arc<HittableList>, Arc<Camera>, Color) {
             let z0 = -1000.0 + j as f64 * w;
             let y0 = 0.0;
             let x1 = x0 + w;
-            let y1 = random_range_double(1.0, 101.0);
+            let y1 = rng.random_range_double(1.0, 101.0);
             let z1 = z0 + w;
             boxes1.add(Hittable::Cube(Cuboid::new(
                 Point3::new(x0, y0, z0),
@@ -405,7 +463,7 @@ pub fn final_scene() -> (Arc<HittableList>, Arc<Camera>, Color) {
     }
 
     let mut objects = HittableList::new();
-    objects.add(boxes1.to_bhv(0.0, 1.0));
+    objects.add(boxes1.to_bhv(0.0, 1.0, rng));
 
     let light = Material::DiffuseLight(Color::new(7.0, 7.0, 7.0).into());
     objects.add(Hittable::XZRect(
@@ -469,7 +527,7 @@ pub fn final_scene() -> (Arc<HittableList>, Arc<Camera>, Color) {
         100.0,
         emat,
     ));
-    let pertext = Texture::Marble(Perlin::new(), 0.1);
+    let pertext = Texture::Marble(Perlin::new(rng), 0.1);
     objects.add(Hittable::Sphere(
         Point3::new(220.0, 280.0, 300.0),
         80.0,
@@ -481,15 +539,16 @@ pub fn final_scene() -> (Arc<HittableList>, Arc<Camera>, Color) {
     let ns = 1000;
     for j in 0..ns {
         boxes2.add(Hittable::Sphere(
-            Point3::random_range(0.0, 165.0),
+            Point3::random_range(rng, 0.0, 165.0),
             10.0,
             white.clone(),
         ));
     }
 
-    let mut boxes2 = boxes2.to_bhv(0.0, 1.0);
-    boxes2 = Hittable::RotateY(Box::new(boxes2), 15.0);
-    boxes2 = Hittable::Translate(Box::new(boxes2), Vec3::new(-100.0, 270.0, 395.0));
+    let mut boxes2 = boxes2.to_bhv(0.0, 1.0, rng);
+    boxes2 = boxes2
+        .rotate_y(15.0)
+        .translate(Vec3::new(-100.0, 270.0, 395.0));
     objects.add(boxes2);
 
     // Camera
@@ -502,7 +561,7 @@ pub fn final_scene() -> (Arc<HittableList>, Arc<Camera>, Color) {
         look_at,
         Vec3::UP,
         40.0,
-        crate::ASPECT_RATIO,
+        aspect_ratio,
         aperture,
         focus_distance,
         (0.0, 1.0),
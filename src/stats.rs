@@ -0,0 +1,207 @@
+use crate::geometry::*;
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+// Every thread counts into its own `Stats`, worker threads add theirs to the
+// totals with `flush` before they finish.
+thread_local! {
+    static LOCAL: RefCell<Stats> = const { RefCell::new(Stats::new()) };
+}
+static TOTALS: Mutex<Stats> = Mutex::new(Stats::new());
+
+fn kind_index(name: &str) -> usize {
+    HITTABLE_NAMES
+        .iter()
+        .position(|kind| *kind == name)
+        .unwrap()
+}
+
+// Stats
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub camera_rays: u64,
+    // Every other ray that looks for the closest hit: bounces and photons.
+    pub secondary_rays: u64,
+    pub shadow_rays: u64,
+    // `ray_cast` calls by kind of object, in `HITTABLE_NAMES` order. Triangle
+    // meshes also count the nodes of their own BVH and the triangles they test.
+    pub ray_casts: [u64; HITTABLE_NAMES.len()],
+    pub aabb_tests: u64,
+    // Camera paths by the number of surfaces and medium points they reached.
+    pub path_depths: Vec<u64>,
+    pub bvh_build_seconds: f64,
+}
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            camera_rays: 0,
+            secondary_rays: 0,
+            shadow_rays: 0,
+            ray_casts: [0; HITTABLE_NAMES.len()],
+            aabb_tests: 0,
+            path_depths: Vec::new(),
+            bvh_build_seconds: 0.0,
+        }
+    }
+    pub fn merge(&mut self, other: &Stats) {
+        self.camera_rays += other.camera_rays;
+        self.secondary_rays += other.secondary_rays;
+        self.shadow_rays += other.shadow_rays;
+        for (count, other_count) in self.ray_casts.iter_mut().zip(&other.ray_casts) {
+            *count += other_count;
+        }
+        self.aabb_tests += other.aabb_tests;
+        if self.path_depths.len() < other.path_depths.len() {
+            self.path_depths.resize(other.path_depths.len(), 0);
+        }
+        for (count, other_count) in self.path_depths.iter_mut().zip(&other.path_depths) {
+            *count += other_count;
+        }
+        self.bvh_build_seconds += other.bvh_build_seconds;
+    }
+    pub fn add_path(&mut self, depth: usize) {
+        if self.path_depths.len() <= depth {
+            self.path_depths.resize(depth + 1, 0);
+        }
+        self.path_depths[depth] += 1;
+    }
+    pub fn total_rays(&self) -> u64 {
+        self.camera_rays + self.secondary_rays + self.shadow_rays
+    }
+    pub fn bvh_nodes_visited(&self) -> u64 {
+        self.ray_casts[kind_index("bvh_node")]
+    }
+    pub fn add_mesh_traversal(&mut self, nodes: u64, triangles: u64) {
+        self.ray_casts[kind_index("bvh_node")] += nodes;
+        self.ray_casts[kind_index("triangle")] += triangles;
+    }
+    pub fn path_count(&self) -> u64 {
+        self.path_depths.iter().sum()
+    }
+    pub fn average_path_depth(&self) -> f64 {
+        let sum: u64 = self
+            .path_depths
+            .iter()
+            .enumerate()
+            .map(|(depth, count)| depth as u64 * count)
+            .sum();
+        sum as f64 / self.path_count().max(1) as f64
+    }
+    pub fn max_path_depth(&self) -> usize {
+        self.path_depths
+            .iter()
+            .rposition(|count| *count > 0)
+            .unwrap_or(0)
+    }
+
+    pub fn report(&self, phases: &PhaseTimes) -> String {
+        let mut lines = vec![
+            String::from("Statistics:"),
+            format!(
+                "    Rays: {} camera, {} secondary, {} shadow ({:.2} M/s)",
+                self.camera_rays,
+                self.secondary_rays,
+                self.shadow_rays,
+                phases.rays_per_second(self) / 1e6
+            ),
+        ];
+        let ray_casts: Vec<String> = HITTABLE_NAMES
+            .iter()
+            .zip(&self.ray_casts)
+            .filter(|(_, count)| **count > 0)
+            .map(|(name, count)| format!("{} {}", count, name))
+            .collect();
+        lines.push(format!("    Ray casts: {}", ray_casts.join(", ")));
+        lines.push(format!(
+            "    AABB tests: {}, BVH nodes visited: {}",
+            self.aabb_tests,
+            self.bvh_nodes_visited()
+        ));
+        lines.push(format!(
+            "    Path depth: {:.2} average, {} max over {} paths",
+            self.average_path_depth(),
+            self.max_path_depth(),
+            self.path_count()
+        ));
+        let histogram: Vec<String> = self.path_depths.iter().map(|c| c.to_string()).collect();
+        lines.push(format!("    Paths by depth: {}", histogram.join(" ")));
+        lines.push(format!(
+            "    Time: scene {:.3} s, BVH {:.3} s, render {:.3} s, output {:.3} s",
+            phases.scene, phases.bvh, phases.render, phases.output
+        ));
+        lines.join("\n")
+    }
+    pub fn to_json(&self, phases: &PhaseTimes) -> String {
+        let ray_casts: Vec<String> = HITTABLE_NAMES
+            .iter()
+            .zip(&self.ray_casts)
+            .map(|(name, count)| format!("\"{}\": {}", name, count))
+            .collect();
+        let histogram: Vec<String> = self.path_depths.iter().map(|c| c.to_string()).collect();
+        format!(
+            r#"{{
+  "rays": {{"camera": {}, "secondary": {}, "shadow": {}, "total": {}, "per_second": {:.1}}},
+  "ray_casts": {{{}}},
+  "aabb_tests": {},
+  "bvh_nodes_visited": {},
+  "paths": {{"count": {}, "average_depth": {:.4}, "max_depth": {}, "depth_histogram": [{}]}},
+  "seconds": {{"scene": {:.6}, "bvh": {:.6}, "render": {:.6}, "output": {:.6}}}
+}}"#,
+            self.camera_rays,
+            self.secondary_rays,
+            self.shadow_rays,
+            self.total_rays(),
+            phases.rays_per_second(self),
+            ray_casts.join(", "),
+            self.aabb_tests,
+            self.bvh_nodes_visited(),
+            self.path_count(),
+            self.average_path_depth(),
+            self.max_path_depth(),
+            histogram.join(", "),
+            phases.scene,
+            phases.bvh,
+            phases.render,
+            phases.output
+        )
+    }
+}
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+// PhaseTimes
+// Seconds spent building the scene (without its BVHs), building BVHs,
+// rendering and saving the output.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PhaseTimes {
+    pub scene: f64,
+    pub bvh: f64,
+    pub render: f64,
+    pub output: f64,
+}
+impl PhaseTimes {
+    pub fn rays_per_second(&self, stats: &Stats) -> f64 {
+        if self.render <= 0.0 {
+            return 0.0;
+        }
+        stats.total_rays() as f64 / self.render
+    }
+}
+
+// Counts into this thread's stats.
+pub fn record<F: FnOnce(&mut Stats)>(f: F) {
+    LOCAL.with(|stats| f(&mut stats.borrow_mut()));
+}
+// Adds this thread's stats to the totals and starts it over.
+pub fn flush() {
+    let local = LOCAL.with(|stats| stats.replace(Stats::new()));
+    TOTALS.lock().unwrap().merge(&local);
+}
+// What every thread has flushed so far, this one included.
+pub fn totals() -> Stats {
+    flush();
+    TOTALS.lock().unwrap().clone()
+}
@@ -1,6 +1,9 @@
 extern crate image;
-use crate::{math::*, noise::*};
-use image::GenericImageView;
+use crate::{math::*, noise::*, ray::*};
+use image::{DynamicImage, GenericImageView, ImageError};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 
 // Texutre
 #[derive(Clone)]
@@ -10,6 +13,8 @@ pub enum Texture {
     Perlin(Perlin, f64),
     Marble(Perlin, f64),
     Image(Image),
+    // Colours given per vertex of a triangle mesh, white elsewhere.
+    VertexColor,
 }
 impl From<Color> for Texture {
     fn from(color: Color) -> Texture {
@@ -35,8 +40,8 @@ pub fn sample_texture(texture: &Texture, u: f64, v: f64, p: &Point3) -> Color {
             if image.data.is_empty() {
                 return Color::CYAN;
             }
-            let u = clamp(u, 0.0, 1.0);
-            let v = 1.0 - clamp(v, 0.0, 1.0);
+            let u = wrap(u, image.wrap.0);
+            let v = 1.0 - wrap(v, image.wrap.1);
             let mut i = (u * image.width as f64) as u32;
             let mut j = (v * image.height as f64) as u32;
             if i >= image.width {
@@ -45,39 +50,196 @@ pub fn sample_texture(texture: &Texture, u: f64, v: f64, p: &Point3) -> Color {
             if j >= image.height {
                 j = image.height - 1;
             }
-            let color_scale = 1.0 / 255.0;
             let pixel = j as usize * image.bytes_per_scanline + i as usize * Image::BYTES_PER_PIXEL;
-            let r = color_scale * image.data[pixel] as f64;
-            let g = color_scale * image.data[pixel + 1] as f64;
-            let b = color_scale * image.data[pixel + 2] as f64;
+            let decode = |byte: u8| {
+                if image.srgb {
+                    srgb_to_linear()[byte as usize]
+                } else {
+                    byte as f64 / 255.0
+                }
+            };
+            let r = decode(image.data[pixel]);
+            let g = decode(image.data[pixel + 1]);
+            let b = decode(image.data[pixel + 2]);
             Color::new(r, g, b)
         }
-        _ => Color::BLACK,
+        Texture::VertexColor => Color::WHITE,
+    }
+}
+// The texture at a hit, which also knows the vertex colours there.
+pub fn sample_hit_texture(texture: &Texture, hit: &RayHit) -> Color {
+    match texture {
+        Texture::VertexColor => hit.color().unwrap_or(Color::WHITE),
+        _ => sample_texture(texture, hit.uv().0, hit.uv().1, hit.point()),
     }
 }
 
+// Wrap
+// What an image does with texture coordinates outside [0, 1].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wrap {
+    // Stretches the edge texels.
+    Clamp,
+    // Tiles the image.
+    Repeat,
+    // Tiles the image, mirroring every other tile.
+    MirroredRepeat,
+}
+fn wrap(coordinate: f64, wrap: Wrap) -> f64 {
+    match wrap {
+        Wrap::Clamp => clamp(coordinate, 0.0, 1.0),
+        Wrap::Repeat => coordinate - coordinate.floor(),
+        Wrap::MirroredRepeat => {
+            let t = coordinate.rem_euclid(2.0);
+            if t > 1.0 {
+                2.0 - t
+            } else {
+                t
+            }
+        }
+    }
+}
+
+// The linear value of every 8-bit sRGB encoded one.
+fn srgb_to_linear() -> &'static [f64; 256] {
+    static TABLE: OnceLock<[f64; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0.0; 256];
+        for (byte, value) in table.iter_mut().enumerate() {
+            let encoded = byte as f64 / 255.0;
+            *value = if encoded <= 0.04045 {
+                encoded / 12.92
+            } else {
+                ((encoded + 0.055) / 1.055).powf(2.4)
+            };
+        }
+        table
+    })
+}
+
+// Image
+// Pixels are shared, so every material cloned from one that holds the image
+// keeps using the same copy. Each copy can wrap u and v its own way, images
+// clamp them by default. Colour images are usually sRGB encoded and are decoded
+// to linear values with `srgb`; data such as normals or roughness is stored
+// linear, which is the default.
 #[derive(Clone)]
 pub struct Image {
-    data: Vec<u8>,
+    data: Arc<Vec<u8>>,
     pub width: u32,
     pub height: u32,
     pub bytes_per_scanline: usize,
+    pub wrap: (Wrap, Wrap),
+    pub srgb: bool,
 }
 impl Image {
     const BYTES_PER_PIXEL: usize = 3;
 
+    pub fn with_wrap(self, wrap_u: Wrap, wrap_v: Wrap) -> Self {
+        Image {
+            wrap: (wrap_u, wrap_v),
+            ..self
+        }
+    }
+    pub fn with_srgb(self, srgb: bool) -> Self {
+        Image { srgb, ..self }
+    }
+
     pub fn new(file_name: &str) -> Self {
-        let components_per_pixel = Image::BYTES_PER_PIXEL;
         let img = image::open(file_name).unwrap();
+        eprintln!(
+            "dimensions {:?}, color: {:?}",
+            img.dimensions(),
+            img.color()
+        );
+        let image = Image::from_dynamic(img);
+        assert_ne!(image.data.len(), 0, "Could not load texture!");
+        image
+    }
+    // Same as `new`, but returns an error for files that can't be read or
+    // decoded.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        image::open(path)
+            .map(Image::from_dynamic)
+            .map_err(image_error)
+    }
+    // An image from the bytes of an encoded file, as embedded in some scenes.
+    pub fn from_memory(bytes: &[u8]) -> io::Result<Self> {
+        image::load_from_memory(bytes)
+            .map(Image::from_dynamic)
+            .map_err(image_error)
+    }
+    fn from_dynamic(img: DynamicImage) -> Self {
         let dimension = img.dimensions();
-        eprintln!("dimensions {:?}, color: {:?}", dimension, img.color());
         let data = img.into_rgb().into_raw();
-        assert_ne!(data.len(), 0, "Could not load texture!");
         Image {
-            data,
+            data: Arc::new(data),
             width: dimension.0,
             height: dimension.1,
             bytes_per_scanline: Image::BYTES_PER_PIXEL * dimension.0 as usize,
+            wrap: (Wrap::Clamp, Wrap::Clamp),
+            srgb: false,
         }
     }
 }
+fn image_error(error: ImageError) -> io::Error {
+    match error {
+        ImageError::IoError(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Four texels in a row: black, dark gray, light gray and white.
+    fn ramp(wrap_u: Wrap) -> Texture {
+        let data = vec![0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+        let image = Image {
+            data: Arc::new(data),
+            width: 4,
+            height: 1,
+            bytes_per_scanline: 12,
+            wrap: (Wrap::Clamp, Wrap::Clamp),
+            srgb: false,
+        };
+        Texture::Image(image.with_wrap(wrap_u, Wrap::Repeat))
+    }
+    fn texel(texture: &Texture, u: f64) -> f64 {
+        let p = Point3::new(0.0, 0.0, 0.0);
+        (sample_texture(texture, u, 0.5, &p).x() * 3.0).round()
+    }
+
+    #[test]
+    fn images_wrap_texture_coordinates() {
+        let us = [-0.9, -0.1, 0.1, 0.9, 1.1, 1.9, 2.3];
+        let sample = |wrap| us.map(|u| texel(&ramp(wrap), u));
+        assert_eq!(sample(Wrap::Clamp), [0.0, 0.0, 0.0, 3.0, 3.0, 3.0, 3.0]);
+        assert_eq!(sample(Wrap::Repeat), [0.0, 3.0, 0.0, 3.0, 0.0, 3.0, 1.0]);
+        assert_eq!(
+            sample(Wrap::MirroredRepeat),
+            [3.0, 0.0, 0.0, 3.0, 3.0, 0.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn srgb_images_decode_to_linear() {
+        let p = Point3::new(0.0, 0.0, 0.0);
+        let srgb = match ramp(Wrap::Clamp) {
+            Texture::Image(image) => Texture::Image(image.with_srgb(true)),
+            _ => unreachable!(),
+        };
+        let decoded: Vec<f64> = [0.1, 0.3, 0.6, 0.9]
+            .iter()
+            .map(|u| sample_texture(&srgb, *u, 0.5, &p).x())
+            .collect();
+        assert_eq!(decoded[0], 0.0);
+        assert!((decoded[1] - 0.0908417).abs() < 1e-6, "{}", decoded[1]);
+        assert!((decoded[2] - 0.4019778).abs() < 1e-6, "{}", decoded[2]);
+        assert_eq!(decoded[3], 1.0);
+        // Linear images keep the stored values.
+        let linear = sample_texture(&ramp(Wrap::Clamp), 0.3, 0.5, &p).x();
+        assert!((linear - 85.0 / 255.0).abs() < 1e-12);
+    }
+}
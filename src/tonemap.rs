@@ -0,0 +1,235 @@
+use crate::math::*;
+
+// ToneMapper
+// Squeezes scene-referred linear colours into the [0,1] display range.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ToneMapper {
+    // Cuts everything above 1 off, bright lights burn out to white.
+    Clamp,
+    // c / (1 + L), on the luminance so hues stay put.
+    Reinhard,
+    // Reinhard that reaches white at the given luminance instead of at infinity.
+    ExtendedReinhard(f64),
+    // Stephen Hill's fit of the ACES reference and sRGB output transforms.
+    Aces,
+    // Minimal AgX with the default look, after Benjamin Wrensch's approximation.
+    Agx,
+}
+impl ToneMapper {
+    pub const NAMES: &'static [&'static str] =
+        &["clamp", "reinhard", "extended-reinhard", "aces", "agx"];
+    pub const DEFAULT_WHITE_POINT: f64 = 4.0;
+
+    pub fn from_name(name: &str, white_point: f64) -> Option<Self> {
+        match name {
+            "clamp" => Some(ToneMapper::Clamp),
+            "reinhard" => Some(ToneMapper::Reinhard),
+            "extended-reinhard" => Some(ToneMapper::ExtendedReinhard(white_point)),
+            "aces" => Some(ToneMapper::Aces),
+            "agx" => Some(ToneMapper::Agx),
+            _ => None,
+        }
+    }
+}
+
+// DisplaySettings
+// Turns linear radiance into display values: exposure, tone mapping and then the
+// sRGB transfer function. Dithering only applies to 8-bit output.
+#[derive(Debug, Clone)]
+pub struct DisplaySettings {
+    // In stops, every stop doubles the brightness.
+    pub exposure: f64,
+    pub tone_mapper: ToneMapper,
+    pub dither: bool,
+}
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings {
+            exposure: 0.0,
+            tone_mapper: ToneMapper::Clamp,
+            dither: false,
+        }
+    }
+}
+impl DisplaySettings {
+    // sRGB encoded colour with every channel in [0,1].
+    pub fn display_color(&self, linear: &Color) -> Color {
+        let exposed = *linear * 2f64.powf(self.exposure);
+        let exposed = Color::new(
+            exposed.x().max(0.0),
+            exposed.y().max(0.0),
+            exposed.z().max(0.0),
+        );
+        let mapped = tone_map(self.tone_mapper, &exposed);
+        Color::new(
+            srgb_oetf(clamp(mapped.x(), 0.0, 1.0)),
+            srgb_oetf(clamp(mapped.y(), 0.0, 1.0)),
+            srgb_oetf(clamp(mapped.z(), 0.0, 1.0)),
+        )
+    }
+    // Quantizes a display value in [0,1] to 8 bits. With dithering, a triangular
+    // noise of one step is added first, which hides banding in smooth gradients.
+    pub fn quantize8(&self, value: f64, pixel: usize, channel: usize) -> u8 {
+        let noise = if self.dither {
+            let hash = hash_seed(((pixel as u64) << 2) | channel as u64);
+            let a = (hash >> 40) as f64 / (1u64 << 24) as f64;
+            let b = (hash & 0xFF_FFFF) as f64 / (1u64 << 24) as f64;
+            a + b - 1.0
+        } else {
+            0.0
+        };
+        clamp((255.0 * value + noise).round(), 0.0, 255.0) as u8
+    }
+}
+
+// The exact piecewise sRGB encoding of a linear value in [0,1].
+pub fn srgb_oetf(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Helper functions
+fn mat3_mul(m: &[[f64; 3]; 3], v: &Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x() + m[0][1] * v.y() + m[0][2] * v.z(),
+        m[1][0] * v.x() + m[1][1] * v.y() + m[1][2] * v.z(),
+        m[2][0] * v.x() + m[2][1] * v.y() + m[2][2] * v.z(),
+    )
+}
+fn scale_luminance(color: &Color, mapped_luminance: f64) -> Color {
+    let l = luminance(color);
+    if l <= 0.0 {
+        return Color::BLACK;
+    }
+    *color * (mapped_luminance / l)
+}
+fn aces(color: &Color) -> Color {
+    const INPUT: [[f64; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[f64; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let rrt_and_odt =
+        |v: f64| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081);
+    let v = mat3_mul(&INPUT, color);
+    let v = Vec3::new(rrt_and_odt(v.x()), rrt_and_odt(v.y()), rrt_and_odt(v.z()));
+    // The fit dips just below zero for black, which has no meaning as a colour.
+    let v = mat3_mul(&OUTPUT, &v);
+    Vec3::new(v.x().max(0.0), v.y().max(0.0), v.z().max(0.0))
+}
+fn agx(color: &Color) -> Color {
+    const INSET: [[f64; 3]; 3] = [
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ];
+    const OUTSET: [[f64; 3]; 3] = [
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ];
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 = 4.026069;
+    // Polynomial fit of the AgX contrast curve on log encoded values.
+    let contrast = |x: f64| {
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    };
+    let encode = |v: f64| {
+        let log = v.max(1e-10).log2();
+        contrast(clamp((log - MIN_EV) / (MAX_EV - MIN_EV), 0.0, 1.0))
+    };
+    let v = mat3_mul(&INSET, color);
+    let v = Vec3::new(encode(v.x()), encode(v.y()), encode(v.z()));
+    // The curve produces display values, undo the outset and the display gamma
+    // to get back to linear for the sRGB transform.
+    let v = mat3_mul(&OUTSET, &v);
+    let linearize = |v: f64| v.max(0.0).powf(2.2);
+    Vec3::new(linearize(v.x()), linearize(v.y()), linearize(v.z()))
+}
+
+pub fn tone_map(tone_mapper: ToneMapper, color: &Color) -> Color {
+    match tone_mapper {
+        ToneMapper::Clamp => *color,
+        ToneMapper::Reinhard => {
+            let l = luminance(color);
+            scale_luminance(color, l / (1.0 + l))
+        }
+        ToneMapper::ExtendedReinhard(white_point) => {
+            let l = luminance(color);
+            let white_squared = white_point * white_point;
+            scale_luminance(color, l * (1.0 + l / white_squared) / (1.0 + l))
+        }
+        ToneMapper::Aces => aces(color),
+        ToneMapper::Agx => agx(color),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TONE_MAPPERS: [ToneMapper; 5] = [
+        ToneMapper::Clamp,
+        ToneMapper::Reinhard,
+        ToneMapper::ExtendedReinhard(ToneMapper::DEFAULT_WHITE_POINT),
+        ToneMapper::Aces,
+        ToneMapper::Agx,
+    ];
+
+    #[test]
+    fn srgb_oetf_is_continuous_at_the_breakpoint() {
+        let linear = 0.0031308;
+        let below = srgb_oetf(linear);
+        let above = srgb_oetf(linear + 1e-12);
+        assert!((below - 12.92 * linear).abs() < 1e-12);
+        assert!((below - above).abs() < 1e-6, "{} vs {}", below, above);
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn tone_mappers_are_monotonic_and_keep_black() {
+        for &tone_mapper in TONE_MAPPERS.iter() {
+            let black = tone_map(tone_mapper, &Color::BLACK);
+            assert_eq!(
+                (black.x(), black.y(), black.z()),
+                (0.0, 0.0, 0.0),
+                "{:?}",
+                tone_mapper
+            );
+            let mut previous = Color::BLACK;
+            for i in 1..=400 {
+                let grey = i as f64 / 20.0;
+                let mapped = tone_map(tone_mapper, &Color::new(grey, grey, grey));
+                for (before, after) in [
+                    (previous.x(), mapped.x()),
+                    (previous.y(), mapped.y()),
+                    (previous.z(), mapped.z()),
+                ]
+                .iter()
+                {
+                    assert!(
+                        after >= before,
+                        "{:?} decreases at {}: {} after {}",
+                        tone_mapper,
+                        grey,
+                        after,
+                        before
+                    );
+                }
+                previous = mapped;
+            }
+        }
+    }
+}
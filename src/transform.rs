@@ -0,0 +1,234 @@
+use crate::{aabb::*, math::*};
+use std::ops::Mul;
+
+// Transform
+// An affine transform from object to world space, kept together with its
+// inverse so that rays are taken into object space without inverting anything
+// while rendering.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    matrix: Matrix4,
+    inverse: Matrix4,
+}
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        matrix: Matrix4::IDENTITY,
+        inverse: Matrix4::IDENTITY,
+    };
+
+    // None for matrices that can't be inverted, which flatten objects.
+    pub fn new(matrix: Matrix4) -> Option<Self> {
+        matrix
+            .inverse()
+            .map(|inverse| Transform { matrix, inverse })
+    }
+    pub fn translation(offset: Vec3) -> Self {
+        Transform {
+            matrix: Matrix4::translation(&offset),
+            inverse: Matrix4::translation(&-offset),
+        }
+    }
+    // Scales along the axes, mirroring for negative factors.
+    pub fn scaling(factors: Vec3) -> Self {
+        if factors.x() == 0.0 || factors.y() == 0.0 || factors.z() == 0.0 {
+            panic!("A scaling can't have a factor of zero!");
+        }
+        let inverse = Vec3::new(1.0 / factors.x(), 1.0 / factors.y(), 1.0 / factors.z());
+        Transform {
+            matrix: Matrix4::scaling(&factors),
+            inverse: Matrix4::scaling(&inverse),
+        }
+    }
+    // Counterclockwise about `axis` through the origin, looking down it.
+    pub fn rotation(axis: Vec3, degrees: f64) -> Self {
+        if axis.length_squared() == 0.0 {
+            panic!("A rotation needs an axis!");
+        }
+        Transform {
+            matrix: Matrix4::rotation(&axis, degrees),
+            inverse: Matrix4::rotation(&axis, -degrees),
+        }
+    }
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+    pub fn matrix(&self) -> &Matrix4 {
+        &self.matrix
+    }
+    pub fn inverse_matrix(&self) -> &Matrix4 {
+        &self.inverse
+    }
+    pub fn point(&self, p: &Point3) -> Point3 {
+        self.matrix.transform_point(p)
+    }
+    pub fn vector(&self, v: &Vec3) -> Vec3 {
+        self.matrix.transform_vector(v)
+    }
+    // Unit normal of the transformed surface. Zero stays zero.
+    pub fn normal(&self, n: &Vec3) -> Vec3 {
+        let normal = self.inverse.transform_normal(n);
+        if normal.length_squared() > 0.0 {
+            normalize(&normal)
+        } else {
+            normal
+        }
+    }
+    pub fn inverse_point(&self, p: &Point3) -> Point3 {
+        self.inverse.transform_point(p)
+    }
+    pub fn inverse_vector(&self, v: &Vec3) -> Vec3 {
+        self.inverse.transform_vector(v)
+    }
+    // Box around the eight transformed corners of `bounds`.
+    pub fn bounding_box(&self, bounds: &AABB) -> AABB {
+        let mut min = Point3::new(INIFINITY, INIFINITY, INIFINITY);
+        let mut max = Point3::new(-INIFINITY, -INIFINITY, -INIFINITY);
+        for corner in 0..8 {
+            let pick = |axis: i32| {
+                if corner & (1 << axis) == 0 {
+                    bounds.min()[axis]
+                } else {
+                    bounds.max()[axis]
+                }
+            };
+            let p = self.point(&Point3::new(pick(0), pick(1), pick(2)));
+            for axis in 0..3 {
+                min[axis] = min[axis].min(p[axis]);
+                max[axis] = max[axis].max(p[axis]);
+            }
+        }
+        AABB::new(min, max)
+    }
+    // How much the area of a flat surface with the unit normal `normal` grows.
+    pub fn area_scale(&self, normal: &Vec3) -> f64 {
+        self.matrix.determinant3().abs() * self.inverse.transform_normal(normal).length()
+    }
+    // Whether the transform only moves, turns, mirrors and scales the same way
+    // along every axis, so that spheres stay spheres.
+    pub fn is_similarity(&self) -> bool {
+        let axes = [Vec3::new(1.0, 0.0, 0.0), Vec3::UP, Vec3::new(0.0, 0.0, 1.0)];
+        let [x, y, z] = axes.map(|axis| self.vector(&axis));
+        let scale = x.length_squared();
+        let tolerance = 1e-9 * scale;
+        (y.length_squared() - scale).abs() <= tolerance
+            && (z.length_squared() - scale).abs() <= tolerance
+            && dot(&x, &y).abs() <= tolerance
+            && dot(&y, &z).abs() <= tolerance
+            && dot(&z, &x).abs() <= tolerance
+    }
+}
+// `a * b` applies `b` first, then `a`.
+impl Mul for Transform {
+    type Output = Transform;
+    fn mul(self, other: Transform) -> Transform {
+        Transform {
+            matrix: self.matrix * other.matrix,
+            inverse: other.inverse * self.inverse,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Vec3, b: &Vec3) {
+        assert!((*a - *b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+    fn random_vector(rng: &mut Rng) -> Vec3 {
+        Vec3::new(
+            rng.random_range_double(-2.0, 2.0),
+            rng.random_range_double(-2.0, 2.0),
+            rng.random_range_double(-2.0, 2.0),
+        )
+    }
+    // Scaling unevenly between two rotations shears, and the negative factor
+    // mirrors.
+    fn sheared() -> Transform {
+        Transform::translation(Vec3::new(1.0, -2.0, 0.5))
+            * Transform::rotation(Vec3::new(1.0, 2.0, -1.0), 35.0)
+            * Transform::scaling(Vec3::new(3.0, 0.5, -1.5))
+            * Transform::rotation(Vec3::new(0.0, 1.0, 1.0), -70.0)
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_to_tangents() {
+        let transform = sheared();
+        let mut rng = Rng::new(1);
+        for _ in 0..100 {
+            let normal = normalize(&random_vector(&mut rng));
+            let tangent = cross(&normal, &random_vector(&mut rng));
+            let bitangent = cross(&normal, &tangent);
+            let transformed = transform.normal(&normal);
+            assert!((transformed.length() - 1.0).abs() < 1e-9);
+            for tangent in &[tangent, bitangent] {
+                let tangent = normalize(&transform.vector(tangent));
+                assert!(dot(&transformed, &tangent).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn bounding_boxes_hold_the_transformed_corners() {
+        let bounds = AABB::new(Point3::new(-1.0, 0.0, 2.0), Point3::new(0.5, 3.0, 2.5));
+        let transform = sheared();
+        let transformed = transform.bounding_box(&bounds);
+        for corner in 0..8 {
+            let pick = |axis: usize, min: f64, max: f64| match corner & (1 << axis) {
+                0 => min,
+                _ => max,
+            };
+            let (min, max) = (bounds.min(), bounds.max());
+            let p = transform.point(&Point3::new(
+                pick(0, min.x(), max.x()),
+                pick(1, min.y(), max.y()),
+                pick(2, min.z(), max.z()),
+            ));
+            for axis in 0..3 {
+                assert!(transformed.min()[axis] <= p[axis] && p[axis] <= transformed.max()[axis]);
+            }
+        }
+    }
+
+    #[test]
+    fn products_apply_the_right_transform_first() {
+        let a = Transform::rotation(Vec3::new(0.0, 0.0, 1.0), 90.0);
+        let b = Transform::translation(Vec3::new(1.0, 0.0, 0.0));
+        let p = Point3::new(1.0, 2.0, 3.0);
+        // Moved to (2, 2, 3), then turned to (-2, 2, 3).
+        assert_close(&(a * b).point(&p), &Point3::new(-2.0, 2.0, 3.0));
+        let (a, b) = (sheared(), Transform::scaling(Vec3::new(0.5, 2.0, 4.0)));
+        let mut rng = Rng::new(2);
+        for _ in 0..100 {
+            let p = random_vector(&mut rng);
+            assert_close(&(a * b).point(&p), &a.point(&b.point(&p)));
+            assert_close(&(a * b).vector(&p), &a.vector(&b.vector(&p)));
+            assert_close(
+                &(a * b).inverse_point(&p),
+                &b.inverse_point(&a.inverse_point(&p)),
+            );
+        }
+    }
+
+    #[test]
+    fn inverses_round_trip() {
+        let transform = sheared();
+        let inverse = transform.inverse();
+        let mut rng = Rng::new(3);
+        for _ in 0..100 {
+            let p = random_vector(&mut rng);
+            assert_close(&inverse.point(&transform.point(&p)), &p);
+            assert_close(&transform.point(&inverse.point(&p)), &p);
+            assert_close(&transform.inverse_vector(&transform.vector(&p)), &p);
+            assert_close(&(transform * inverse).point(&p), &p);
+        }
+        let general = Transform::new(*transform.matrix()).unwrap();
+        assert_close(
+            &general.inverse_point(&transform.point(&Vec3::UP)),
+            &Vec3::UP,
+        );
+    }
+}